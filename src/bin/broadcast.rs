@@ -4,6 +4,8 @@ use std::{
 };
 
 use malen::{
+    config::env_or,
+    element::{Element, ElementKind, Json},
     message::{Body, Message, MessageWriter},
     node::Node,
     process::process_loop,
@@ -14,19 +16,20 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+#[serde(bound = "E: Element")]
+enum Payload<E> {
     Init {
         node_id: String,
         node_ids: Vec<String>,
     },
     InitOk,
     Broadcast {
-        message: usize,
+        message: E,
     },
     BroadcastOk,
     Read,
     ReadOk {
-        messages: HashSet<usize>,
+        messages: HashSet<E>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
@@ -34,24 +37,24 @@ enum Payload {
     TopologyOk,
     GossipSend,
     Gossip {
-        messages: HashSet<usize>,
+        messages: HashSet<E>,
     },
     GossipOk,
 }
 
-struct BroadcastNode {
+struct BroadcastNode<E> {
     msg_id: usize,
     node_id: String,
-    messages: HashSet<usize>,
+    messages: HashSet<E>,
     neighbors: Vec<String>,
-    verified: HashMap<String, HashSet<usize>>,
-    gossips_sent: HashMap<usize, HashSet<usize>>,
+    verified: HashMap<String, HashSet<E>>,
+    gossips_sent: HashMap<usize, HashSet<E>>,
     last_gossips_sent: HashSet<usize>,
-    tx: Option<std::sync::mpsc::Sender<Message<Payload>>>,
+    tx: Option<std::sync::mpsc::Sender<Message<Payload<E>>>>,
 }
 
-impl Node<Payload> for BroadcastNode {
-    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+impl<E: Element> Node<Payload<E>> for BroadcastNode<E> {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload<E>>>) {
         self.tx = Some(tx);
     }
 
@@ -63,7 +66,7 @@ impl Node<Payload> for BroadcastNode {
 
     fn handle(
         &mut self,
-        input_msg: Message<Payload>,
+        input_msg: Message<Payload<E>>,
         writer: &mut MessageWriter,
     ) -> anyhow::Result<()> {
         match input_msg.body.payload {
@@ -112,7 +115,7 @@ impl Node<Payload> for BroadcastNode {
                         });
 
                     // get the messages that we have that we know the dest does not have
-                    let gossip_messages: HashSet<usize> = self
+                    let gossip_messages: HashSet<E> = self
                         .messages
                         .difference(self.verified.get(&dest_id).unwrap_or(&HashSet::new()))
                         .cloned()
@@ -147,13 +150,13 @@ impl Node<Payload> for BroadcastNode {
             }
             Payload::Gossip { ref messages } => {
                 // add the gossip messages to our set
-                self.messages.extend(messages);
+                self.messages.extend(messages.iter().cloned());
 
                 // we know that the source has these messages as well so we don't need to send them
                 self.verified
                     .entry(input_msg.src.clone())
                     .or_default()
-                    .extend(messages);
+                    .extend(messages.iter().cloned());
                 let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
                 writer.write_message(&reply)?;
             }
@@ -170,12 +173,12 @@ impl Node<Payload> for BroadcastNode {
                     }
                 }
             }
-            Payload::Broadcast { message } => {
-                self.messages.insert(message);
+            Payload::Broadcast { ref message } => {
+                self.messages.insert(message.clone());
                 let reply = input_msg.into_reply(self.get_msg_id(), Payload::BroadcastOk);
                 writer.write_message(&reply)?;
             }
-            Payload::BroadcastOk { .. } => {}
+            Payload::BroadcastOk => {}
             Payload::Read => {
                 let reply = input_msg.into_reply(
                     self.get_msg_id(),
@@ -194,7 +197,7 @@ impl Node<Payload> for BroadcastNode {
                 let reply = input_msg.into_reply(self.get_msg_id(), Payload::TopologyOk);
                 writer.write_message(&reply)?;
             }
-            Payload::TopologyOk { .. } => {}
+            Payload::TopologyOk => {}
         };
         Ok(())
     }
@@ -205,7 +208,14 @@ fn main() -> anyhow::Result<()> {
         tracing_appender::rolling::daily("/Users/kyle/workspaces/malen/log", "maelstrom.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     tracing_subscriber::fmt().with_writer(non_blocking).init();
-    let mut node = BroadcastNode {
+    match env_or("MALEN_BROADCAST_MESSAGES", ElementKind::Integer)? {
+        ElementKind::Integer => run::<usize>(),
+        ElementKind::Json => run::<Json>(),
+    }
+}
+
+fn run<E: Element>() -> anyhow::Result<()> {
+    let mut node = BroadcastNode::<E> {
         msg_id: 0,
         node_id: "0".to_string(),
         messages: HashSet::new(),
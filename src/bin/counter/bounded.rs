@@ -0,0 +1,213 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    message::{error_code, Body, Message, MessageWriter},
+    node::Node,
+};
+
+use crate::{count, overflow_error, Counter, Payload};
+
+/// How long a decrement waits for peers to transfer it rights.
+pub const RIGHTS_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// A decrement this node has too few rights for, waiting on transfers.
+struct WaitingDecrement {
+    request: Message<Payload>,
+    key: String,
+    amount: i64,
+    /// peers that have not answered its `request_rights` yet
+    asked: HashSet<String>,
+    deadline: Instant,
+}
+
+/// Escrow state of bounded counters.
+///
+/// Each node may only subtract from a counter what it has rights to: its own
+/// increments, less its own decrements, plus what other nodes transferred to
+/// it, less what it transferred away. Rights never add up to more than the
+/// value, so no interleaving of decrements can take it below zero.
+#[derive(Default)]
+pub struct Escrow {
+    waiting: Vec<WaitingDecrement>,
+}
+
+impl Counter {
+    /// Rights on the counter `key` that `node` holds, as far as this node
+    /// knows, summed in an i128 so that no combination of counts overflows.
+    pub(super) fn rights(&self, key: &str, node: &str) -> i128 {
+        let inc = i128::from(count(&self.inc_values, key, node));
+        let dec = i128::from(count(&self.dec_values, key, node));
+        let Some(transfers) = self.transfers.get(key) else {
+            return inc - dec;
+        };
+        let received: i128 = transfers
+            .values()
+            .filter_map(|to| to.get(node))
+            .map(|v| i128::from(*v))
+            .sum();
+        let sent: i128 = transfers
+            .get(node)
+            .map_or(0, |to| to.values().map(|v| i128::from(*v)).sum());
+        inc - dec + received - sent
+    }
+
+    fn transferred(&mut self, key: &str, from: &str, to: &str) -> &mut i64 {
+        let transfers = self.transfers.entry(key.to_string()).or_default();
+        let to_nodes = transfers.entry(from.to_string()).or_default();
+        to_nodes.entry(to.to_string()).or_insert(0)
+    }
+
+    /// Apply a decrement if this node has the rights for it, otherwise ask
+    /// every peer for the difference.
+    pub(super) fn bounded_decrement(
+        &mut self,
+        input_msg: Message<Payload>,
+        key: String,
+        amount: i64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let held = self.rights(&key, &self.node_id);
+        if held >= i128::from(amount) {
+            return self.decrement(input_msg, &key, amount, writer);
+        }
+        let missing = i64::try_from(i128::from(amount) - held).unwrap_or(i64::MAX);
+
+        let peers: HashSet<String> = self
+            .node_ids
+            .iter()
+            .filter(|node| **node != self.node_id)
+            .cloned()
+            .collect();
+        for peer in &peers {
+            let msg_id = self.get_msg_id();
+            let message = Message {
+                src: self.node_id.clone(),
+                dest: peer.clone(),
+                body: Body {
+                    msg_id,
+                    in_reply_to: None,
+                    payload: Payload::RequestRights {
+                        key: key.clone(),
+                        amount: missing,
+                    },
+                },
+            };
+            writer.write_message(&message)?;
+        }
+        self.escrow.waiting.push(WaitingDecrement {
+            request: input_msg,
+            key,
+            amount,
+            asked: peers,
+            deadline: Instant::now() + RIGHTS_TIMEOUT,
+        });
+        // a single node cluster has nobody to ask
+        self.settle_waiting(Instant::now(), writer)
+    }
+
+    fn decrement(
+        &mut self,
+        input_msg: Message<Payload>,
+        key: &str,
+        amount: i64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let nodes = self.dec_values.entry(key.to_string()).or_default();
+        let dec = nodes.entry(self.node_id.clone()).or_insert(0);
+        // other decrements may have been applied while this one waited
+        let payload = match dec.checked_add(amount) {
+            Some(sum) => {
+                *dec = sum;
+                Payload::AddOk
+            }
+            None => overflow_error(format!(
+                "subtracting {} from this node's count of {} overflows i64",
+                amount, dec
+            )),
+        };
+        self.mark_changed(key, None);
+        let reply = input_msg.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)
+    }
+
+    /// Transfer up to `amount` of this node's rights to the peer asking.
+    pub(super) fn handle_request_rights(
+        &mut self,
+        input_msg: Message<Payload>,
+        key: String,
+        amount: i64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let spare = self
+            .rights(&key, &self.node_id)
+            .clamp(0, i128::from(i64::MAX));
+        let grant = i128::from(amount).min(spare) as i64;
+        let node_id = self.node_id.clone();
+        let transferred = self.transferred(&key, &node_id, &input_msg.src);
+        // the running total is what both sides agree on, so capping it only
+        // means transferring less
+        *transferred = transferred.saturating_add(grant);
+        let granted = *transferred;
+        self.mark_changed(&key, None);
+
+        let reply =
+            input_msg.into_reply(self.get_msg_id(), Payload::RequestRightsOk { key, granted });
+        writer.write_message(&reply)
+    }
+
+    /// Record a peer's transfer and apply whatever waiting decrements it
+    /// covers.
+    pub(super) fn handle_request_rights_ok(
+        &mut self,
+        input_msg: Message<Payload>,
+        key: String,
+        granted: i64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let from = input_msg.src;
+        let node_id = self.node_id.clone();
+        let transferred = self.transferred(&key, &from, &node_id);
+        if granted > *transferred {
+            *transferred = granted;
+            self.mark_changed(&key, Some(&from));
+        }
+
+        for waiting in &mut self.escrow.waiting {
+            if waiting.key == key {
+                waiting.asked.remove(&from);
+            }
+        }
+        self.settle_waiting(Instant::now(), writer)
+    }
+
+    /// Apply waiting decrements in arrival order while the rights last, and
+    /// reject those nobody is left to give rights to.
+    pub(super) fn settle_waiting(
+        &mut self,
+        now: Instant,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        for waiting in std::mem::take(&mut self.escrow.waiting) {
+            let held = self.rights(&waiting.key, &self.node_id);
+            if held >= i128::from(waiting.amount) {
+                self.decrement(waiting.request, &waiting.key, waiting.amount, writer)?;
+            } else if waiting.asked.is_empty() || waiting.deadline <= now {
+                let payload = Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    text: format!(
+                        "not enough rights to subtract {} from {}: this node holds {}",
+                        waiting.amount, waiting.key, held
+                    ),
+                };
+                let reply = waiting.request.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            } else {
+                self.escrow.waiting.push(waiting);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    config::env_or,
+    message::{Body, Message, MessageWriter},
+    node::{spawn_ticker, Node},
+    process::process_loop,
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+
+use crate::{
+    bounded::{Escrow, RIGHTS_TIMEOUT},
+    seqkv::{Backend, SeqKv, SEQ_KV},
+};
+
+mod bounded;
+mod seqkv;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Add {
+        delta: i64,
+        /// the counter to add to, `DEFAULT_KEY` if unset
+        #[serde(default)]
+        key: Option<String>,
+    },
+    AddOk,
+    Read {
+        /// the counter to read, `DEFAULT_KEY` if unset; on reads this node
+        /// sends to seq-kv, the seq-kv key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        /// only leaves the i64 range with `MALEN_COUNTER_I128_READS`
+        #[serde(deserialize_with = "deserialize_wide")]
+        value: i128,
+    },
+    Write {
+        key: String,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: i64,
+        to: i64,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    ReadAll {
+        /// only counters whose key starts with this
+        #[serde(default)]
+        prefix: String,
+    },
+    ReadAllOk {
+        values: HashMap<String, i128>,
+    },
+    Error {
+        code: u64,
+        text: String,
+    },
+    GossipSend,
+    /// the state of the counters that changed since the last gossip the
+    /// receiver acknowledged
+    Gossip {
+        inc_messages: Counts,
+        dec_messages: Counts,
+        #[serde(default)]
+        transfers: Transfers,
+    },
+    GossipOk,
+    RequestRights {
+        key: String,
+        amount: i64,
+    },
+    RequestRightsOk {
+        key: String,
+        /// everything the sender has transferred to the requester so far
+        granted: i64,
+    },
+    Tick,
+}
+
+/// Counter for `add` and `read` requests that do not name one.
+const DEFAULT_KEY: &str = "default";
+
+/// Error code for an `add` or `read` whose result does not fit in an i64.
+const OVERFLOW: u64 = 1000;
+
+/// Counter key -> node -> what the node added (or subtracted).
+type Counts = HashMap<String, HashMap<String, i64>>;
+/// Counter key -> node -> node -> rights the first transferred to the second.
+type Transfers = HashMap<String, HashMap<String, HashMap<String, i64>>>;
+
+struct Counter {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    inc_values: Counts,
+    dec_values: Counts,
+    // rights each node transferred to each other node, for bounded counters
+    transfers: Transfers,
+    // peer -> counters that changed since the peer last acknowledged them
+    unsynced: HashMap<String, HashSet<String>>,
+    // gossip msg_id -> the peer and the counters it carried
+    gossips_in_flight: HashMap<usize, (String, HashSet<String>)>,
+    backend: Backend,
+    // whether decrements are limited to the rights a node holds, which keeps
+    // the value from going below zero
+    bounded: bool,
+    // whether reads return sums past the i64 range instead of an error; each
+    // node's counts are i64, so the i128 sum of them is always exact
+    i128_reads: bool,
+    seq_kv: SeqKv,
+    escrow: Escrow,
+    tx: Option<Sender<Message<Payload>>>,
+}
+
+// serde cannot buffer an i128 for an internally tagged enum, so read the
+// number as JSON and widen it
+fn deserialize_wide<'de, D>(deserializer: D) -> Result<i128, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let number = Number::deserialize(deserializer)?;
+    number
+        .as_i64()
+        .map(i128::from)
+        .or_else(|| number.as_u64().map(i128::from))
+        .ok_or_else(|| D::Error::custom(format!("{} is not an integer", number)))
+}
+
+/// Error reply for a request whose result does not fit in an i64. The
+/// request had no effect.
+fn overflow_error(text: String) -> Payload {
+    Payload::Error {
+        code: OVERFLOW,
+        text,
+    }
+}
+
+/// What `node` counted for the counter `key`.
+fn count(counts: &Counts, key: &str, node: &str) -> i64 {
+    counts
+        .get(key)
+        .and_then(|nodes| nodes.get(node))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Merge `from` into `into`, keeping the larger count of each node, and
+/// note the counters that grew.
+fn merge_counts(into: &mut Counts, from: &Counts, changed: &mut HashSet<String>) {
+    for (key, nodes) in from {
+        let known = into.entry(key.clone()).or_default();
+        for (node, value) in nodes {
+            let entry = known.entry(node.clone()).or_insert(0);
+            if *value > *entry {
+                *entry = *value;
+                changed.insert(key.clone());
+            }
+        }
+    }
+}
+
+impl Counter {
+    /// The value of the counter `key` as far as this node knows. Every
+    /// node's counts fit in an i64, so their sums fit in an i128.
+    fn value(&self, key: &str) -> i128 {
+        let sum = |counts: &Counts| -> i128 {
+            counts
+                .get(key)
+                .map_or(0, |nodes| nodes.values().map(|v| i128::from(*v)).sum())
+        };
+        sum(&self.inc_values) - sum(&self.dec_values)
+    }
+
+    /// How much `delta` adds to this node's increments or decrements of
+    /// `key`, or why it cannot be applied.
+    fn check_add(&self, key: &str, delta: i64) -> Result<i64, String> {
+        let amount = delta
+            .checked_abs()
+            .ok_or_else(|| format!("cannot negate {}", delta))?;
+        let counts = if delta >= 0 {
+            &self.inc_values
+        } else {
+            &self.dec_values
+        };
+        let own = count(counts, key, &self.node_id);
+        if own.checked_add(amount).is_none() {
+            return Err(format!(
+                "adding {} to this node's count of {} overflows i64",
+                amount, own
+            ));
+        }
+        let value = self.value(key) + i128::from(delta);
+        if !self.i128_reads && i64::try_from(value).is_err() {
+            return Err(format!("adding {} makes the value {}", delta, value));
+        }
+        Ok(amount)
+    }
+
+    /// The reply to a read of a counter worth `value`.
+    fn read_ok(&self, value: i128) -> Payload {
+        if self.i128_reads || i64::try_from(value).is_ok() {
+            Payload::ReadOk { value }
+        } else {
+            overflow_error(format!("{} does not fit in an i64", value))
+        }
+    }
+
+    /// Queue the counter `key` for gossip to every peer but `except`.
+    fn mark_changed(&mut self, key: &str, except: Option<&str>) {
+        for (peer, keys) in self.unsynced.iter_mut() {
+            if Some(peer.as_str()) != except {
+                keys.insert(key.to_string());
+            }
+        }
+    }
+
+    /// Send each peer the counters it has not acknowledged yet.
+    fn gossip(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        // unanswered gossip is sent again with whatever changed since
+        for (_, (peer, keys)) in self.gossips_in_flight.drain() {
+            self.unsynced.entry(peer).or_default().extend(keys);
+        }
+
+        let peers: Vec<String> = self.unsynced.keys().cloned().collect();
+        for dest_id in peers {
+            let keys = std::mem::take(self.unsynced.get_mut(&dest_id).expect("known peer"));
+            if keys.is_empty() {
+                continue;
+            }
+            let select = |counts: &Counts| -> Counts {
+                keys.iter()
+                    .filter_map(|key| Some((key.clone(), counts.get(key)?.clone())))
+                    .collect()
+            };
+            let inc_messages = select(&self.inc_values);
+            let dec_messages = select(&self.dec_values);
+            let transfers = keys
+                .iter()
+                .filter_map(|key| Some((key.clone(), self.transfers.get(key)?.clone())))
+                .collect();
+
+            let msg_id = self.get_msg_id().expect("No message id");
+            let gossip = Message {
+                src: self.node_id.clone(),
+                dest: dest_id.clone(),
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload: Payload::Gossip {
+                        inc_messages,
+                        dec_messages,
+                        transfers,
+                    },
+                },
+            };
+            writer.write_message(&gossip)?;
+            self.gossips_in_flight.insert(msg_id, (dest_id, keys));
+        }
+        Ok(())
+    }
+}
+
+impl Node<Payload> for Counter {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.tx = Some(tx);
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        if self.backend == Backend::SeqKv {
+            if input_msg.src == SEQ_KV {
+                return self.kv_reply(input_msg, writer);
+            }
+            if matches!(
+                input_msg.body.payload,
+                Payload::Add { .. } | Payload::Read { .. } | Payload::ReadAll { .. }
+            ) {
+                return self.kv_request(input_msg, writer);
+            }
+        }
+
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+                self.unsynced = node_ids
+                    .iter()
+                    .filter(|node| **node != self.node_id)
+                    .map(|node| (node.clone(), HashSet::new()))
+                    .collect();
+                // nothing to gossip when the value lives in seq-kv
+                if self.backend == Backend::Gossip {
+                    let tx = self.tx.clone().unwrap();
+                    spawn_ticker(
+                        tx,
+                        node_id.clone(),
+                        Duration::from_millis(5000),
+                        Payload::GossipSend,
+                    );
+                }
+                if self.bounded {
+                    let tx = self.tx.clone().unwrap();
+                    spawn_ticker(tx, node_id.clone(), RIGHTS_TIMEOUT / 10, Payload::Tick);
+                }
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Add { delta, ref key } => {
+                let key = key.clone().unwrap_or_else(|| DEFAULT_KEY.to_string());
+                let payload = match self.check_add(&key, delta) {
+                    Err(text) => overflow_error(text),
+                    Ok(amount) if self.bounded && delta < 0 => {
+                        return self.bounded_decrement(input_msg, key, amount, writer);
+                    }
+                    Ok(amount) => {
+                        let counts = if delta >= 0 {
+                            &mut self.inc_values
+                        } else {
+                            &mut self.dec_values
+                        };
+                        let nodes = counts.entry(key.clone()).or_default();
+                        *nodes.entry(self.node_id.clone()).or_insert(0) += amount;
+                        self.mark_changed(&key, None);
+                        Payload::AddOk
+                    }
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::AddOk => panic!("Unexpected AddOk message"),
+
+            Payload::Read { ref key } => {
+                let key = key.as_deref().unwrap_or(DEFAULT_KEY);
+                let payload = self.read_ok(self.value(key));
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::ReadOk { value: _ } => panic!("Unexpected ReadOk message"),
+
+            Payload::ReadAll { ref prefix } => {
+                let keys: HashSet<&String> = self
+                    .inc_values
+                    .keys()
+                    .chain(self.dec_values.keys())
+                    .filter(|key| key.starts_with(prefix.as_str()))
+                    .collect();
+                let mut values = HashMap::new();
+                let mut payload = None;
+                for key in keys {
+                    match self.read_ok(self.value(key)) {
+                        Payload::ReadOk { value } => {
+                            values.insert(key.clone(), value);
+                        }
+                        error => {
+                            payload = Some(error);
+                            break;
+                        }
+                    }
+                }
+                let payload = payload.unwrap_or(Payload::ReadAllOk { values });
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::ReadAllOk { .. } => panic!("Unexpected ReadAllOk message"),
+
+            Payload::Write { .. }
+            | Payload::WriteOk
+            | Payload::Cas { .. }
+            | Payload::CasOk
+            | Payload::Error { .. } => {
+                tracing::info!("Ignoring seq-kv message in gossip mode: {:?}", input_msg);
+            }
+
+            Payload::GossipSend => self.gossip(writer)?,
+            Payload::Gossip {
+                ref inc_messages,
+                ref dec_messages,
+                ref transfers,
+            } => {
+                let mut changed = HashSet::new();
+                merge_counts(&mut self.inc_values, inc_messages, &mut changed);
+                merge_counts(&mut self.dec_values, dec_messages, &mut changed);
+                for (key, from) in transfers {
+                    let known = self.transfers.entry(key.clone()).or_default();
+                    for (node, to) in from {
+                        let known = known.entry(node.clone()).or_default();
+                        for (peer, value) in to {
+                            let entry = known.entry(peer.clone()).or_insert(0);
+                            if *value > *entry {
+                                *entry = *value;
+                                changed.insert(key.clone());
+                            }
+                        }
+                    }
+                }
+                // pass news on, in case the sender cannot reach everyone
+                for key in changed {
+                    self.mark_changed(&key, Some(&input_msg.src));
+                }
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::GossipOk => {
+                if let Some(msg_id) = input_msg.body.in_reply_to {
+                    self.gossips_in_flight.remove(&msg_id);
+                }
+            }
+
+            Payload::RequestRights { ref key, amount } => {
+                let key = key.clone();
+                self.handle_request_rights(input_msg, key, amount, writer)?;
+            }
+            Payload::RequestRightsOk { ref key, granted } => {
+                let key = key.clone();
+                self.handle_request_rights_ok(input_msg, key, granted, writer)?;
+            }
+            Payload::Tick => self.settle_waiting(Instant::now(), writer)?,
+        };
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let file_appender =
+        tracing_appender::rolling::daily("/Users/kyle/workspaces/malen/log", "maelstrom.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    tracing_subscriber::fmt().with_writer(non_blocking).init();
+    let backend = env_or("MALEN_COUNTER_BACKEND", Backend::Gossip)?;
+    let bounded = env_or("MALEN_COUNTER_BOUNDED", false)?;
+    if bounded && backend != Backend::Gossip {
+        anyhow::bail!("MALEN_COUNTER_BOUNDED needs the gossip backend");
+    }
+    let mut node = Counter {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        inc_values: HashMap::new(),
+        dec_values: HashMap::new(),
+        transfers: HashMap::new(),
+        unsynced: HashMap::new(),
+        gossips_in_flight: HashMap::new(),
+        backend,
+        bounded,
+        i128_reads: env_or("MALEN_COUNTER_I128_READS", false)?,
+        seq_kv: SeqKv::default(),
+        escrow: Escrow::default(),
+        tx: None,
+    };
+
+    process_loop(&mut node)
+}
@@ -0,0 +1,246 @@
+use std::{collections::HashMap, str::FromStr};
+
+use malen::{
+    message::{error_code, Body, Message, MessageWriter},
+    node::Node,
+};
+use serde_json::Value;
+
+use crate::{overflow_error, Counter, Payload, DEFAULT_KEY};
+
+/// Node id of Maelstrom's sequentially consistent key/value service.
+pub const SEQ_KV: &str = "seq-kv";
+
+/// seq-kv key holding the value of the counter `key`.
+fn counter_key(key: &str) -> String {
+    format!("counter/{}", key)
+}
+
+/// seq-kv key written before every read, see `SeqKv`.
+const SYNC_KEY: &str = "counter-sync";
+
+/// How a node keeps the counter, selected with `MALEN_COUNTER_BACKEND`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// a PN-counter: every node counts its own increments and decrements and
+    /// gossips them to the others
+    Gossip,
+    /// a single value in `seq-kv`, updated with compare-and-swap
+    SeqKv,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gossip" => Ok(Backend::Gossip),
+            "seq-kv" => Ok(Backend::SeqKv),
+            other => anyhow::bail!("expected gossip or seq-kv, got {}", other),
+        }
+    }
+}
+
+/// What the reply to an outstanding seq-kv operation means.
+enum KvOp {
+    /// read of the value an `add` will compare-and-swap from
+    AddRead { key: String, delta: i64 },
+    /// compare-and-swap of the value from `from` to `from + delta`
+    AddCas { key: String, delta: i64, from: i64 },
+    /// write of a value nobody has written before, ahead of a `read`
+    Sync { key: String },
+    /// read of the value a `read` returns
+    Read,
+}
+
+/// State of the seq-kv backend.
+///
+/// seq-kv may serve a read from any earlier state, so a node could keep
+/// reading a stale value forever. Writing a unique value first moves this
+/// node's view of the store past that write, and the read that follows has
+/// to see at least that state.
+#[derive(Default)]
+pub struct SeqKv {
+    // outstanding operations by msg_id, with the client request they serve
+    ops: HashMap<usize, (KvOp, Message<Payload>)>,
+}
+
+impl Counter {
+    fn kv_send(
+        &mut self,
+        payload: Payload,
+        op: KvOp,
+        request: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.get_msg_id().expect("No message id");
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: SEQ_KV.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        writer.write_message(&message)?;
+        self.seq_kv.ops.insert(msg_id, (op, request));
+        Ok(())
+    }
+
+    fn kv_read(
+        &mut self,
+        key: &str,
+        op: KvOp,
+        request: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let payload = Payload::Read {
+            key: Some(counter_key(key)),
+        };
+        self.kv_send(payload, op, request, writer)
+    }
+
+    fn kv_cas(
+        &mut self,
+        key: String,
+        delta: i64,
+        from: i128,
+        request: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        // the value stays within i64 so every client can read it
+        let Some((from, to)) = i64::try_from(from)
+            .ok()
+            .and_then(|from| Some((from, from.checked_add(delta)?)))
+        else {
+            let payload = overflow_error(format!("adding {} to {} overflows i64", delta, from));
+            let reply = request.into_reply(self.get_msg_id(), payload);
+            return writer.write_message(&reply);
+        };
+        let payload = Payload::Cas {
+            key: counter_key(&key),
+            from,
+            to,
+            create_if_not_exists: true,
+        };
+        self.kv_send(payload, KvOp::AddCas { key, delta, from }, request, writer)
+    }
+
+    /// Start serving a client `add` or `read` from seq-kv.
+    pub(super) fn kv_request(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match input_msg.body.payload {
+            Payload::Add { delta, ref key } => {
+                let key = key.clone().unwrap_or_else(|| DEFAULT_KEY.to_string());
+                let op = KvOp::AddRead {
+                    key: key.clone(),
+                    delta,
+                };
+                self.kv_read(&key, op, input_msg, writer)
+            }
+            Payload::Read { ref key } => {
+                let key = key.clone().unwrap_or_else(|| DEFAULT_KEY.to_string());
+                let value = Value::String(format!("{}-{}", self.node_id, self.msg_id + 1));
+                let payload = Payload::Write {
+                    key: SYNC_KEY.to_string(),
+                    value,
+                };
+                self.kv_send(payload, KvOp::Sync { key }, input_msg, writer)
+            }
+            Payload::ReadAll { .. } => {
+                // seq-kv has no way to list keys
+                let payload = Payload::Error {
+                    code: error_code::NOT_SUPPORTED,
+                    text: "read_all needs the gossip backend".to_string(),
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)
+            }
+            _ => anyhow::bail!("not a counter request"),
+        }
+    }
+
+    /// Move the client request an answer from seq-kv belongs to on to its
+    /// next step.
+    pub(super) fn kv_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some((op, request)) = input_msg
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.seq_kv.ops.remove(&msg_id))
+        else {
+            tracing::info!("Ignoring unexpected seq-kv reply: {:?}", input_msg);
+            return Ok(());
+        };
+
+        match (op, input_msg.body.payload) {
+            (KvOp::AddRead { key, delta }, Payload::ReadOk { value }) => {
+                self.kv_cas(key, delta, value, request, writer)
+            }
+            (
+                KvOp::AddRead { key, delta },
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => self.kv_cas(key, delta, 0, request, writer),
+            (
+                KvOp::AddCas { key, delta, from },
+                Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    ..
+                },
+            ) => {
+                // someone else added first; start over from their value
+                tracing::info!("CAS from {} lost a race, retrying", from);
+                let op = KvOp::AddRead {
+                    key: key.clone(),
+                    delta,
+                };
+                self.kv_read(&key, op, request, writer)
+            }
+            (KvOp::AddCas { .. }, Payload::CasOk) => {
+                let reply = request.into_reply(self.get_msg_id(), Payload::AddOk);
+                writer.write_message(&reply)
+            }
+            (KvOp::Sync { key }, Payload::WriteOk) => {
+                self.kv_read(&key, KvOp::Read, request, writer)
+            }
+            (KvOp::Read, Payload::ReadOk { value }) => {
+                let reply = request.into_reply(self.get_msg_id(), Payload::ReadOk { value });
+                writer.write_message(&reply)
+            }
+            (
+                KvOp::Read,
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => {
+                let reply = request.into_reply(self.get_msg_id(), Payload::ReadOk { value: 0 });
+                writer.write_message(&reply)
+            }
+            (_, Payload::Error { code, text }) => {
+                let reply = request.into_reply(self.get_msg_id(), Payload::Error { code, text });
+                writer.write_message(&reply)
+            }
+            (_, payload) => {
+                // whatever seq-kv did, the client cannot be told it worked
+                tracing::info!("Unexpected seq-kv reply {:?}", payload);
+                let payload = Payload::Error {
+                    code: error_code::CRASH,
+                    text: format!("unexpected reply from {}", SEQ_KV),
+                };
+                let reply = request.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)
+            }
+        }
+    }
+}
@@ -1,10 +1,18 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
 use malen::{
-    message::{Message, MessageWriter},
-    node::Node,
+    id::{generator, IdConfig, IdGenerator, IdMode, IdOrder},
+    message::{error_code, Body, Message, MessageWriter},
+    node::{spawn_ticker, Node},
     process::process_loop,
 };
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -17,17 +25,229 @@ enum Payload {
     InitOk,
     Generate,
     GenerateOk {
-        id: String,
+        /// a string, or a number for leased IDs
+        id: Value,
+    },
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: u64,
     },
+    Cas {
+        key: String,
+        from: u64,
+        to: u64,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        text: String,
+    },
+    Tick,
+}
+
+/// Key in the KV service holding the first ID nobody has leased yet.
+const NEXT_ID_KEY: &str = "generate-next-id";
+/// How long a lease request may go unanswered before it is retried.
+const LEASE_RETRY: Duration = Duration::from_millis(500);
+
+/// Ranges of IDs this node leased from the shared counter.
+///
+/// Each node hands out its leases in order, so IDs are dense apart from the
+/// unused end of a lease held by a node that crashed, and increase
+/// monotonically per node. With `IdOrder::Node` a node leases a block ahead
+/// of time, so across nodes IDs only follow the order the leases were taken
+/// in. With `IdOrder::Global` nothing is leased ahead: each CAS takes
+/// exactly as many IDs as there are requests waiting when it is sent, and
+/// requests that arrive later wait for the next one, so every ID is larger
+/// than any handed out before its request arrived.
+struct Leases {
+    /// half-open ranges still to be handed out, oldest first
+    ranges: VecDeque<(u64, u64)>,
+    /// last value of the counter we saw
+    next_unleased: u64,
+    /// msg_id and send time of the read or CAS in flight
+    in_flight: Option<(usize, Instant)>,
+    /// IDs the CAS in flight takes
+    leasing: u64,
+    /// `generate` requests waiting for a lease, and when they arrived
+    waiting: VecDeque<(Message<Payload>, Instant)>,
+}
+
+impl Leases {
+    fn remaining(&self) -> u64 {
+        self.ranges.iter().map(|(next, end)| end - next).sum()
+    }
+
+    fn take(&mut self) -> Option<u64> {
+        let (next, end) = self.ranges.front_mut()?;
+        let id = *next;
+        *next += 1;
+        if next == end {
+            self.ranges.pop_front();
+        }
+        Some(id)
+    }
 }
 
 struct GenerateNode {
     node_id: String,
     msg_id: usize,
+    // which generator to build once the node ids are known
+    id_config: IdConfig,
+    ids: Option<Box<dyn IdGenerator>>,
+    leases: Leases,
+    tx: Option<Sender<Message<Payload>>>,
+}
+
+impl GenerateNode {
+    fn kv_request(&mut self, payload: Payload, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let msg_id = self.get_msg_id().expect("No message id");
+        self.leases.in_flight = Some((msg_id, Instant::now()));
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: self.id_config.kv_service.clone(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        writer.write_message(&message)
+    }
+
+    // try to move the shared counter past one more lease of `size` IDs
+    fn request_lease(&mut self, size: u64, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let from = self.leases.next_unleased;
+        let payload = Payload::Cas {
+            key: NEXT_ID_KEY.to_string(),
+            from,
+            to: from + size,
+            create_if_not_exists: from == 1,
+        };
+        self.leases.leasing = size;
+        self.kv_request(payload, writer)
+    }
+
+    /// Answer waiting requests that arrived by `arrived_by` from the leases
+    /// held, and lease more before running out.
+    fn serve(&mut self, arrived_by: Instant, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        while self
+            .leases
+            .waiting
+            .front()
+            .is_some_and(|(_, arrived)| *arrived <= arrived_by)
+        {
+            let Some(id) = self.leases.take() else {
+                break;
+            };
+            let (request, _) = self
+                .leases
+                .waiting
+                .pop_front()
+                .expect("a request is waiting");
+            let reply =
+                request.into_reply(self.get_msg_id(), Payload::GenerateOk { id: id.into() });
+            writer.write_message(&reply)?;
+        }
+
+        let size = match self.id_config.order {
+            IdOrder::Node if self.leases.remaining() < self.id_config.block_size / 2 + 1 => {
+                self.id_config.block_size
+            }
+            IdOrder::Node => 0,
+            IdOrder::Global => {
+                // what is left was leased before the requests still waiting
+                // arrived, so it might be below IDs other nodes handed out since
+                self.leases.ranges.clear();
+                self.leases.waiting.len() as u64
+            }
+        };
+        if self.leases.in_flight.is_none() && size > 0 {
+            self.request_lease(size, writer)?;
+        }
+        Ok(())
+    }
+
+    // fail requests the KV service has not let us answer in time
+    fn expire_waiting(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        while let Some((_, arrived)) = self.leases.waiting.front() {
+            if arrived.elapsed() < self.id_config.timeout {
+                break;
+            }
+            let (request, _) = self
+                .leases
+                .waiting
+                .pop_front()
+                .expect("a request is waiting");
+            let payload = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: format!("no ID lease from {} in time", self.id_config.kv_service),
+            };
+            let reply = request.into_reply(self.get_msg_id(), payload);
+            writer.write_message(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle_kv_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let sent = match self.leases.in_flight {
+            Some((msg_id, sent)) if input_msg.body.in_reply_to == Some(msg_id) => sent,
+            _ => {
+                tracing::info!("Ignoring stale KV reply: {:?}", input_msg);
+                return Ok(());
+            }
+        };
+        self.leases.in_flight = None;
+
+        // only requests that were waiting before the CAS was sent may be
+        // answered from the lease it takes, or another node's ID from after
+        // the lease might come first
+        let mut arrived_by = Instant::now();
+        match input_msg.body.payload {
+            Payload::CasOk => {
+                if self.id_config.order == IdOrder::Global {
+                    arrived_by = sent;
+                }
+                let start = self.leases.next_unleased;
+                let end = start + self.leases.leasing;
+                tracing::info!("Leased IDs {}..{}", start, end);
+                self.leases.ranges.push_back((start, end));
+                self.leases.next_unleased = end;
+            }
+            Payload::ReadOk { value } => self.leases.next_unleased = value,
+            Payload::Error {
+                code: error_code::PRECONDITION_FAILED,
+                ..
+            } => {
+                // another node leased first; find out how far it got
+                let key = NEXT_ID_KEY.to_string();
+                return self.kv_request(Payload::Read { key }, writer);
+            }
+            Payload::Error {
+                code: error_code::KEY_DOES_NOT_EXIST,
+                ..
+            } => self.leases.next_unleased = 1,
+            Payload::Error { code, text } => {
+                tracing::info!("Lease request failed ({}): {}", code, text);
+            }
+            _ => {}
+        }
+        self.serve(arrived_by, writer)
+    }
 }
 
 impl Node<Payload> for GenerateNode {
-    fn init(&mut self, _tx: std::sync::mpsc::Sender<Message<Payload>>) {}
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.tx = Some(tx);
+    }
     fn get_msg_id(&mut self) -> Option<usize> {
         self.msg_id += 1;
 
@@ -42,9 +262,15 @@ impl Node<Payload> for GenerateNode {
         match input_msg.body.payload {
             Payload::Init {
                 ref node_id,
-                node_ids: _,
+                ref node_ids,
             } => {
                 self.node_id = node_id.clone();
+                if self.id_config.mode == IdMode::Leased {
+                    let tx = self.tx.clone().expect("node not initialized");
+                    spawn_ticker(tx, node_id.clone(), LEASE_RETRY, Payload::Tick);
+                } else {
+                    self.ids = Some(generator(&self.id_config, node_id, node_ids)?);
+                }
                 let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
 
                 writer.write_message(&reply)?;
@@ -52,15 +278,34 @@ impl Node<Payload> for GenerateNode {
 
             Payload::InitOk => panic!("Unexpected InitOk message"),
             Payload::Generate => {
-                let reply = input_msg.into_reply(
-                    self.get_msg_id(),
-                    Payload::GenerateOk {
-                        id: format!("{}-{}", self.node_id, self.msg_id),
-                    },
-                );
-                writer.write_message(&reply)?;
+                if let Some(ids) = self.ids.as_mut() {
+                    let id = ids.next_id()?.into();
+                    let reply = input_msg.into_reply(self.get_msg_id(), Payload::GenerateOk { id });
+                    writer.write_message(&reply)?;
+                } else {
+                    let now = Instant::now();
+                    self.leases.waiting.push_back((input_msg, now));
+                    self.serve(now, writer)?;
+                }
             }
             Payload::GenerateOk { .. } => {}
+
+            Payload::ReadOk { .. } | Payload::CasOk | Payload::Error { .. } => {
+                self.handle_kv_reply(input_msg, writer)?;
+            }
+            Payload::Read { .. } | Payload::Cas { .. } => panic!("Unexpected KV request"),
+
+            Payload::Tick => {
+                // a lost request or reply would otherwise stall leasing; a
+                // lease whose CAS went through unseen is skipped, not reused
+                if let Some((_, sent)) = self.leases.in_flight {
+                    if sent.elapsed() >= LEASE_RETRY {
+                        let key = NEXT_ID_KEY.to_string();
+                        self.kv_request(Payload::Read { key }, writer)?;
+                    }
+                }
+                self.expire_waiting(writer)?;
+            }
         };
         Ok(())
     }
@@ -70,6 +315,16 @@ fn main() -> anyhow::Result<()> {
     let mut node = GenerateNode {
         msg_id: 1,
         node_id: "0".to_string(),
+        id_config: IdConfig::from_env()?,
+        ids: None,
+        leases: Leases {
+            ranges: VecDeque::new(),
+            next_unleased: 1,
+            in_flight: None,
+            leasing: 0,
+            waiting: VecDeque::new(),
+        },
+        tx: None,
     };
 
     process_loop(&mut node)
@@ -1,47 +1,70 @@
 use std::collections::HashSet;
 
 use malen::{
-    message::{Body, Message, MessageWriter},
+    config::env_or,
+    element::{Element, ElementKind, Json},
+    message::{error_code, Body, Message, MessageWriter},
     node::{GossipManager, Node},
     process::process_loop,
 };
 
 use serde::{Deserialize, Serialize};
 
+use crate::set::{new_set, Mode, Set, Update};
+
+mod set;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+#[serde(bound = "E: Element")]
+enum Payload<E> {
     Init {
         node_id: String,
         node_ids: Vec<String>,
     },
     InitOk,
     Add {
-        element: usize,
+        element: E,
     },
     AddOk,
+    Remove {
+        element: E,
+    },
+    RemoveOk,
     Read,
     ReadOk {
-        value: HashSet<usize>,
+        value: HashSet<E>,
     },
     GossipSend,
     Gossip {
-        messages: HashSet<usize>,
+        messages: HashSet<Update<E>>,
     },
     GossipOk,
+    Error {
+        code: u64,
+        text: String,
+    },
 }
 
-struct GSetNode {
+struct GSetNode<E: Element> {
     msg_id: usize,
     node_id: String,
     node_ids: Vec<String>,
-    values: HashSet<usize>,
-    gossip_manager: Option<GossipManager<Payload, usize>>,
+    mode: Mode,
+    // built once the node knows its id, which LWW timestamps carry
+    set: Option<Box<dyn Set<E>>>,
+    gossip_manager: Option<GossipManager<Payload<E>, Update<E>>>,
+}
+
+impl<E: Element> GSetNode<E> {
+    fn set(&mut self) -> &mut Box<dyn Set<E>> {
+        self.set.as_mut().expect("node not initialized")
+    }
 }
 
-impl Node<Payload> for GSetNode {
-    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+impl<E: Element> Node<Payload<E>> for GSetNode<E> {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload<E>>>) {
         self.gossip_manager = Some(GossipManager::new(tx.clone()));
     }
 
@@ -53,7 +76,7 @@ impl Node<Payload> for GSetNode {
 
     fn handle(
         &mut self,
-        input_msg: Message<Payload>,
+        input_msg: Message<Payload<E>>,
         writer: &mut MessageWriter,
     ) -> anyhow::Result<()> {
         match input_msg.body.payload {
@@ -63,6 +86,7 @@ impl Node<Payload> for GSetNode {
             } => {
                 self.node_id = node_id.clone();
                 self.node_ids = node_ids.clone();
+                self.set = Some(new_set(self.mode, node_id));
 
                 if let Some(manager) = &mut self.gossip_manager {
                     manager.create_gossip_monitor(
@@ -79,21 +103,35 @@ impl Node<Payload> for GSetNode {
 
             Payload::InitOk => panic!("Unexpected InitOk message"),
 
-            Payload::Add { element } => {
-                self.values.insert(element);
+            Payload::Add { ref element } => {
+                let element = element.clone();
+                self.set().add(element);
                 let reply = input_msg.into_reply(self.get_msg_id(), Payload::AddOk);
                 writer.write_message(&reply)?;
             }
 
             Payload::AddOk => panic!("Unexpected AddOk message"),
 
-            Payload::Read => {
-                let reply = input_msg.into_reply(
-                    self.get_msg_id(),
-                    Payload::ReadOk {
-                        value: self.values.clone(),
+            Payload::Remove { ref element } => {
+                let element = element.clone();
+                let payload = match self.set().remove(element) {
+                    Ok(()) => Payload::RemoveOk,
+                    Err(text) => Payload::Error {
+                        code: error_code::NOT_SUPPORTED,
+                        text,
                     },
-                );
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::RemoveOk | Payload::Error { .. } => {
+                panic!("Unexpected {:?} message", input_msg.body.payload)
+            }
+
+            Payload::Read => {
+                let value = self.set().read();
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::ReadOk { value });
                 writer.write_message(&reply)?;
             }
 
@@ -105,7 +143,11 @@ impl Node<Payload> for GSetNode {
                     let msg_id = self.get_msg_id().expect("No message id");
 
                     if let Some(manager) = &mut self.gossip_manager {
-                        let hash: Vec<usize> = self.values.iter().cloned().collect();
+                        let hash = self
+                            .set
+                            .as_ref()
+                            .map(|set| set.updates())
+                            .unwrap_or_default();
                         let gossip_messages =
                             manager.prune_stale_sent_gossips(&dest_id, msg_id, &hash);
                         if gossip_messages.is_empty() {
@@ -121,10 +163,7 @@ impl Node<Payload> for GSetNode {
                                 msg_id: Some(msg_id),
                                 in_reply_to: None,
                                 payload: Payload::Gossip {
-                                    messages: gossip_messages
-                                        .iter()
-                                        .cloned()
-                                        .collect::<HashSet<usize>>(),
+                                    messages: gossip_messages.into_iter().collect(),
                                 },
                             },
                         };
@@ -140,14 +179,13 @@ impl Node<Payload> for GSetNode {
                     &messages
                 );
 
-                // add the gossip messages to our set
-                self.values.extend(messages);
+                // merge the gossip messages into our set
+                for update in messages {
+                    self.set().merge(update.clone());
+                }
 
                 if let Some(manager) = &mut self.gossip_manager {
-                    manager.verify_messages(
-                        &input_msg.src,
-                        messages.iter().cloned().collect::<Vec<usize>>(),
-                    );
+                    manager.verify_messages(&input_msg.src, messages.iter().cloned().collect());
 
                     let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
                     writer.write_message(&reply)?;
@@ -169,11 +207,20 @@ fn main() -> anyhow::Result<()> {
         tracing_appender::rolling::daily("/Users/kyle/workspaces/malen/log", "maelstrom.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     tracing_subscriber::fmt().with_writer(non_blocking).init();
-    let mut node = GSetNode {
+    let mode = env_or("MALEN_GSET_MODE", Mode::GrowOnly)?;
+    match env_or("MALEN_GSET_ELEMENTS", ElementKind::Integer)? {
+        ElementKind::Integer => run::<usize>(mode),
+        ElementKind::Json => run::<Json>(mode),
+    }
+}
+
+fn run<E: Element>(mode: Mode) -> anyhow::Result<()> {
+    let mut node = GSetNode::<E> {
         msg_id: 0,
         node_id: "0".to_string(),
         node_ids: Vec::new(),
-        values: HashSet::new(),
+        mode,
+        set: None,
         gossip_manager: None,
     };
 
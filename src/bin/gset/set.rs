@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use malen::{
+    element::Element,
+    hlc::{HybridClock, Timestamp},
+};
+use serde::{Deserialize, Serialize};
+
+/// Which set a node runs, selected with `MALEN_GSET_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// elements can only be added
+    GrowOnly,
+    /// elements can be removed, but never added back
+    TwoPhase,
+    /// the latest add or remove of an element wins, by hybrid logical clock
+    Lww,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grow-only" => Ok(Mode::GrowOnly),
+            "two-phase" => Ok(Mode::TwoPhase),
+            "lww" => Ok(Mode::Lww),
+            other => anyhow::bail!("expected grow-only, two-phase or lww, got {}", other),
+        }
+    }
+}
+
+/// One piece of a set's state, as gossiped between nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+#[serde(bound = "E: Element")]
+pub enum Update<E> {
+    Add {
+        element: E,
+    },
+    Remove {
+        element: E,
+    },
+    /// the latest add or remove of an element in an LWW-element-set
+    Stamp {
+        element: E,
+        at: Timestamp,
+        removed: bool,
+    },
+}
+
+/// A replicated set whose state is merged from gossiped updates.
+pub trait Set<E>: Send {
+    fn add(&mut self, element: E);
+    /// Remove `element`, or explain why this kind of set cannot.
+    fn remove(&mut self, element: E) -> Result<(), String>;
+    fn read(&self) -> HashSet<E>;
+    /// Updates that together make up the set's state.
+    fn updates(&self) -> Vec<Update<E>>;
+    fn merge(&mut self, update: Update<E>);
+}
+
+pub fn new_set<E: Element>(mode: Mode, node_id: &str) -> Box<dyn Set<E>> {
+    match mode {
+        Mode::GrowOnly => Box::<GrowOnly<E>>::default(),
+        Mode::TwoPhase => Box::<TwoPhase<E>>::default(),
+        Mode::Lww => Box::new(Lww::new(node_id)),
+    }
+}
+
+pub struct GrowOnly<E> {
+    values: HashSet<E>,
+}
+
+impl<E> Default for GrowOnly<E> {
+    fn default() -> Self {
+        Self {
+            values: HashSet::new(),
+        }
+    }
+}
+
+impl<E: Element> Set<E> for GrowOnly<E> {
+    fn add(&mut self, element: E) {
+        self.values.insert(element);
+    }
+
+    fn remove(&mut self, _element: E) -> Result<(), String> {
+        Err("a grow-only set cannot remove elements".to_string())
+    }
+
+    fn read(&self) -> HashSet<E> {
+        self.values.clone()
+    }
+
+    fn updates(&self) -> Vec<Update<E>> {
+        self.values
+            .iter()
+            .map(|element| Update::Add {
+                element: element.clone(),
+            })
+            .collect()
+    }
+
+    fn merge(&mut self, update: Update<E>) {
+        if let Update::Add { element } = update {
+            self.values.insert(element);
+        }
+    }
+}
+
+/// Two grow-only sets: everything ever added, and everything ever removed.
+pub struct TwoPhase<E> {
+    added: HashSet<E>,
+    removed: HashSet<E>,
+}
+
+impl<E> Default for TwoPhase<E> {
+    fn default() -> Self {
+        Self {
+            added: HashSet::new(),
+            removed: HashSet::new(),
+        }
+    }
+}
+
+impl<E: Element> Set<E> for TwoPhase<E> {
+    fn add(&mut self, element: E) {
+        self.added.insert(element);
+    }
+
+    fn remove(&mut self, element: E) -> Result<(), String> {
+        // removing an element nobody added yet keeps it out for good too
+        self.removed.insert(element);
+        Ok(())
+    }
+
+    fn read(&self) -> HashSet<E> {
+        self.added.difference(&self.removed).cloned().collect()
+    }
+
+    fn updates(&self) -> Vec<Update<E>> {
+        let added = self.added.iter().map(|element| Update::Add {
+            element: element.clone(),
+        });
+        let removed = self.removed.iter().map(|element| Update::Remove {
+            element: element.clone(),
+        });
+        added.chain(removed).collect()
+    }
+
+    fn merge(&mut self, update: Update<E>) {
+        match update {
+            Update::Add { element } => {
+                self.added.insert(element);
+            }
+            Update::Remove { element } => {
+                self.removed.insert(element);
+            }
+            Update::Stamp { .. } => {}
+        }
+    }
+}
+
+/// LWW-element-set: the latest add or remove of each element, by hybrid
+/// logical clock timestamp.
+pub struct Lww<E> {
+    clock: HybridClock,
+    // element -> when it was last added or removed, and whether it was removed
+    latest: HashMap<E, (Timestamp, bool)>,
+}
+
+impl<E: Element> Lww<E> {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            clock: HybridClock::new(node_id),
+            latest: HashMap::new(),
+        }
+    }
+
+    fn stamp(&mut self, element: E, removed: bool) {
+        let at = self.clock.now();
+        self.latest.insert(element, (at, removed));
+    }
+}
+
+impl<E: Element> Set<E> for Lww<E> {
+    fn add(&mut self, element: E) {
+        self.stamp(element, false);
+    }
+
+    fn remove(&mut self, element: E) -> Result<(), String> {
+        self.stamp(element, true);
+        Ok(())
+    }
+
+    fn read(&self) -> HashSet<E> {
+        self.latest
+            .iter()
+            .filter(|(_, (_, removed))| !removed)
+            .map(|(element, _)| element.clone())
+            .collect()
+    }
+
+    fn updates(&self) -> Vec<Update<E>> {
+        self.latest
+            .iter()
+            .map(|(element, (at, removed))| Update::Stamp {
+                element: element.clone(),
+                at: at.clone(),
+                removed: *removed,
+            })
+            .collect()
+    }
+
+    fn merge(&mut self, update: Update<E>) {
+        let Update::Stamp {
+            element,
+            at,
+            removed,
+        } = update
+        else {
+            return;
+        };
+        self.clock.observe(&at);
+        let newer = self
+            .latest
+            .get(&element)
+            .is_none_or(|(latest, _)| at > *latest);
+        if newer {
+            self.latest.insert(element, (at, removed));
+        }
+    }
+}
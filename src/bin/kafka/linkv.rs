@@ -0,0 +1,976 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    config::env_or,
+    message::{error_code, Body, Message, MessageWriter},
+    node::Node,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{poll_reply, Kafka, Payload, PollLimits, DEFAULT_GROUP};
+
+/// Node id of Maelstrom's linearizable key/value service.
+pub const LIN_KV: &str = "lin-kv";
+
+/// Messages stored under one lin-kv key, so that a poll reads many with a
+/// single request.
+const CHUNK_SIZE: u64 = 32;
+
+/// Tries at writing a message before its send fails.
+const STORE_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry of a failed write; it doubles after each
+/// further failure.
+const STORE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// lin-kv errors after which a write may still go through when tried again.
+const RETRYABLE: &[u64] = &[
+    error_code::TIMEOUT,
+    error_code::TEMPORARILY_UNAVAILABLE,
+    error_code::CRASH,
+];
+
+/// Where a node keeps its logs, selected with `MALEN_KAFKA_BACKEND`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// every key lives in the `Log` of the node that owns it
+    Local,
+    /// offsets are allocated with compare-and-swap on `lin-kv`, and messages
+    /// and committed offsets are stored there too, so any node can serve
+    /// any key
+    LinKv,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Backend::Local),
+            "lin-kv" => Ok(Backend::LinKv),
+            other => anyhow::bail!("expected local or lin-kv, got {}", other),
+        }
+    }
+}
+
+/// Messages of `CHUNK_SIZE` consecutive offsets of a key. `None` marks an
+/// offset that was allocated but given up on before its message was
+/// written.
+pub type Chunk = BTreeMap<u64, Option<u64>>;
+
+/// A value kept in lin-kv.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KvValue {
+    Offset(u64),
+    Chunk(#[serde(with = "chunk_entries")] Chunk),
+}
+
+// a chunk is stored as `[offset, msg]` pairs in order of offset, which
+// compare equal in a CAS whenever the chunks do
+mod chunk_entries {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Chunk;
+
+    pub fn serialize<S: Serializer>(chunk: &Chunk, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(chunk)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Chunk, D::Error> {
+        let entries = Vec::<(u64, Option<u64>)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+// lin-kv keys: the latest offset of each key, each chunk of messages, and
+// the committed offset of each group
+fn offset_key(key: &str) -> String {
+    format!("offset/{}", key)
+}
+
+fn chunk_key(key: &str, index: u64) -> String {
+    format!("msgs/{}/{}", key, index)
+}
+
+fn committed_key(group: &str, key: &str) -> String {
+    format!("committed/{}/{}", group, key)
+}
+
+/// A client request waiting on lin-kv operations.
+struct KvRequest {
+    message: Message<Payload>,
+    deadline: Instant,
+    // operations that still have to come back before it can be answered
+    outstanding: usize,
+    state: RequestState,
+}
+
+enum RequestState {
+    Send {
+        key: String,
+        msg: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+        limits: PollLimits,
+        found: HashMap<String, PolledKey>,
+    },
+    CommitOffsets,
+    ListCommittedOffsets {
+        offsets: HashMap<String, u64>,
+    },
+}
+
+/// The chunks a poll read of one key.
+#[derive(Default)]
+struct PolledKey {
+    // last offset the poll can return
+    to: u64,
+    chunks: HashMap<u64, Chunk>,
+}
+
+/// A message being written to the chunk of the offset allocated for it.
+struct Store {
+    key: String,
+    offset: u64,
+    msg: u64,
+    // the chunk as last read, when a write raced with another one
+    chunk: Option<Chunk>,
+    // failed tries so far; lost races do not count
+    failures: u32,
+    attempt: Attempt,
+}
+
+enum Attempt {
+    /// an operation is out since then
+    Sent(Instant),
+    /// tried again at that point
+    Backoff(Instant),
+}
+
+/// What the reply to an outstanding lin-kv operation means.
+enum KvOp {
+    /// CAS that moves the latest offset of the send's key up to `offset`
+    Allocate {
+        request: usize,
+        key: String,
+        msg: u64,
+        offset: u64,
+    },
+    /// read of the latest offset after an allocation lost a race
+    RefreshLatest {
+        request: usize,
+    },
+    /// CAS that adds the message of a store to chunk `index` of its key
+    StoreMessage {
+        request: usize,
+        index: u64,
+        to: Chunk,
+    },
+    /// read of a chunk after a store lost a race
+    ReadChunk {
+        request: usize,
+        index: u64,
+    },
+    PollLatest {
+        request: usize,
+        key: String,
+    },
+    PollChunk {
+        request: usize,
+        key: String,
+        index: u64,
+    },
+    /// CAS that marks an offset nobody wrote as given up on
+    FillGap {
+        key: String,
+        offset: u64,
+        index: u64,
+        to: Chunk,
+    },
+    Commit {
+        request: usize,
+    },
+    ReadCommitted {
+        request: usize,
+        key: String,
+    },
+}
+
+impl KvOp {
+    fn request(&self) -> Option<usize> {
+        match self {
+            KvOp::Allocate { request, .. }
+            | KvOp::RefreshLatest { request }
+            | KvOp::StoreMessage { request, .. }
+            | KvOp::ReadChunk { request, .. }
+            | KvOp::PollLatest { request, .. }
+            | KvOp::PollChunk { request, .. }
+            | KvOp::Commit { request }
+            | KvOp::ReadCommitted { request, .. } => Some(*request),
+            KvOp::FillGap { .. } => None,
+        }
+    }
+
+    // operations that carry on after the client request timed out: an
+    // allocated offset is written even once the client gave up, as polls
+    // stop at an offset that was allocated but never written
+    fn outlives_request(&self) -> bool {
+        matches!(
+            self,
+            KvOp::Allocate { .. }
+                | KvOp::StoreMessage { .. }
+                | KvOp::ReadChunk { .. }
+                | KvOp::FillGap { .. }
+        )
+    }
+}
+
+/// State of the lin-kv backend.
+///
+/// - `MALEN_KAFKA_GAP_TIMEOUT_MS`: how long an offset may stay allocated
+///   but unwritten before polls give up on it and skip it, in case its
+///   sender crashed
+#[derive(Default)]
+pub struct LinKv {
+    /// latest offset seen for each key; a CAS from a stale value fails and
+    /// the value is read again
+    latest: HashMap<String, u64>,
+    /// newest chunk seen of each key, and its index, which the next write
+    /// to it starts from
+    chunks: HashMap<String, (u64, Chunk)>,
+    requests: HashMap<usize, KvRequest>,
+    // messages being written, by the request that sent them
+    stores: HashMap<usize, Store>,
+    // offsets polls found allocated but not written, and since when
+    gaps: HashMap<(String, u64), Instant>,
+    gap_timeout: Duration,
+    // outstanding operations by msg_id
+    ops: HashMap<usize, KvOp>,
+}
+
+impl LinKv {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            gap_timeout: Duration::from_millis(env_or("MALEN_KAFKA_GAP_TIMEOUT_MS", 10_000)?),
+            ..Self::default()
+        })
+    }
+
+    fn note_latest(&mut self, key: &str, offset: u64) {
+        let latest = self.latest.entry(key.to_string()).or_default();
+        *latest = (*latest).max(offset);
+    }
+
+    fn note_chunk(&mut self, key: &str, index: u64, chunk: &Chunk) {
+        match self.chunks.get_mut(key) {
+            Some((newest, _)) if *newest > index => {}
+            Some(entry) => *entry = (index, chunk.clone()),
+            None => {
+                self.chunks.insert(key.to_string(), (index, chunk.clone()));
+            }
+        }
+    }
+}
+
+impl Kafka {
+    fn kv(&mut self) -> &mut LinKv {
+        self.lin_kv.as_mut().expect("lin-kv backend not enabled")
+    }
+
+    fn kv_call(
+        &mut self,
+        op: KvOp,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.get_msg_id().expect("No message id");
+        self.kv().ops.insert(msg_id, op);
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: LIN_KV.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        writer.write_message(&message)
+    }
+
+    fn kv_reply_to(
+        &mut self,
+        request: usize,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(request) = self.kv().requests.remove(&request) else {
+            return Ok(());
+        };
+        let reply = request.message.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)
+    }
+
+    /// Answer a client request from lin-kv.
+    pub(super) fn kv_request(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let request = self.get_msg_id().expect("No message id");
+        let mut calls = Vec::new();
+        let state = match &input_msg.body.payload {
+            Payload::Send {
+                key,
+                msg,
+                producer_id: None,
+                seq: None,
+                timestamp: None,
+            } => RequestState::Send {
+                key: key.clone(),
+                msg: *msg,
+            },
+
+            Payload::Poll {
+                offsets,
+                max_messages_per_key,
+                max_messages,
+                max_bytes_per_key,
+                max_bytes,
+                timestamps: false,
+                ..
+            } => {
+                for key in offsets.keys() {
+                    let op = KvOp::PollLatest {
+                        request,
+                        key: key.clone(),
+                    };
+                    let key = offset_key(key);
+                    calls.push((op, Payload::Read { key }));
+                }
+                RequestState::Poll {
+                    offsets: offsets.clone(),
+                    limits: self.poll_limits.with_overrides(&PollLimits {
+                        max_messages_per_key: *max_messages_per_key,
+                        max_messages: *max_messages,
+                        max_bytes_per_key: *max_bytes_per_key,
+                        max_bytes: *max_bytes,
+                    }),
+                    found: HashMap::new(),
+                }
+            }
+
+            Payload::CommitOffsets { offsets, group } => {
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                for (key, offset) in offsets {
+                    let payload = Payload::Write {
+                        key: committed_key(group, key),
+                        value: KvValue::Offset(*offset),
+                    };
+                    calls.push((KvOp::Commit { request }, payload));
+                }
+                RequestState::CommitOffsets
+            }
+
+            Payload::ListCommittedOffsets { keys, group } => {
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                for key in keys {
+                    let op = KvOp::ReadCommitted {
+                        request,
+                        key: key.clone(),
+                    };
+                    let key = committed_key(group, key);
+                    calls.push((op, Payload::Read { key }));
+                }
+                RequestState::ListCommittedOffsets {
+                    offsets: HashMap::new(),
+                }
+            }
+
+            other => {
+                let reply = input_msg.clone().into_reply(
+                    self.get_msg_id(),
+                    Payload::Error {
+                        code: error_code::NOT_SUPPORTED,
+                        text: format!("{:?} is not supported by the lin-kv backend", other),
+                    },
+                );
+                return writer.write_message(&reply);
+            }
+        };
+
+        let is_send = matches!(state, RequestState::Send { .. });
+        let kv_request = KvRequest {
+            message: input_msg,
+            deadline: Instant::now() + self.request_timeout,
+            outstanding: calls.len(),
+            state,
+        };
+        self.kv().requests.insert(request, kv_request);
+
+        if is_send {
+            return self.allocate(request, writer);
+        }
+        if calls.is_empty() {
+            return self.complete(request, writer);
+        }
+        for (op, payload) in calls {
+            self.kv_call(op, payload, writer)?;
+        }
+        Ok(())
+    }
+
+    // try to claim the offset after the latest one we know of
+    fn allocate(&mut self, request: usize, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let kv = self.kv();
+        let Some(RequestState::Send { key, msg }) = kv.requests.get(&request).map(|r| &r.state)
+        else {
+            return Ok(());
+        };
+        let from = kv.latest.get(key).copied().unwrap_or(0);
+        let payload = Payload::Cas {
+            key: offset_key(key),
+            from: KvValue::Offset(from),
+            to: KvValue::Offset(from + 1),
+            create_if_not_exists: true,
+        };
+        let op = KvOp::Allocate {
+            request,
+            key: key.clone(),
+            msg: *msg,
+            offset: from + 1,
+        };
+        self.kv_call(op, payload, writer)
+    }
+
+    // add the message of a store to its chunk, unless the chunk as last
+    // seen already settles it
+    fn store(&mut self, request: usize, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let kv = self.kv();
+        let Some(store) = kv.stores.get_mut(&request) else {
+            return Ok(());
+        };
+        let index = store.offset / CHUNK_SIZE;
+        let from = match &store.chunk {
+            Some(chunk) => chunk.clone(),
+            None => match kv.chunks.get(&store.key) {
+                Some((newest, chunk)) if *newest == index => chunk.clone(),
+                _ => Chunk::new(),
+            },
+        };
+        match from.get(&store.offset) {
+            Some(Some(_)) => {
+                let offset = store.offset;
+                kv.stores.remove(&request);
+                return self.kv_reply_to(request, Payload::SendOk { offset }, writer);
+            }
+            Some(None) => {
+                // a poll gave up on the offset first; the message needs
+                // another one
+                tracing::info!("Offset {} of {} was given up on", store.offset, store.key);
+                kv.stores.remove(&request);
+                return self.allocate(request, writer);
+            }
+            None => {}
+        }
+
+        store.attempt = Attempt::Sent(Instant::now());
+        let mut to = from.clone();
+        to.insert(store.offset, Some(store.msg));
+        let payload = Payload::Cas {
+            key: chunk_key(&store.key, index),
+            from: KvValue::Chunk(from),
+            to: KvValue::Chunk(to.clone()),
+            create_if_not_exists: true,
+        };
+        let op = KvOp::StoreMessage { request, index, to };
+        self.kv_call(op, payload, writer)
+    }
+
+    // a try at writing a message failed: try again after a while, unless
+    // trying again is pointless or the tries are used up
+    fn store_failed(
+        &mut self,
+        request: usize,
+        code: u64,
+        text: String,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let kv = self.kv();
+        let Some(store) = kv.stores.get_mut(&request) else {
+            return Ok(());
+        };
+        store.failures += 1;
+        if !RETRYABLE.contains(&code) || store.failures >= STORE_ATTEMPTS {
+            tracing::info!(
+                "Giving up on writing {} offset {} after {} tries: {}",
+                store.key,
+                store.offset,
+                store.failures,
+                text
+            );
+            kv.stores.remove(&request);
+            let payload = Payload::Error {
+                code,
+                text: format!("lin-kv: {}", text),
+            };
+            return self.kv_reply_to(request, payload, writer);
+        }
+        let backoff = STORE_BACKOFF * 2u32.pow(store.failures - 1);
+        tracing::info!(
+            "Retrying write of {} offset {} in {:?}: {}",
+            store.key,
+            store.offset,
+            backoff,
+            text
+        );
+        store.attempt = Attempt::Backoff(Instant::now() + backoff);
+        Ok(())
+    }
+
+    /// Handle a reply from lin-kv.
+    pub(super) fn kv_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(op) = input_msg
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.kv().ops.remove(&msg_id))
+        else {
+            tracing::info!("Ignoring unexpected lin-kv reply: {:?}", input_msg);
+            return Ok(());
+        };
+        let payload = input_msg.body.payload;
+
+        match (op, payload) {
+            (
+                KvOp::Allocate {
+                    request,
+                    key,
+                    msg,
+                    offset,
+                },
+                Payload::CasOk,
+            ) => {
+                self.kv().note_latest(&key, offset);
+                let store = Store {
+                    key,
+                    offset,
+                    msg,
+                    chunk: None,
+                    failures: 0,
+                    attempt: Attempt::Sent(Instant::now()),
+                };
+                self.kv().stores.insert(request, store);
+                self.store(request, writer)
+            }
+
+            (KvOp::StoreMessage { request, index, to }, Payload::CasOk) => {
+                let kv = self.kv();
+                let Some(store) = kv.stores.remove(&request) else {
+                    return Ok(());
+                };
+                kv.note_chunk(&store.key, index, &to);
+                let payload = Payload::SendOk {
+                    offset: store.offset,
+                };
+                self.kv_reply_to(request, payload, writer)
+            }
+            (
+                KvOp::StoreMessage { request, index, .. },
+                Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    ..
+                },
+            ) => {
+                let Some(store) = self.kv().stores.get(&request) else {
+                    return Ok(());
+                };
+                let key = chunk_key(&store.key, index);
+                self.kv_call(
+                    KvOp::ReadChunk { request, index },
+                    Payload::Read { key },
+                    writer,
+                )
+            }
+            (
+                KvOp::ReadChunk { request, index },
+                Payload::ReadOk {
+                    value: KvValue::Chunk(chunk),
+                },
+            ) => {
+                let kv = self.kv();
+                let Some(store) = kv.stores.get_mut(&request) else {
+                    return Ok(());
+                };
+                store.chunk = Some(chunk.clone());
+                let key = store.key.clone();
+                kv.note_chunk(&key, index, &chunk);
+                self.store(request, writer)
+            }
+            (
+                KvOp::ReadChunk { request, .. },
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => {
+                if let Some(store) = self.kv().stores.get_mut(&request) {
+                    store.chunk = Some(Chunk::new());
+                }
+                self.store(request, writer)
+            }
+            (
+                KvOp::StoreMessage { request, .. } | KvOp::ReadChunk { request, .. },
+                Payload::Error { code, text },
+            ) => self.store_failed(request, code, text, writer),
+
+            (
+                KvOp::FillGap {
+                    key,
+                    offset,
+                    index,
+                    to,
+                },
+                payload,
+            ) => {
+                // on failure the gap stays, and the next poll that runs into
+                // it tries again
+                if let Payload::CasOk = payload {
+                    tracing::info!("Gave up on offset {} of {}", offset, key);
+                    let kv = self.kv();
+                    kv.gaps.remove(&(key.clone(), offset));
+                    kv.note_chunk(&key, index, &to);
+                }
+                Ok(())
+            }
+
+            (op, payload) => self.kv_request_reply(op, payload, writer),
+        }
+    }
+
+    // the reply to an operation of a request that is answered once it
+    // completes
+    fn kv_request_reply(
+        &mut self,
+        op: KvOp,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(request) = op.request() else {
+            return Ok(());
+        };
+        if !self.kv().requests.contains_key(&request) {
+            return Ok(());
+        }
+
+        match (op, payload) {
+            (
+                KvOp::Allocate { key, .. },
+                Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    ..
+                },
+            ) => {
+                let key = offset_key(&key);
+                self.kv_call(
+                    KvOp::RefreshLatest { request },
+                    Payload::Read { key },
+                    writer,
+                )
+            }
+            (
+                KvOp::RefreshLatest { .. },
+                Payload::ReadOk {
+                    value: KvValue::Offset(value),
+                },
+            ) => {
+                if let Some(RequestState::Send { key, .. }) =
+                    self.kv().requests.get(&request).map(|r| &r.state)
+                {
+                    let key = key.clone();
+                    self.kv().note_latest(&key, value);
+                }
+                self.allocate(request, writer)
+            }
+            (
+                KvOp::RefreshLatest { .. },
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => self.allocate(request, writer),
+
+            (
+                KvOp::PollLatest { key, .. },
+                Payload::ReadOk {
+                    value: KvValue::Offset(value),
+                },
+            ) => {
+                self.kv().note_latest(&key, value);
+                self.read_polled(request, &key, value, writer)?;
+                self.complete_part(request, writer)
+            }
+            (
+                KvOp::PollChunk { key, index, .. },
+                Payload::ReadOk {
+                    value: KvValue::Chunk(chunk),
+                },
+            ) => {
+                let kv = self.kv();
+                kv.note_chunk(&key, index, &chunk);
+                if let Some(RequestState::Poll { found, .. }) =
+                    kv.requests.get_mut(&request).map(|r| &mut r.state)
+                {
+                    found.entry(key).or_default().chunks.insert(index, chunk);
+                }
+                self.complete_part(request, writer)
+            }
+            // a chunk nobody wrote to yet
+            (
+                KvOp::PollChunk { key, index, .. },
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => {
+                if let Some(RequestState::Poll { found, .. }) =
+                    self.kv().requests.get_mut(&request).map(|r| &mut r.state)
+                {
+                    let chunks = &mut found.entry(key).or_default().chunks;
+                    chunks.insert(index, Chunk::new());
+                }
+                self.complete_part(request, writer)
+            }
+            (KvOp::Commit { .. }, Payload::WriteOk) => self.complete_part(request, writer),
+            (
+                KvOp::ReadCommitted { key, .. },
+                Payload::ReadOk {
+                    value: KvValue::Offset(value),
+                },
+            ) => {
+                if let Some(RequestState::ListCommittedOffsets { offsets }) =
+                    self.kv().requests.get_mut(&request).map(|r| &mut r.state)
+                {
+                    offsets.insert(key, value);
+                }
+                self.complete_part(request, writer)
+            }
+            // keys that were never sent to or committed
+            (
+                KvOp::PollLatest { .. } | KvOp::ReadCommitted { .. },
+                Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    ..
+                },
+            ) => self.complete_part(request, writer),
+
+            (_, Payload::Error { code, text }) => {
+                let payload = Payload::Error {
+                    code,
+                    text: format!("lin-kv: {}", text),
+                };
+                self.kv_reply_to(request, payload, writer)
+            }
+            (_, payload) => {
+                tracing::info!("Unexpected lin-kv reply: {:?}", payload);
+                Ok(())
+            }
+        }
+    }
+
+    // read the chunks holding the messages a poll can return now that the
+    // key's latest offset is known
+    fn read_polled(
+        &mut self,
+        request: usize,
+        key: &str,
+        latest: u64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(KvRequest {
+            state:
+                RequestState::Poll {
+                    offsets,
+                    limits,
+                    found,
+                },
+            outstanding,
+            ..
+        }) = self.kv().requests.get_mut(&request)
+        else {
+            return Ok(());
+        };
+        // offsets start at 1
+        let from = offsets.get(key).copied().unwrap_or(0).max(1);
+        let max_read = limits
+            .max_messages_per_key
+            .into_iter()
+            .chain(limits.max_messages)
+            .min()
+            .unwrap_or(usize::MAX) as u64;
+        let to = latest.min(from.saturating_add(max_read).saturating_sub(1));
+        if from > to {
+            return Ok(());
+        }
+
+        found.entry(key.to_string()).or_default().to = to;
+        let indexes = from / CHUNK_SIZE..=to / CHUNK_SIZE;
+        *outstanding += indexes.clone().count();
+        for index in indexes {
+            let op = KvOp::PollChunk {
+                request,
+                key: key.to_string(),
+                index,
+            };
+            let payload = Payload::Read {
+                key: chunk_key(key, index),
+            };
+            self.kv_call(op, payload, writer)?;
+        }
+        Ok(())
+    }
+
+    fn complete_part(&mut self, request: usize, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let Some(kv_request) = self.kv().requests.get_mut(&request) else {
+            return Ok(());
+        };
+        kv_request.outstanding = kv_request.outstanding.saturating_sub(1);
+        if kv_request.outstanding == 0 {
+            self.complete(request, writer)?;
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self, request: usize, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let kv = self.kv();
+        let Some(kv_request) = kv.requests.get_mut(&request) else {
+            return Ok(());
+        };
+        let mut fills = Vec::new();
+        let payload = match &mut kv_request.state {
+            RequestState::Send { .. } => return Ok(()),
+            RequestState::Poll {
+                offsets,
+                limits,
+                found,
+            } => {
+                let mut entries = Vec::new();
+                for (key, start) in offsets.iter() {
+                    let polled = found.remove(key).unwrap_or_default();
+                    let mut msgs = Vec::new();
+                    for offset in (*start).max(1)..=polled.to {
+                        let index = offset / CHUNK_SIZE;
+                        let Some(chunk) = polled.chunks.get(&index) else {
+                            break;
+                        };
+                        match chunk.get(&offset) {
+                            Some(Some(msg)) => msgs.push(vec![offset, *msg]),
+                            Some(None) => {}
+                            // allocated but not written yet: stop, so a
+                            // consumer never skips over a message still being
+                            // written, and give up on it once its sender had
+                            // long enough
+                            None => {
+                                let since = *kv.gaps.entry((key.clone(), offset)).or_insert(now);
+                                if now.duration_since(since) >= kv.gap_timeout {
+                                    let mut to = chunk.clone();
+                                    to.insert(offset, None);
+                                    let payload = Payload::Cas {
+                                        key: chunk_key(key, index),
+                                        from: KvValue::Chunk(chunk.clone()),
+                                        to: KvValue::Chunk(to.clone()),
+                                        create_if_not_exists: true,
+                                    };
+                                    let op = KvOp::FillGap {
+                                        key: key.clone(),
+                                        offset,
+                                        index,
+                                        to,
+                                    };
+                                    fills.push((op, payload));
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    if !kv.gaps.is_empty() {
+                        for entry in &msgs {
+                            kv.gaps.remove(&(key.clone(), entry[0]));
+                        }
+                    }
+                    entries.push((key.clone(), msgs));
+                }
+                poll_reply(entries, limits)
+            }
+            RequestState::CommitOffsets => Payload::CommitOffsetsOk,
+            RequestState::ListCommittedOffsets { offsets } => Payload::ListCommittedOffsetsOk {
+                offsets: std::mem::take(offsets),
+            },
+        };
+        self.kv_reply_to(request, payload, writer)?;
+        for (op, payload) in fills {
+            self.kv_call(op, payload, writer)?;
+        }
+        Ok(())
+    }
+
+    /// Fail requests lin-kv has not answered in time, and retry writes of
+    /// messages.
+    pub(super) fn kv_expire(
+        &mut self,
+        now: Instant,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let timeout = self.request_timeout;
+        let kv = self.kv();
+        let expired: Vec<usize> = kv
+            .requests
+            .iter()
+            .filter(|(_, request)| request.deadline <= now)
+            .map(|(request, _)| *request)
+            .collect();
+        kv.ops.retain(|_, op| {
+            op.outlives_request() || op.request().is_some_and(|r| !expired.contains(&r))
+        });
+
+        // a write lin-kv never answered counts as failed
+        let mut unanswered = Vec::new();
+        let mut due = Vec::new();
+        for (request, store) in &kv.stores {
+            match store.attempt {
+                Attempt::Sent(at) if at + timeout <= now => unanswered.push(*request),
+                Attempt::Backoff(at) if at <= now => due.push(*request),
+                _ => {}
+            }
+        }
+        kv.ops.retain(|_, op| {
+            !matches!(op, KvOp::StoreMessage { .. } | KvOp::ReadChunk { .. })
+                || op.request().is_some_and(|r| !unanswered.contains(&r))
+        });
+
+        for request in expired {
+            let payload = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: "timed out waiting for lin-kv".to_string(),
+            };
+            self.kv_reply_to(request, payload, writer)?;
+        }
+        for request in unanswered {
+            let text = "no reply".to_string();
+            self.store_failed(request, error_code::TIMEOUT, text, writer)?;
+        }
+        for request in due {
+            self.store(request, writer)?;
+        }
+        Ok(())
+    }
+}
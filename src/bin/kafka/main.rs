@@ -0,0 +1,2541 @@
+mod linkv;
+mod replication;
+mod segment;
+mod storage;
+mod subscription;
+mod txn;
+
+use malen::{
+    config::{env_or, env_var},
+    message::{error_code, Body, Message, MessageWriter},
+    node::{spawn_ticker, Node},
+    process::process_loop,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::mpsc::Sender,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use linkv::{Backend, KvValue, LinKv, LIN_KV};
+use replication::{AckWaiter, ReplicaState, Replication, ReplicationConfig};
+use serde::{Deserialize, Serialize};
+use storage::{ProducerWindows, Record, Storage, StorageConfig};
+use subscription::{Delivery, Subscription, DEFAULT_MAX_IN_FLIGHT};
+use txn::{CommittingTxn, CoordinatedTxn, Decision, Participant, PreparedTxn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Send {
+        key: String,
+        msg: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// producer time for the message, in milliseconds since the epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    SendOk {
+        offset: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages_per_key: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes_per_key: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
+        /// return `[offset, msg, timestamp]` entries, with the producer
+        /// timestamp appended when the message has one
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        timestamps: bool,
+        /// hold the poll for up to this long if nothing is there yet;
+        /// answered right away by the lin-kv backend
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wait_ms: Option<u64>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<Vec<u64>>>,
+        /// only between nodes: offsets of the transactional messages in
+        /// `msgs`, by transaction
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        txn_offsets: TxnOffsets,
+        /// only between nodes: transactions on the polled keys whose
+        /// messages the node is not showing yet
+        #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+        hidden_txns: HashSet<String>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, u64>,
+    },
+    ListKeys,
+    ListKeysOk {
+        keys: Vec<String>,
+    },
+    DescribeKey {
+        key: String,
+    },
+    DescribeKeyOk {
+        key: String,
+        owner: String,
+        first_offset: Option<u64>,
+        last_offset: Option<u64>,
+        committed: HashMap<String, u64>,
+        count: u64,
+        bytes: u64,
+        replicas: Vec<String>,
+        in_sync: Vec<String>,
+    },
+    DeleteKey {
+        key: String,
+    },
+    DeleteKeyOk {
+        offset: u64,
+    },
+    ListGroups,
+    ListGroupsOk {
+        groups: HashMap<String, HashMap<String, u64>>,
+    },
+    DescribeGroup {
+        group: String,
+    },
+    DescribeGroupOk {
+        offsets: HashMap<String, u64>,
+        lag: HashMap<String, u64>,
+    },
+    OffsetsForTimes {
+        times: HashMap<String, u64>,
+    },
+    OffsetsForTimesOk {
+        offsets: HashMap<String, u64>,
+    },
+    SendTxn {
+        msgs: Vec<(String, u64)>,
+    },
+    SendTxnOk {
+        offsets: Vec<(String, u64)>,
+    },
+    TxnPrepare {
+        txn_id: String,
+        msgs: Vec<(String, u64)>,
+        /// every owner taking part, so that one can settle the transaction
+        /// with the others when the coordinator turns out to have lost it
+        #[serde(default)]
+        participants: Vec<String>,
+    },
+    TxnVote {
+        txn_id: String,
+        prepared: bool,
+    },
+    TxnCommit {
+        txn_id: String,
+    },
+    TxnCommitOk {
+        txn_id: String,
+        offsets: Vec<(String, u64)>,
+    },
+    /// the participant has nothing prepared to commit
+    TxnCommitFailed {
+        txn_id: String,
+        text: String,
+    },
+    TxnAbort {
+        txn_id: String,
+    },
+    TxnAbortOk {
+        txn_id: String,
+    },
+    /// a participant asking the coordinator for a decision it is missing
+    TxnStatus {
+        txn_id: String,
+    },
+    /// the coordinator is done with the transaction: every participant has
+    /// its messages, or the transaction aborted or was forgotten in a crash
+    TxnComplete {
+        txn_id: String,
+    },
+    /// a participant whose coordinator is done with a transaction it never
+    /// heard the decision of, asking another participant whether it committed
+    TxnOutcome {
+        txn_id: String,
+    },
+    TxnOutcomeOk {
+        txn_id: String,
+        committed: bool,
+    },
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: KvValue,
+    },
+    Write {
+        key: String,
+        value: KvValue,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: KvValue,
+        to: KvValue,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Fetch {
+        /// next offset the follower needs for each key it has
+        offsets: HashMap<String, u64>,
+        /// epoch of the follower's newest record of each of those keys
+        #[serde(default)]
+        epochs: HashMap<String, u64>,
+    },
+    FetchOk {
+        records: HashMap<String, Vec<Record>>,
+        /// offset from which the follower's log diverged from the leader's,
+        /// for keys it has to drop the end of before appending
+        #[serde(default)]
+        truncate: HashMap<String, u64>,
+        /// first offset the leader still has, for keys the follower is
+        /// further behind than that
+        #[serde(default)]
+        start_offsets: HashMap<String, u64>,
+        /// dedup windows of those same keys, which the follower can no
+        /// longer rebuild from the records it missed
+        #[serde(default)]
+        producers: HashMap<String, ProducerWindows>,
+        /// committed offsets of every consumer group, by key
+        #[serde(default)]
+        committed: HashMap<String, HashMap<String, u64>>,
+        /// the batch was cut short
+        #[serde(default)]
+        more: bool,
+    },
+    /// between replicating nodes: this node is alive, and what it has of
+    /// each key it holds
+    Heartbeat {
+        keys: HashMap<String, ReplicaState>,
+    },
+    Subscribe {
+        offsets: HashMap<String, u64>,
+        /// messages pushed but not yet acknowledged before pushing pauses
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_in_flight: Option<usize>,
+        /// set by the node the client subscribed through
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscription: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<String>,
+    },
+    SubscribeOk {
+        subscription: String,
+    },
+    Unsubscribe {
+        subscription: String,
+    },
+    UnsubscribeOk,
+    Deliver {
+        subscription: String,
+        msgs: HashMap<String, Vec<Vec<u64>>>,
+    },
+    DeliverOk,
+    /// between nodes: wake the sender's long poll once one of these keys has
+    /// messages from its offset on
+    Watch {
+        offsets: HashMap<String, u64>,
+        wait_ms: u64,
+    },
+    /// between nodes: keys a `Watch` was waiting on got messages
+    Appended {
+        keys: Vec<String>,
+    },
+    ApplyRetention,
+    Tick,
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+/// How often timeouts are checked.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a coordinator waits before resending unanswered 2PC messages,
+/// and a participant before asking its coordinator about a transaction again.
+const TXN_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Error code for a `poll` below the first offset retention has kept.
+const OFFSET_OUT_OF_RANGE: u64 = 1000;
+/// Error code for an idempotent `send` whose sequence number is older than
+/// anything left in the producer's dedup window, so it cannot be told apart
+/// from a duplicate.
+const SEQUENCE_TOO_OLD: u64 = 1001;
+
+/// Number of sequence numbers remembered per producer and key, unless
+/// `MALEN_KAFKA_DEDUP_WINDOW` says otherwise.
+const DEFAULT_DEDUP_WINDOW: usize = 64;
+
+/// Outcome of appending a message to a log.
+enum Append {
+    Appended(u64),
+    /// the producer already sent this sequence number; it was stored here
+    Duplicate(u64),
+    SequenceTooOld {
+        oldest: u64,
+    },
+}
+
+/// What a `poll` for an offset that retention already removed gets back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TruncatedPoll {
+    /// return messages from the first offset that is still kept
+    Skip,
+    /// reply with an `OFFSET_OUT_OF_RANGE` error
+    Error,
+}
+
+impl FromStr for TruncatedPoll {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(TruncatedPoll::Skip),
+            "error" => Ok(TruncatedPoll::Error),
+            other => anyhow::bail!("expected skip or error, got {}", other),
+        }
+    }
+}
+
+/// How much of each key's log is kept, read from the environment:
+///
+/// - `MALEN_KAFKA_RETENTION_MESSAGES`: newest messages to keep per key
+/// - `MALEN_KAFKA_RETENTION_MS`: maximum message age
+/// - `MALEN_KAFKA_RETENTION_BYTES`: maximum stored size per key
+/// - `MALEN_KAFKA_COMPACT`: drop messages below the lowest committed offset
+///   of all consumer groups
+/// - `MALEN_KAFKA_RETENTION_CHECK_MS`: how often the policy is applied
+/// - `MALEN_KAFKA_TRUNCATED_POLL`: `skip` (default) or `error`
+#[derive(Debug, Clone)]
+struct RetentionPolicy {
+    max_messages: Option<u64>,
+    max_age_ms: Option<u64>,
+    max_bytes: Option<u64>,
+    compact: bool,
+    check_interval: Duration,
+    truncated_poll: TruncatedPoll,
+}
+
+impl RetentionPolicy {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_messages: env_var("MALEN_KAFKA_RETENTION_MESSAGES")?,
+            max_age_ms: env_var("MALEN_KAFKA_RETENTION_MS")?,
+            max_bytes: env_var("MALEN_KAFKA_RETENTION_BYTES")?,
+            compact: env_or("MALEN_KAFKA_COMPACT", false)?,
+            check_interval: Duration::from_millis(env_or("MALEN_KAFKA_RETENTION_CHECK_MS", 1000)?),
+            truncated_poll: env_or("MALEN_KAFKA_TRUNCATED_POLL", TruncatedPoll::Skip)?,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_messages.is_some()
+            || self.max_age_ms.is_some()
+            || self.max_bytes.is_some()
+            || self.compact
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Limits on how much a single `poll` returns. `None` means unlimited, and
+/// a message limit of 0 returns nothing.
+///
+/// Node-wide defaults come from the `MALEN_KAFKA_POLL_*` environment
+/// variables; any limit set on a `poll` request replaces the default.
+#[derive(Debug, Clone, Default)]
+struct PollLimits {
+    max_messages_per_key: Option<usize>,
+    max_messages: Option<usize>,
+    max_bytes_per_key: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl PollLimits {
+    /// What the old fixed `offset..=offset + 10` window returned.
+    const DEFAULT_MAX_MESSAGES_PER_KEY: usize = 11;
+
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_messages_per_key: Some(env_or(
+                "MALEN_KAFKA_POLL_MAX_MESSAGES_PER_KEY",
+                Self::DEFAULT_MAX_MESSAGES_PER_KEY,
+            )?),
+            max_messages: env_var("MALEN_KAFKA_POLL_MAX_MESSAGES")?,
+            max_bytes_per_key: env_var("MALEN_KAFKA_POLL_MAX_BYTES_PER_KEY")?,
+            max_bytes: env_var("MALEN_KAFKA_POLL_MAX_BYTES")?,
+        })
+    }
+
+    fn with_overrides(&self, overrides: &PollLimits) -> PollLimits {
+        PollLimits {
+            max_messages_per_key: overrides.max_messages_per_key.or(self.max_messages_per_key),
+            max_messages: overrides.max_messages.or(self.max_messages),
+            max_bytes_per_key: overrides.max_bytes_per_key.or(self.max_bytes_per_key),
+            max_bytes: overrides.max_bytes.or(self.max_bytes),
+        }
+    }
+}
+
+// size of a polled entry as it is encoded in the `poll_ok` reply
+fn entry_bytes(entry: &[u64]) -> usize {
+    serde_json::to_vec(entry).map_or(0, |bytes| bytes.len())
+}
+
+// a record as it is returned by `poll`
+fn poll_entry(record: Record, timestamps: bool) -> Vec<u64> {
+    let mut entry = vec![record.offset, record.msg];
+    if timestamps {
+        entry.push(record.timestamp);
+        entry.extend(record.producer_timestamp);
+    }
+    entry
+}
+
+/// Key -> entries of a `poll_ok`.
+type PolledMsgs = HashMap<String, Vec<Vec<u64>>>;
+
+/// Transaction id -> keys and offsets of its messages.
+type TxnOffsets = HashMap<String, Vec<(String, u64)>>;
+
+/// Consumer group used when a request does not name one, which keeps the
+/// single shared cursor that the Maelstrom workload expects.
+const DEFAULT_GROUP: &str = "default";
+
+/// What `describe_key` reports about the messages a log still holds.
+struct LogStats {
+    first_offset: Option<u64>,
+    last_offset: Option<u64>,
+    count: u64,
+    bytes: u64,
+}
+
+struct Log {
+    current_offset: u64,
+    // the last record is a tombstone
+    deleted: bool,
+    // broker timestamp of the newest record
+    last_timestamp: u64,
+    storage: Box<dyn Storage>,
+    producers: ProducerWindows,
+    dedup_window: usize,
+    // leader epoch -> first offset appended in it
+    epochs: BTreeMap<u64, u64>,
+    // epoch this node leads the key in, if it does
+    leading: Option<u64>,
+}
+impl Log {
+    fn new(storage: Box<dyn Storage>, dedup_window: usize) -> anyhow::Result<Self> {
+        let mut log = Self {
+            current_offset: storage.last_offset().unwrap_or(0),
+            deleted: false,
+            last_timestamp: 0,
+            producers: storage.producers().clone(),
+            storage,
+            dedup_window,
+            epochs: BTreeMap::new(),
+            leading: None,
+        };
+
+        // the windows saved at the last truncation plus the records kept
+        // since give the windows as they were before a restart; replaying a
+        // record the snapshot already has changes nothing
+        let mut from = log.start_offset();
+        loop {
+            let records = log.storage.read(from, 1024)?;
+            let Some(last) = records.last() else {
+                break;
+            };
+            from = last.offset + 1;
+            log.last_timestamp = last.timestamp;
+            log.deleted = last.tombstone;
+            for record in &records {
+                log.track_producer(record);
+                log.track_epoch(record);
+            }
+        }
+
+        Ok(log)
+    }
+
+    fn track_epoch(&mut self, record: &Record) {
+        if self.last_epoch() < record.epoch {
+            self.epochs.insert(record.epoch, record.offset);
+        }
+    }
+
+    /// Epoch of the newest record.
+    fn last_epoch(&self) -> u64 {
+        self.epochs.keys().next_back().copied().unwrap_or(0)
+    }
+
+    /// Offset just past the records appended in `epoch` or before it, which
+    /// is where a replica whose last record is from `epoch` diverges.
+    fn epoch_end(&self, epoch: u64) -> u64 {
+        self.epochs
+            .range(epoch + 1..)
+            .next()
+            .map_or(self.current_offset + 1, |(_, start)| *start)
+    }
+
+    /// Fold producer windows copied from the leader into this replica's.
+    fn merge_producers(&mut self, producers: &ProducerWindows) {
+        for (producer_id, window) in producers {
+            for (&seq, &offset) in window {
+                self.track(producer_id, seq, offset);
+            }
+        }
+    }
+
+    fn track_producer(&mut self, record: &Record) {
+        if let (Some(producer_id), Some(seq)) = (&record.producer_id, record.seq) {
+            self.track(producer_id, seq, record.offset);
+        }
+    }
+
+    fn track(&mut self, producer_id: &str, seq: u64, offset: u64) {
+        let window = self.producers.entry(producer_id.to_string()).or_default();
+        window.insert(seq, offset);
+        while window.len() > self.dedup_window {
+            window.pop_first();
+        }
+    }
+
+    fn insert_message(
+        &mut self,
+        msg: u64,
+        producer: Option<(&str, u64)>,
+        producer_timestamp: Option<u64>,
+        txn_id: Option<&str>,
+    ) -> anyhow::Result<Append> {
+        if let Some((producer_id, seq)) = producer {
+            if let Some(window) = self.producers.get(producer_id) {
+                if let Some(offset) = window.get(&seq) {
+                    return Ok(Append::Duplicate(*offset));
+                }
+                if let Some(&oldest) = window.keys().next() {
+                    if window.len() >= self.dedup_window && seq < oldest {
+                        return Ok(Append::SequenceTooOld { oldest });
+                    }
+                }
+            }
+        }
+
+        // the broker clock can step back, but lookups by time rely on the
+        // timestamps of a key never decreasing
+        let record = Record {
+            offset: self.current_offset + 1,
+            msg,
+            timestamp: now_millis().max(self.last_timestamp),
+            producer_timestamp,
+            producer_id: producer.map(|(producer_id, _)| producer_id.to_string()),
+            seq: producer.map(|(_, seq)| seq),
+            txn_id: txn_id.map(str::to_string),
+            tombstone: false,
+            epoch: self.leading.unwrap_or(0),
+        };
+        self.append(&record)?;
+
+        Ok(Append::Appended(self.current_offset))
+    }
+
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.storage.append(record)?;
+        self.current_offset = record.offset;
+        self.last_timestamp = self.last_timestamp.max(record.timestamp);
+        self.deleted = record.tombstone;
+        self.track_epoch(record);
+        if record.tombstone {
+            // producers resending what they sent before the delete still
+            // get the offset it had instead of a second copy
+            self.truncate_before(record.offset)?;
+        } else {
+            self.track_producer(record);
+        }
+        Ok(())
+    }
+
+    /// Drop every record before `offset`, saving the dedup windows first
+    /// since they can no longer be rebuilt from the records.
+    fn truncate_before(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.storage.save_producers(&self.producers)?;
+        self.storage.truncate_before(offset)
+    }
+
+    /// Drop every record from `offset` on, where this replica's log diverged
+    /// from the leader's. Sends deduplicated against the dropped records
+    /// are forgotten with them.
+    fn truncate_from(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.storage.truncate_from(offset)?;
+        self.current_offset = self.current_offset.min(offset.saturating_sub(1));
+        self.epochs.retain(|_, start| *start < offset);
+        for window in self.producers.values_mut() {
+            window.retain(|_, appended| *appended < offset);
+        }
+        self.producers.retain(|_, window| !window.is_empty());
+        self.storage.save_producers(&self.producers)?;
+        self.deleted = self
+            .storage
+            .read(self.current_offset, 1)?
+            .first()
+            .is_some_and(|record| record.offset == self.current_offset && record.tombstone);
+        Ok(())
+    }
+
+    /// Append a record copied from the leader, unless it is already here.
+    fn append_replica(&mut self, record: &Record) -> anyhow::Result<()> {
+        if record.offset <= self.current_offset {
+            return Ok(());
+        }
+        self.append(record)
+    }
+
+    /// Offset a follower fetches from next.
+    fn next_offset(&self) -> u64 {
+        (self.current_offset + 1).max(self.start_offset())
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.storage.start_offset()
+    }
+
+    /// Append a tombstone and drop every message before it. Offsets keep
+    /// counting up if the key is sent to again.
+    fn delete(&mut self) -> anyhow::Result<u64> {
+        let record = Record {
+            offset: self.current_offset + 1,
+            msg: 0,
+            timestamp: now_millis().max(self.last_timestamp),
+            producer_timestamp: None,
+            producer_id: None,
+            seq: None,
+            txn_id: None,
+            tombstone: true,
+            epoch: self.leading.unwrap_or(0),
+        };
+        self.append(&record)?;
+        Ok(record.offset)
+    }
+
+    fn stats(&self) -> anyhow::Result<LogStats> {
+        let mut stats = LogStats {
+            first_offset: None,
+            last_offset: None,
+            count: 0,
+            bytes: 0,
+        };
+        let mut from = self.start_offset();
+        loop {
+            let records = self.storage.read(from, 1024)?;
+            let Some(last) = records.last() else {
+                break;
+            };
+            from = last.offset + 1;
+            for record in records.iter().filter(|record| !record.tombstone) {
+                stats.first_offset.get_or_insert(record.offset);
+                stats.last_offset = Some(record.offset);
+                stats.count += 1;
+                stats.bytes += record.bytes();
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Drop whatever the retention policy no longer keeps.
+    fn apply_retention(&mut self, policy: &RetentionPolicy, now: u64) -> anyhow::Result<()> {
+        let mut keep_from = Vec::new();
+        if let Some(max) = policy.max_messages {
+            // offsets are dense, so the newest `max` messages start here
+            keep_from.push((self.current_offset + 1).saturating_sub(max));
+        }
+        if let Some(max_age) = policy.max_age_ms {
+            let oldest = now.saturating_sub(max_age);
+            keep_from.push(
+                self.storage
+                    .offset_for_timestamp(oldest)?
+                    .unwrap_or(self.current_offset + 1),
+            );
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            keep_from.extend(self.storage.offset_for_size(max_bytes));
+        }
+        if policy.compact {
+            keep_from.extend(self.committed_offsets().values().min().copied());
+        }
+
+        match keep_from.into_iter().max() {
+            Some(offset) if offset > self.start_offset() => {
+                tracing::info!("Retention truncating log before offset {}", offset);
+                self.truncate_before(offset)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read(&self, offset: u64, max: usize) -> anyhow::Result<Vec<Record>> {
+        self.storage.read(offset, max)
+    }
+
+    /// Earliest offset still kept whose broker timestamp is at or after
+    /// `timestamp`.
+    fn offset_for_timestamp(&self, timestamp: u64) -> anyhow::Result<Option<u64>> {
+        self.storage.offset_for_timestamp(timestamp)
+    }
+
+    fn commit(&mut self, group: &str, offset: u64) -> anyhow::Result<()> {
+        self.storage.commit(group, offset)
+    }
+
+    fn committed_offsets(&self) -> &HashMap<String, u64> {
+        self.storage.committed_offsets()
+    }
+
+    fn committed_offset(&self, group: &str) -> Option<u64> {
+        self.committed_offsets().get(group).copied()
+    }
+
+    // number of messages appended after the group's committed offset
+    fn lag(&self, group: &str) -> u64 {
+        self.current_offset
+            .saturating_sub(self.committed_offset(group).unwrap_or(0))
+    }
+}
+
+/// A client request split across the nodes that own its keys, waiting for
+/// every part to come back.
+struct Gather {
+    request: Message<Payload>,
+    reply: Option<Payload>,
+    parts: HashSet<usize>,
+    deadline: Instant,
+    // a long poll that comes back empty is parked until then
+    wait_until: Option<Instant>,
+}
+
+/// A long poll waiting for messages to be appended to one of its keys.
+struct ParkedPoll {
+    request: Message<Payload>,
+    wait_until: Instant,
+}
+
+impl ParkedPoll {
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        match &self.request.body.payload {
+            Payload::Poll { offsets, .. } => Some(offsets.keys()),
+            _ => None,
+        }
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// A long poll on another node, waiting for messages on keys this node owns.
+struct KeyWatch {
+    node: String,
+    keys: HashSet<String>,
+    until: Instant,
+}
+
+/// Merge the reply to one part of a split request into the combined reply.
+fn merge_reply(reply: &mut Option<Payload>, part: Payload) {
+    match (reply.as_mut(), part) {
+        (None, part) => *reply = Some(part),
+        (Some(Payload::Error { .. }), _) => {}
+        (Some(_), part @ Payload::Error { .. }) => *reply = Some(part),
+        (
+            Some(Payload::PollOk {
+                msgs,
+                txn_offsets,
+                hidden_txns,
+            }),
+            Payload::PollOk {
+                msgs: part,
+                txn_offsets: part_txn_offsets,
+                hidden_txns: part_hidden_txns,
+            },
+        ) => {
+            msgs.extend(part);
+            for (txn_id, offsets) in part_txn_offsets {
+                txn_offsets.entry(txn_id).or_default().extend(offsets);
+            }
+            hidden_txns.extend(part_hidden_txns);
+        }
+        (
+            Some(Payload::ListCommittedOffsetsOk { offsets }),
+            Payload::ListCommittedOffsetsOk { offsets: part },
+        ) => offsets.extend(part),
+        (
+            Some(Payload::OffsetsForTimesOk { offsets }),
+            Payload::OffsetsForTimesOk { offsets: part },
+        ) => offsets.extend(part),
+        (Some(Payload::ListKeysOk { keys }), Payload::ListKeysOk { keys: part }) => {
+            keys.extend(part);
+            keys.sort();
+            keys.dedup();
+        }
+        (Some(Payload::ListGroupsOk { groups }), Payload::ListGroupsOk { groups: part }) => {
+            for (group, lag) in part {
+                groups.entry(group).or_default().extend(lag);
+            }
+        }
+        (
+            Some(Payload::DescribeGroupOk { offsets, lag }),
+            Payload::DescribeGroupOk {
+                offsets: part_offsets,
+                lag: part_lag,
+            },
+        ) => {
+            offsets.extend(part_offsets);
+            lag.extend(part_lag);
+        }
+        (Some(_), _) => {}
+    }
+}
+
+/// Drop the messages of transactions that one owner showed while another
+/// still hid them, and everything after them on the same key, so a poll sees
+/// all of a transaction or none of it. Owners answer at different times, and
+/// one may have heard the transaction is complete before another.
+fn hide_partial_txns(
+    msgs: &mut HashMap<String, Vec<Vec<u64>>>,
+    txn_offsets: &TxnOffsets,
+    hidden_txns: &HashSet<String>,
+) {
+    for txn_id in hidden_txns {
+        for (key, offset) in txn_offsets.get(txn_id).into_iter().flatten() {
+            if let Some(entries) = msgs.get_mut(key) {
+                entries.retain(|entry| entry[0] < *offset);
+            }
+        }
+    }
+}
+
+/// Take messages from each key in turn until the limits are reached, so that
+/// a busy key cannot use up the whole reply.
+fn round_robin(
+    entries: Vec<(String, Vec<Vec<u64>>)>,
+    limits: &PollLimits,
+) -> HashMap<String, Vec<Vec<u64>>> {
+    let mut msgs: HashMap<String, Vec<Vec<u64>>> = HashMap::new();
+    let mut cursors = Vec::new();
+    for (key, messages) in entries {
+        msgs.insert(key.clone(), Vec::new());
+        cursors.push((key, messages.into_iter().peekable(), 0));
+    }
+    cursors.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut total_messages = 0;
+    let mut total_bytes = 0;
+    let mut full = limits.max_messages == Some(0);
+    while !full && !cursors.is_empty() {
+        // each round takes at most one message from every key still in play
+        cursors.retain_mut(|(key, messages, key_bytes)| {
+            if full {
+                return true;
+            }
+            let key_msgs = msgs.get_mut(key).expect("poll key missing");
+            let Some(entry) = messages.peek() else {
+                return false;
+            };
+            let bytes = entry_bytes(entry);
+
+            // always hand out the first message so a consumer can make progress
+            if total_messages > 0
+                && limits
+                    .max_bytes
+                    .is_some_and(|max| total_bytes + bytes > max)
+            {
+                full = true;
+                return true;
+            }
+            if limits
+                .max_messages_per_key
+                .is_some_and(|max| key_msgs.len() >= max)
+                || (!key_msgs.is_empty()
+                    && limits
+                        .max_bytes_per_key
+                        .is_some_and(|max| *key_bytes + bytes > max))
+            {
+                return false;
+            }
+
+            key_msgs.extend(messages.next());
+            *key_bytes += bytes;
+            total_messages += 1;
+            total_bytes += bytes;
+            full = limits.max_messages.is_some_and(|max| total_messages >= max);
+            true
+        });
+    }
+
+    msgs
+}
+
+/// The reply to a poll of `entries`, taken round-robin within `limits`, for
+/// a backend whose sends are never part of a transaction.
+fn poll_reply(entries: Vec<(String, Vec<Vec<u64>>)>, limits: &PollLimits) -> Payload {
+    Payload::PollOk {
+        msgs: round_robin(entries, limits),
+        txn_offsets: HashMap::new(),
+        hidden_txns: HashSet::new(),
+    }
+}
+
+struct Kafka {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    logs: HashMap<String, Log>,
+    poll_limits: PollLimits,
+    storage: StorageConfig,
+    retention: RetentionPolicy,
+    dedup_window: usize,
+    request_timeout: Duration,
+    txn_timeout: Duration,
+    // split requests by gather id, and the gather id of each outstanding part
+    gathers: HashMap<usize, Gather>,
+    gather_parts: HashMap<usize, usize>,
+    txn_id: u64,
+    // millisecond the node started, part of every txn id so that a restarted
+    // coordinator cannot reuse the id of a transaction it forgot
+    started: u64,
+    txns: HashMap<String, CoordinatedTxn>,
+    participant: Participant,
+    subscription_id: u64,
+    subscriptions: HashMap<String, Subscription>,
+    parked_polls: Vec<ParkedPoll>,
+    // long polls of other nodes on keys owned here
+    watches: Vec<KeyWatch>,
+    // keys appended to while handling the current message
+    appended: HashSet<String>,
+    replication_config: ReplicationConfig,
+    replication: Replication,
+    // set when the lin-kv backend is used instead of local logs
+    lin_kv: Option<LinKv>,
+    tx: Option<Sender<Message<Payload>>>,
+}
+
+impl Kafka {
+    // reopen every key a previous run of this node left in storage
+    fn load_logs(&mut self) -> anyhow::Result<()> {
+        for key in self.storage.existing_keys(&self.node_id)? {
+            let log = Log::new(self.storage.open(&self.node_id, &key)?, self.dedup_window)?;
+            tracing::info!("Recovered key {} at offset {}", key, log.current_offset);
+            self.logs.insert(key, log);
+        }
+        Ok(())
+    }
+
+    /// Node responsible for `key`. Without replication every node computes
+    /// the same owner; with it, the owner is whichever replica leads the key.
+    fn owner(&self, key: &str) -> String {
+        if self.replication_config.factor > 1 && self.lin_kv.is_none() {
+            return self.leader(key);
+        }
+        self.node_ids
+            .get(self.owner_index(key))
+            .cloned()
+            .unwrap_or_else(|| self.node_id.clone())
+    }
+
+    fn owner_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.node_ids.len().max(1) as u64) as usize
+    }
+
+    fn owns(&self, key: &str) -> bool {
+        self.owner(key) == self.node_id
+    }
+
+    fn is_peer(&self, src: &str) -> bool {
+        self.node_ids.iter().any(|node_id| node_id == src)
+    }
+
+    // keys polled below the offset retention has kept, with their start offset
+    fn truncated_keys(&self, offsets: &HashMap<String, u64>) -> Vec<(String, u64)> {
+        offsets
+            .iter()
+            .filter_map(|(key, offset)| {
+                let start = self.logs.get(key)?.start_offset();
+                (*offset < start).then(|| (key.clone(), start))
+            })
+            .collect()
+    }
+
+    fn log_mut(&mut self, key: &str) -> anyhow::Result<&mut Log> {
+        if !self.logs.contains_key(key) {
+            let log = Log::new(self.storage.open(&self.node_id, key)?, self.dedup_window)?;
+            self.logs.insert(key.to_string(), log);
+        }
+        Ok(self.logs.get_mut(key).expect("log was just inserted"))
+    }
+
+    /// Collect messages for every requested key held by this node, leaving
+    /// out transactions not every participant has appended yet. Also gives
+    /// where the transactional messages among them are.
+    fn poll(
+        &self,
+        offsets: &HashMap<String, u64>,
+        limits: &PollLimits,
+        timestamps: bool,
+    ) -> anyhow::Result<(PolledMsgs, TxnOffsets)> {
+        // no key can contribute more than either message limit allows
+        let max_read = limits
+            .max_messages_per_key
+            .into_iter()
+            .chain(limits.max_messages)
+            .min()
+            .unwrap_or(usize::MAX);
+
+        let mut entries = Vec::new();
+        let mut txn_offsets: TxnOffsets = HashMap::new();
+        for (key, offset) in offsets {
+            let hidden_from = self.participant.hidden_from(key);
+            let records = match self.logs.get(key) {
+                Some(log) => log.read(*offset, max_read)?,
+                None => Vec::new(),
+            };
+            let mut messages = Vec::new();
+            for record in records
+                .into_iter()
+                .take_while(|record| hidden_from.is_none_or(|hidden| record.offset < hidden))
+                .filter(|record| !record.tombstone)
+            {
+                if let Some(txn_id) = &record.txn_id {
+                    let offsets = txn_offsets.entry(txn_id.clone()).or_default();
+                    offsets.push((key.clone(), record.offset));
+                }
+                messages.push(poll_entry(record, timestamps));
+            }
+            entries.push((key.clone(), messages));
+        }
+
+        Ok((round_robin(entries, limits), txn_offsets))
+    }
+
+    // logs this node leads, leaving out the ones it only follows
+    fn owned_logs(&self) -> impl Iterator<Item = (&String, &Log)> {
+        self.logs.iter().filter(|(key, _)| self.owns(key))
+    }
+
+    fn groups(&self) -> HashSet<String> {
+        self.owned_logs()
+            .flat_map(|(_, log)| log.committed_offsets().keys().cloned())
+            .collect()
+    }
+
+    fn group_lag(&self, group: &str) -> HashMap<String, u64> {
+        self.owned_logs()
+            .map(|(key, log)| (key.clone(), log.lag(group)))
+            .collect()
+    }
+
+    /// Split a client request into the part each node has to answer.
+    fn split_request(&self, payload: &Payload) -> HashMap<String, Payload> {
+        let mut parts = HashMap::new();
+        match payload {
+            Payload::Send { key, .. }
+            | Payload::DescribeKey { key }
+            | Payload::DeleteKey { key } => {
+                parts.insert(self.owner(key), payload.clone());
+            }
+            Payload::Poll {
+                offsets,
+                max_messages_per_key,
+                max_messages,
+                max_bytes_per_key,
+                max_bytes,
+                timestamps,
+                ..
+            } => {
+                let mut by_owner: HashMap<String, HashMap<String, u64>> = HashMap::new();
+                for (key, offset) in offsets {
+                    by_owner
+                        .entry(self.owner(key))
+                        .or_default()
+                        .insert(key.clone(), *offset);
+                }
+                for (node, offsets) in by_owner {
+                    let part = Payload::Poll {
+                        offsets,
+                        max_messages_per_key: *max_messages_per_key,
+                        max_messages: *max_messages,
+                        max_bytes_per_key: *max_bytes_per_key,
+                        max_bytes: *max_bytes,
+                        timestamps: *timestamps,
+                        // the node holding the client request does the waiting
+                        wait_ms: None,
+                    };
+                    parts.insert(node, part);
+                }
+            }
+            Payload::CommitOffsets { offsets, group } => {
+                let mut by_owner: HashMap<String, HashMap<String, u64>> = HashMap::new();
+                for (key, offset) in offsets {
+                    by_owner
+                        .entry(self.owner(key))
+                        .or_default()
+                        .insert(key.clone(), *offset);
+                }
+                for (node, offsets) in by_owner {
+                    let group = group.clone();
+                    parts.insert(node, Payload::CommitOffsets { offsets, group });
+                }
+            }
+            Payload::ListCommittedOffsets { keys, group } => {
+                let mut by_owner: HashMap<String, Vec<String>> = HashMap::new();
+                for key in keys {
+                    by_owner
+                        .entry(self.owner(key))
+                        .or_default()
+                        .push(key.clone());
+                }
+                for (node, keys) in by_owner {
+                    let group = group.clone();
+                    parts.insert(node, Payload::ListCommittedOffsets { keys, group });
+                }
+            }
+            Payload::OffsetsForTimes { times } => {
+                let mut by_owner: HashMap<String, HashMap<String, u64>> = HashMap::new();
+                for (key, time) in times {
+                    by_owner
+                        .entry(self.owner(key))
+                        .or_default()
+                        .insert(key.clone(), *time);
+                }
+                for (node, times) in by_owner {
+                    parts.insert(node, Payload::OffsetsForTimes { times });
+                }
+            }
+            Payload::Subscribe {
+                offsets,
+                max_in_flight,
+                subscription,
+                client,
+            } => {
+                let mut by_owner: HashMap<String, HashMap<String, u64>> = HashMap::new();
+                for (key, offset) in offsets {
+                    by_owner
+                        .entry(self.owner(key))
+                        .or_default()
+                        .insert(key.clone(), *offset);
+                }
+                for (node, offsets) in by_owner {
+                    let part = Payload::Subscribe {
+                        offsets,
+                        max_in_flight: *max_in_flight,
+                        subscription: subscription.clone(),
+                        client: client.clone(),
+                    };
+                    parts.insert(node, part);
+                }
+            }
+            Payload::ListKeys
+            | Payload::ListGroups
+            | Payload::DescribeGroup { .. }
+            | Payload::Unsubscribe { .. } => {
+                let now = Instant::now();
+                for node in self.node_ids.iter().filter(|node| self.is_alive(node, now)) {
+                    parts.insert(node.clone(), payload.clone());
+                }
+            }
+            _ => {}
+        }
+        parts
+    }
+
+    /// Answer a request from the logs this node holds.
+    fn handle_local(&mut self, payload: &Payload) -> anyhow::Result<Payload> {
+        let reply = match payload {
+            Payload::Send {
+                key,
+                msg,
+                producer_id,
+                seq,
+                timestamp,
+            } => {
+                let producer = match (producer_id.as_deref(), seq) {
+                    (Some(producer_id), Some(seq)) => Some((producer_id, *seq)),
+                    _ => None,
+                };
+                if producer_id.is_some() != seq.is_some() {
+                    Payload::Error {
+                        code: error_code::MALFORMED_REQUEST,
+                        text: "producer_id and seq must be sent together".to_string(),
+                    }
+                } else if let Some(error) = self.lead(key)? {
+                    error
+                } else {
+                    match self.log_mut(key)?.insert_message(*msg, producer, *timestamp, None)? {
+                        Append::Appended(offset) => {
+                            self.appended.insert(key.clone());
+                            Payload::SendOk { offset }
+                        }
+                        Append::Duplicate(offset) => {
+                            tracing::info!("Duplicate send {:?} at offset {}", producer, offset);
+                            Payload::SendOk { offset }
+                        }
+                        Append::SequenceTooOld { oldest } => Payload::Error {
+                            code: SEQUENCE_TOO_OLD,
+                            text: format!(
+                                "seq {:?} is older than the dedup window of producer {:?}, which starts at {}",
+                                seq, producer_id, oldest
+                            ),
+                        },
+                    }
+                }
+            }
+
+            Payload::Poll {
+                offsets,
+                max_messages_per_key,
+                max_messages,
+                max_bytes_per_key,
+                max_bytes,
+                timestamps,
+                ..
+            } => {
+                let limits = self.poll_limits.with_overrides(&PollLimits {
+                    max_messages_per_key: *max_messages_per_key,
+                    max_messages: *max_messages,
+                    max_bytes_per_key: *max_bytes_per_key,
+                    max_bytes: *max_bytes,
+                });
+
+                let truncated = self.truncated_keys(offsets);
+                if self.retention.truncated_poll == TruncatedPoll::Error && !truncated.is_empty() {
+                    let text = truncated
+                        .iter()
+                        .map(|(key, start)| format!("{} starts at offset {}", key, start))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    Payload::Error {
+                        code: OFFSET_OUT_OF_RANGE,
+                        text: format!("offsets out of range: {}", text),
+                    }
+                } else {
+                    let (msgs, txn_offsets) = self.poll(offsets, &limits, *timestamps)?;
+                    Payload::PollOk {
+                        msgs,
+                        txn_offsets,
+                        hidden_txns: self.participant.pending_on(&offsets.keys().collect()),
+                    }
+                }
+            }
+
+            Payload::CommitOffsets { offsets, group } => {
+                for key in offsets.keys() {
+                    if let Some(error) = self.lead(key)? {
+                        return Ok(error);
+                    }
+                }
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                for (key, offset) in offsets {
+                    self.log_mut(key)?.commit(group, *offset)?;
+                }
+                Payload::CommitOffsetsOk
+            }
+
+            Payload::ListCommittedOffsets { keys, group } => {
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                let mut committed_offsets = HashMap::new();
+                for key in keys {
+                    // keys the group never committed are left out of the reply
+                    if let Some(offset) = self
+                        .logs
+                        .get(key)
+                        .and_then(|log| log.committed_offset(group))
+                    {
+                        committed_offsets.insert(key.clone(), offset);
+                    }
+                }
+                Payload::ListCommittedOffsetsOk {
+                    offsets: committed_offsets,
+                }
+            }
+
+            Payload::OffsetsForTimes { times } => {
+                let mut offsets = HashMap::new();
+                for (key, time) in times {
+                    // keys with nothing at or after the time are left out
+                    if let Some(log) = self.logs.get(key) {
+                        if let Some(offset) = log.offset_for_timestamp(*time)? {
+                            offsets.insert(key.clone(), offset);
+                        }
+                    }
+                }
+                Payload::OffsetsForTimesOk { offsets }
+            }
+
+            Payload::Subscribe {
+                offsets,
+                max_in_flight,
+                subscription: Some(subscription),
+                client: Some(client),
+            } => {
+                tracing::info!(
+                    "Subscribing {} to {:?} as {}",
+                    client,
+                    offsets,
+                    subscription
+                );
+                self.subscriptions.insert(
+                    subscription.clone(),
+                    Subscription {
+                        client: client.clone(),
+                        offsets: offsets.clone(),
+                        max_in_flight: max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+                        in_flight: HashMap::new(),
+                    },
+                );
+                // push whatever is already there
+                self.appended.extend(offsets.keys().cloned());
+                Payload::SubscribeOk {
+                    subscription: subscription.clone(),
+                }
+            }
+
+            Payload::Unsubscribe { subscription } => {
+                self.subscriptions.remove(subscription);
+                Payload::UnsubscribeOk
+            }
+
+            Payload::ListKeys => {
+                let mut keys: Vec<String> = self
+                    .owned_logs()
+                    .filter(|(_, log)| !log.deleted)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                keys.sort();
+                Payload::ListKeysOk { keys }
+            }
+
+            Payload::DescribeKey { key } => match self.logs.get(key) {
+                Some(log) if !log.deleted => {
+                    let stats = log.stats()?;
+                    Payload::DescribeKeyOk {
+                        key: key.clone(),
+                        owner: self.node_id.clone(),
+                        first_offset: stats.first_offset,
+                        last_offset: stats.last_offset,
+                        committed: log.committed_offsets().clone(),
+                        count: stats.count,
+                        bytes: stats.bytes,
+                        replicas: self.replicas(key),
+                        in_sync: self.in_sync_replicas(key, Instant::now()),
+                    }
+                }
+                _ => Payload::Error {
+                    code: error_code::KEY_DOES_NOT_EXIST,
+                    text: format!("key {} does not exist", key),
+                },
+            },
+
+            Payload::DeleteKey { key } => {
+                if self.logs.get(key).is_none_or(|log| log.deleted) {
+                    Payload::Error {
+                        code: error_code::KEY_DOES_NOT_EXIST,
+                        text: format!("key {} does not exist", key),
+                    }
+                } else if let Some(error) = self.lead(key)? {
+                    error
+                } else {
+                    let offset = self.log_mut(key)?.delete()?;
+                    tracing::info!("Deleted key {} at offset {}", key, offset);
+                    Payload::DeleteKeyOk { offset }
+                }
+            }
+
+            Payload::ListGroups => {
+                // only the keys a group committed, since a node that owns
+                // none of them does not know the group
+                let groups = self
+                    .groups()
+                    .into_iter()
+                    .map(|group| {
+                        let mut lag = self.group_lag(&group);
+                        lag.retain(|key, _| self.logs[key].committed_offset(&group).is_some());
+                        (group, lag)
+                    })
+                    .collect();
+                Payload::ListGroupsOk { groups }
+            }
+
+            Payload::DescribeGroup { group } => {
+                let offsets = self
+                    .owned_logs()
+                    .filter_map(|(key, log)| {
+                        log.committed_offset(group)
+                            .map(|offset| (key.clone(), offset))
+                    })
+                    .collect();
+                let lag = self.group_lag(group);
+                Payload::DescribeGroupOk { offsets, lag }
+            }
+
+            other => anyhow::bail!("{:?} cannot be answered locally", other),
+        };
+        Ok(reply)
+    }
+
+    /// Answer a client request, forwarding the parts owned by other nodes and
+    /// replying once they have all come back.
+    fn route(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        // requests from other nodes are already split by owner
+        if self.is_peer(&input_msg.src) {
+            let payload = self.handle_local(&input_msg.body.payload)?;
+            if let (Payload::Send { key, .. }, Payload::SendOk { offset }) =
+                (&input_msg.body.payload, &payload)
+            {
+                if self.needs_acks(key, *offset) {
+                    let (key, offset) = (key.clone(), *offset);
+                    self.await_acks(&key, offset, AckWaiter::Reply(Box::new(input_msg)));
+                    return Ok(());
+                }
+            }
+            let reply = input_msg.into_reply(self.get_msg_id(), payload);
+            return writer.write_message(&reply);
+        }
+
+        let mut input_msg = input_msg;
+        let wait_until = match &mut input_msg.body.payload {
+            Payload::Poll {
+                wait_ms: Some(wait_ms),
+                ..
+            } => Some(Instant::now() + Duration::from_millis(*wait_ms)),
+            Payload::Subscribe {
+                subscription,
+                client,
+                ..
+            } => {
+                // every owner pushes its keys straight to the client
+                self.subscription_id += 1;
+                *subscription = Some(format!("{}-{}", self.node_id, self.subscription_id));
+                *client = Some(input_msg.src.clone());
+                None
+            }
+            _ => None,
+        };
+        self.gather(input_msg, wait_until, writer)
+    }
+
+    // send each part of a client request to its owner
+    fn gather(
+        &mut self,
+        input_msg: Message<Payload>,
+        wait_until: Option<Instant>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let gather_id = self.get_msg_id().expect("No message id");
+        let mut gather = Gather {
+            request: input_msg.clone(),
+            reply: None,
+            parts: HashSet::new(),
+            deadline: Instant::now() + self.request_timeout,
+            wait_until,
+        };
+        for (node, part) in self.split_request(&input_msg.body.payload) {
+            if node == self.node_id {
+                let local = self.handle_local(&part)?;
+                if let (Payload::Send { key, .. }, Payload::SendOk { offset }) = (&part, &local) {
+                    // wait for the followers like for a part on another node
+                    if self.needs_acks(key, *offset) {
+                        let part_id = self.get_msg_id().expect("No message id");
+                        gather.parts.insert(part_id);
+                        self.gather_parts.insert(part_id, gather_id);
+                        self.await_acks(key, *offset, AckWaiter::Part(part_id));
+                        continue;
+                    }
+                }
+                merge_reply(&mut gather.reply, local);
+                continue;
+            }
+
+            let msg_id = self.get_msg_id().expect("No message id");
+            let forward = Message {
+                src: self.node_id.clone(),
+                dest: node,
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload: part,
+                },
+            };
+            writer.write_message(&forward)?;
+            gather.parts.insert(msg_id);
+            self.gather_parts.insert(msg_id, gather_id);
+        }
+
+        if gather.parts.is_empty() {
+            self.finish_gather(gather, writer)
+        } else {
+            self.gathers.insert(gather_id, gather);
+            Ok(())
+        }
+    }
+
+    // a reply from the owner of part of a split request
+    fn handle_part_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(gather_id) = input_msg
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.gather_parts.remove(&msg_id))
+        else {
+            tracing::info!("Ignoring unexpected reply: {:?}", input_msg);
+            return Ok(());
+        };
+        let Some(gather) = self.gathers.get_mut(&gather_id) else {
+            return Ok(());
+        };
+
+        if let Some(msg_id) = input_msg.body.in_reply_to {
+            gather.parts.remove(&msg_id);
+        }
+        merge_reply(&mut gather.reply, input_msg.body.payload);
+
+        if gather.parts.is_empty() {
+            let gather = self
+                .gathers
+                .remove(&gather_id)
+                .expect("gather was just used");
+            self.finish_gather(gather, writer)?;
+        }
+        Ok(())
+    }
+
+    fn finish_gather(&mut self, gather: Gather, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let mut payload = gather.reply.unwrap_or(Payload::Error {
+            code: error_code::MALFORMED_REQUEST,
+            text: "request has no keys".to_string(),
+        });
+
+        // each owner applied the poll limits on its own, so apply the totals
+        // again to the combined reply
+        if let (
+            Payload::PollOk {
+                msgs,
+                txn_offsets,
+                hidden_txns,
+            },
+            Payload::Poll {
+                max_messages_per_key,
+                max_messages,
+                max_bytes_per_key,
+                max_bytes,
+                ..
+            },
+        ) = (&mut payload, &gather.request.body.payload)
+        {
+            let limits = self.poll_limits.with_overrides(&PollLimits {
+                max_messages_per_key: *max_messages_per_key,
+                max_messages: *max_messages,
+                max_bytes_per_key: *max_bytes_per_key,
+                max_bytes: *max_bytes,
+            });
+            hide_partial_txns(msgs, txn_offsets, hidden_txns);
+            txn_offsets.clear();
+            hidden_txns.clear();
+            let entries = msgs.drain().collect();
+            *msgs = round_robin(entries, &limits);
+        }
+
+        if let (Some(wait_until), Payload::PollOk { msgs, .. }) = (gather.wait_until, &payload) {
+            if Instant::now() < wait_until && msgs.values().all(Vec::is_empty) {
+                self.watch_remote_keys(&gather.request, wait_until, writer)?;
+                self.parked_polls.push(ParkedPoll {
+                    request: gather.request,
+                    wait_until,
+                });
+                return Ok(());
+            }
+        }
+
+        let reply = gather.request.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)
+    }
+
+    // ask the owners of a parked poll's other keys to say when those get
+    // messages, which wakes the poll like an append here does
+    fn watch_remote_keys(
+        &mut self,
+        request: &Message<Payload>,
+        wait_until: Instant,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Payload::Poll { offsets, .. } = &request.body.payload else {
+            return Ok(());
+        };
+        let mut by_owner: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for (key, offset) in offsets {
+            let owner = self.owner(key);
+            if owner != self.node_id {
+                by_owner
+                    .entry(owner)
+                    .or_default()
+                    .insert(key.clone(), *offset);
+            }
+        }
+        let wait_ms = wait_until
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u64;
+        for (node, offsets) in by_owner {
+            self.send_to(&node, Payload::Watch { offsets, wait_ms }, writer)?;
+        }
+        Ok(())
+    }
+
+    // another node parked a long poll on keys owned here; anything already
+    // there wakes it right away
+    fn watch(
+        &mut self,
+        node: &str,
+        offsets: &HashMap<String, u64>,
+        wait_ms: u64,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .poll(offsets, &self.poll_limits, false)?
+            .0
+            .into_iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(key, _)| key)
+            .collect();
+        if !keys.is_empty() {
+            return self.send_to(node, Payload::Appended { keys }, writer);
+        }
+        self.watches.push(KeyWatch {
+            node: node.to_string(),
+            keys: offsets.keys().cloned().collect(),
+            until: Instant::now() + Duration::from_millis(wait_ms),
+        });
+        Ok(())
+    }
+
+    fn send_to(
+        &mut self,
+        dest: &str,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.get_msg_id();
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id,
+                in_reply_to: None,
+                payload,
+            },
+        };
+        writer.write_message(&message)
+    }
+
+    /// Start coordinating a transactional send.
+    fn begin_txn(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Payload::SendTxn { ref msgs } = input_msg.body.payload else {
+            anyhow::bail!("not a send_txn request");
+        };
+        if msgs.is_empty() {
+            let reply = input_msg.into_reply(
+                self.get_msg_id(),
+                Payload::SendTxnOk {
+                    offsets: Vec::new(),
+                },
+            );
+            return writer.write_message(&reply);
+        }
+
+        self.txn_id += 1;
+        let txn_id = format!("{}-{}-{}", self.node_id, self.started, self.txn_id);
+        let mut owners = Vec::new();
+        let mut parts: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+        for (key, msg) in msgs {
+            let owner = self.owner(key);
+            parts
+                .entry(owner.clone())
+                .or_default()
+                .push((key.clone(), *msg));
+            owners.push(owner);
+        }
+        tracing::info!("Starting transaction {} across {:?}", txn_id, parts.keys());
+
+        let now = Instant::now();
+        let txn = CoordinatedTxn {
+            request: Some(input_msg),
+            owners,
+            parts,
+            votes: HashMap::new(),
+            decision: None,
+            acks: HashMap::new(),
+            failed: Vec::new(),
+            deadline: now + self.txn_timeout,
+            retry_at: now + TXN_RETRY_INTERVAL,
+        };
+        let parts: Vec<(String, Vec<(String, u64)>)> = txn
+            .parts
+            .iter()
+            .map(|(node, part)| (node.clone(), part.clone()))
+            .collect();
+        let participants: Vec<String> = txn.parts.keys().cloned().collect();
+        self.txns.insert(txn_id.clone(), txn);
+
+        for (node, msgs) in parts {
+            if node == self.node_id {
+                let coordinator = self.node_id.clone();
+                let prepared = self.prepare_txn(&txn_id, msgs, &coordinator, participants.clone());
+                if let Some(txn) = self.txns.get_mut(&txn_id) {
+                    txn.votes.insert(node, prepared);
+                }
+            } else {
+                let payload = Payload::TxnPrepare {
+                    txn_id: txn_id.clone(),
+                    msgs,
+                    participants: participants.clone(),
+                };
+                self.send_to(&node, payload, writer)?;
+            }
+        }
+
+        self.advance_txn(&txn_id, writer)
+    }
+
+    /// Move a coordinated transaction along: decide once the votes are in,
+    /// tell the participants, and answer the client once all of them know.
+    fn advance_txn(&mut self, txn_id: &str, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let Some(txn) = self.txns.get_mut(txn_id) else {
+            return Ok(());
+        };
+        if let Some(decision) = txn.decide_from_votes() {
+            tracing::info!("Transaction {} decided: {:?}", txn_id, decision);
+            let participants: Vec<String> = txn.parts.keys().cloned().collect();
+            for node in participants {
+                self.send_decision(txn_id, &node, decision, writer)?;
+            }
+        }
+
+        // participants that committed may show their messages once all of
+        // them have, and hear so before the client does, so that a poll
+        // after the reply sees the whole transaction
+        let Some(txn) = self.txns.get(txn_id) else {
+            return Ok(());
+        };
+        let finished = txn.is_finished();
+        if finished && txn.decision == Some(Decision::Commit) {
+            let participants: Vec<String> = txn.parts.keys().cloned().collect();
+            for node in participants {
+                if node == self.node_id {
+                    self.complete_txn(txn_id, writer)?;
+                } else {
+                    let txn_id = txn_id.to_string();
+                    self.send_to(&node, Payload::TxnComplete { txn_id }, writer)?;
+                }
+            }
+        }
+
+        let Some(txn) = self.txns.get_mut(txn_id) else {
+            return Ok(());
+        };
+        let payload = match txn.decision {
+            Some(Decision::Abort) => Some(Payload::Error {
+                code: error_code::ABORT,
+                text: format!("transaction {} aborted", txn_id),
+            }),
+            Some(Decision::Commit) if txn.is_finished() && !txn.failed.is_empty() => {
+                Some(Payload::Error {
+                    code: error_code::CRASH,
+                    text: format!(
+                        "transaction {} committed only in part: {}",
+                        txn_id,
+                        txn.failed.join(", ")
+                    ),
+                })
+            }
+            Some(Decision::Commit) if txn.is_finished() => Some(Payload::SendTxnOk {
+                offsets: txn.offsets(),
+            }),
+            _ => None,
+        };
+        if let Some(payload) = payload {
+            if let Some(request) = txn.request.take() {
+                let reply = request.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+        }
+
+        // keep retrying the decision until every participant has it
+        if finished {
+            self.txns.remove(txn_id);
+        }
+        Ok(())
+    }
+
+    fn send_decision(
+        &mut self,
+        txn_id: &str,
+        node: &str,
+        decision: Decision,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        if node == self.node_id {
+            let reply = match decision {
+                Decision::Commit => self.commit_txn(txn_id)?,
+                Decision::Abort => {
+                    self.abort_txn(txn_id);
+                    Payload::TxnAbortOk {
+                        txn_id: txn_id.to_string(),
+                    }
+                }
+            };
+            self.record_ack(node, reply);
+            return Ok(());
+        }
+
+        let txn_id = txn_id.to_string();
+        let payload = match decision {
+            Decision::Commit => Payload::TxnCommit { txn_id },
+            Decision::Abort => Payload::TxnAbort { txn_id },
+        };
+        self.send_to(node, payload, writer)
+    }
+
+    // coordinator side: note a participant's answer to the decision
+    fn record_ack(&mut self, node: &str, reply: Payload) {
+        let (txn_id, offsets, failure) = match reply {
+            Payload::TxnCommitOk { txn_id, offsets } => (txn_id, offsets, None),
+            Payload::TxnAbortOk { txn_id } => (txn_id, Vec::new(), None),
+            Payload::TxnCommitFailed { txn_id, text } => (txn_id, Vec::new(), Some(text)),
+            _ => return,
+        };
+        let Some(txn) = self.txns.get_mut(&txn_id) else {
+            return;
+        };
+        if txn.acks.insert(node.to_string(), offsets).is_none() {
+            if let Some(text) = failure {
+                tracing::info!("{} could not commit {}: {}", node, txn_id, text);
+                txn.failed.push(format!("{}: {}", node, text));
+            }
+        }
+    }
+
+    // coordinator side: a participant is still waiting on `txn_id`. An
+    // undecided transaction it asks about has run out of time. One this node
+    // no longer knows is finished or was lost in a restart, and either way no
+    // decision is coming: the participant settles it with the others.
+    fn txn_status(
+        &mut self,
+        txn_id: &str,
+        node: &str,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some(txn) = self.txns.get_mut(txn_id) else {
+            let payload = Payload::TxnComplete {
+                txn_id: txn_id.to_string(),
+            };
+            if node == self.node_id {
+                return self.complete_txn(txn_id, writer);
+            }
+            return self.send_to(node, payload, writer);
+        };
+        match txn.decision {
+            None => {
+                tracing::info!("Aborting {}: {} is still waiting for it", txn_id, node);
+                txn.deadline = Instant::now();
+                Ok(())
+            }
+            Some(decision) if !txn.acks.contains_key(node) => {
+                self.send_decision(txn_id, node, decision, writer)?;
+                self.advance_txn(txn_id, writer)
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    // participant side: vote to commit if every key is ours, and hold the
+    // messages back until the decision arrives. A transaction decided here
+    // already gets a no: this prepare arrived after its decision did.
+    fn prepare_txn(
+        &mut self,
+        txn_id: &str,
+        msgs: Vec<(String, u64)>,
+        coordinator: &str,
+        participants: Vec<String>,
+    ) -> bool {
+        if self.participant.is_decided(txn_id) {
+            tracing::info!("Voting to abort {}: already decided", txn_id);
+            return false;
+        }
+        if msgs.iter().any(|(key, _)| self.owner(key) != self.node_id) {
+            tracing::info!("Voting to abort {}: not the owner of every key", txn_id);
+            return false;
+        }
+        for (key, _) in &msgs {
+            if !matches!(self.lead(key), Ok(None)) {
+                tracing::info!("Voting to abort {}: cannot lead {}", txn_id, key);
+                return false;
+            }
+        }
+        let check_at = Instant::now() + self.txn_timeout;
+        self.participant
+            .prepared
+            .entry(txn_id.to_string())
+            .or_insert(PreparedTxn {
+                msgs,
+                coordinator: coordinator.to_string(),
+                participants,
+                uncommitted: HashSet::new(),
+                check_at,
+            });
+        true
+    }
+
+    // participant side: append the prepared messages, hidden from polls
+    // until the coordinator says every participant has
+    fn commit_txn(&mut self, txn_id: &str) -> anyhow::Result<Payload> {
+        if let Some(offsets) = self.participant.committed(txn_id) {
+            return Ok(Payload::TxnCommitOk {
+                txn_id: txn_id.to_string(),
+                offsets: offsets.clone(),
+            });
+        }
+        let Some(prepared) = self.participant.prepared.remove(txn_id) else {
+            tracing::info!("Commit for unprepared transaction {}", txn_id);
+            return Ok(Payload::TxnCommitFailed {
+                txn_id: txn_id.to_string(),
+                text: format!("transaction {} is not prepared on {}", txn_id, self.node_id),
+            });
+        };
+
+        let mut offsets = Vec::with_capacity(prepared.msgs.len());
+        for (key, msg) in prepared.msgs {
+            let log = self.log_mut(&key)?;
+            let offset = match log.insert_message(msg, None, None, Some(txn_id))? {
+                Append::Appended(offset) | Append::Duplicate(offset) => offset,
+                Append::SequenceTooOld { .. } => {
+                    unreachable!("transactional sends have no producer")
+                }
+            };
+            offsets.push((key, offset));
+        }
+        self.participant.committing.insert(
+            txn_id.to_string(),
+            CommittingTxn {
+                offsets: offsets.clone(),
+                coordinator: prepared.coordinator,
+                check_at: Instant::now() + self.txn_timeout,
+            },
+        );
+        Ok(Payload::TxnCommitOk {
+            txn_id: txn_id.to_string(),
+            offsets,
+        })
+    }
+
+    fn abort_txn(&mut self, txn_id: &str) {
+        self.participant.prepared.remove(txn_id);
+        if self.participant.committed(txn_id).is_none() {
+            self.participant.remember_abort(txn_id);
+        }
+    }
+
+    // participant side: the coordinator is done with `txn_id`, so committed
+    // messages can be shown. One still only prepared missed the decision, or
+    // the coordinator lost it in a restart: the transaction committed if any
+    // other participant committed it, so ask them.
+    fn complete_txn(&mut self, txn_id: &str, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        if let Some(txn) = self.participant.committing.remove(txn_id) {
+            self.appended
+                .extend(txn.offsets.iter().map(|(key, _)| key.clone()));
+            self.participant.remember_commit(txn_id, txn.offsets);
+            return Ok(());
+        }
+        let Some(txn) = self.participant.prepared.get(txn_id) else {
+            return Ok(());
+        };
+        let asking: Vec<String> = txn
+            .participants
+            .iter()
+            .filter(|node| **node != self.node_id && !txn.uncommitted.contains(*node))
+            .cloned()
+            .collect();
+        if asking.is_empty() {
+            tracing::info!("No participant committed {}, aborting it", txn_id);
+            self.abort_txn(txn_id);
+            return Ok(());
+        }
+        for node in asking {
+            let txn_id = txn_id.to_string();
+            self.send_to(&node, Payload::TxnOutcome { txn_id }, writer)?;
+        }
+        Ok(())
+    }
+
+    // participant side: another participant's answer to `TxnOutcome`
+    fn txn_outcome(
+        &mut self,
+        txn_id: &str,
+        node: &str,
+        committed: bool,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        if !self.participant.prepared.contains_key(txn_id) {
+            return Ok(());
+        }
+        if committed {
+            tracing::info!("{} committed {}, committing it too", node, txn_id);
+            self.commit_txn(txn_id)?;
+        } else if let Some(txn) = self.participant.prepared.get_mut(txn_id) {
+            txn.uncommitted.insert(node.to_string());
+        }
+        self.complete_txn(txn_id, writer)
+    }
+
+    /// Push to subscribers and answer long polls waiting on the keys that
+    /// were appended to since the last call.
+    fn wake(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        if self.appended.is_empty() {
+            return Ok(());
+        }
+        let appended = std::mem::take(&mut self.appended);
+
+        let subscriptions: Vec<String> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| {
+                subscription
+                    .offsets
+                    .keys()
+                    .any(|key| appended.contains(key))
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in subscriptions {
+            self.push(&id, writer)?;
+        }
+
+        let (woken, parked) = std::mem::take(&mut self.parked_polls)
+            .into_iter()
+            .partition(|poll| poll.keys().any(|key| appended.contains(key)));
+        self.parked_polls = parked;
+        for poll in woken {
+            self.gather(poll.request, Some(poll.wait_until), writer)?;
+        }
+
+        let mut notify = Vec::new();
+        self.watches.retain(|watch| {
+            let keys: Vec<String> = watch.keys.intersection(&appended).cloned().collect();
+            if keys.is_empty() {
+                return true;
+            }
+            notify.push((watch.node.clone(), keys));
+            false
+        });
+        for (node, keys) in notify {
+            self.send_to(&node, Payload::Appended { keys }, writer)?;
+        }
+        Ok(())
+    }
+
+    // send a subscriber as much as its unacknowledged deliveries allow
+    fn push(&mut self, id: &str, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let Some(subscription) = self.subscriptions.get(id) else {
+            return Ok(());
+        };
+        let credit = subscription.credit();
+        if credit == 0 {
+            return Ok(());
+        }
+        let limits = self.poll_limits.with_overrides(&PollLimits {
+            max_messages: Some(credit),
+            ..PollLimits::default()
+        });
+        let msgs: HashMap<String, Vec<Vec<u64>>> = self
+            .poll(&subscription.offsets, &limits, false)?
+            .0
+            .into_iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect();
+        if msgs.is_empty() {
+            return Ok(());
+        }
+
+        let msg_id = self.get_msg_id().expect("No message id");
+        let subscription = self
+            .subscriptions
+            .get_mut(id)
+            .expect("subscription was just read");
+        for (key, entries) in &msgs {
+            if let Some(last) = entries.last() {
+                subscription.offsets.insert(key.clone(), last[0] + 1);
+            }
+        }
+        subscription.in_flight.insert(
+            msg_id,
+            Delivery {
+                msgs: msgs.clone(),
+                sent_at: Instant::now(),
+            },
+        );
+        let deliver = Message {
+            src: self.node_id.clone(),
+            dest: subscription.client.clone(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: Payload::Deliver {
+                    subscription: id.to_string(),
+                    msgs,
+                },
+            },
+        };
+        writer.write_message(&deliver)
+    }
+
+    // a subscriber acknowledged a delivery, which frees room for more
+    fn handle_deliver_ok(&mut self, msg_id: usize) {
+        for subscription in self.subscriptions.values_mut() {
+            if subscription.in_flight.remove(&msg_id).is_some() {
+                self.appended.extend(subscription.offsets.keys().cloned());
+                return;
+            }
+        }
+    }
+
+    /// Time out requests and transactions, and retry unanswered 2PC messages.
+    fn handle_tick(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if self.lin_kv.is_some() {
+            self.kv_expire(now, writer)?;
+        } else if self.replication_config.factor > 1 {
+            self.replicate(now, writer)?;
+        }
+
+        // expired long polls get a last try and a reply
+        let (expired, parked) = std::mem::take(&mut self.parked_polls)
+            .into_iter()
+            .partition(|poll: &ParkedPoll| poll.wait_until <= now);
+        self.parked_polls = parked;
+        for poll in expired {
+            self.gather(poll.request, None, writer)?;
+        }
+        self.watches.retain(|watch| watch.until > now);
+
+        // deliveries that were not acknowledged in time are sent again
+        let mut redeliver = Vec::new();
+        for (id, subscription) in &mut self.subscriptions {
+            let expired: Vec<usize> = subscription
+                .in_flight
+                .iter()
+                .filter(|(_, delivery)| delivery.sent_at + self.request_timeout <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            for msg_id in expired {
+                if let Some(delivery) = subscription.in_flight.remove(&msg_id) {
+                    redeliver.push((id.clone(), subscription.client.clone(), delivery.msgs));
+                }
+            }
+        }
+        for (id, client, msgs) in redeliver {
+            let msg_id = self.get_msg_id().expect("No message id");
+            if let Some(subscription) = self.subscriptions.get_mut(&id) {
+                let delivery = Delivery {
+                    msgs: msgs.clone(),
+                    sent_at: now,
+                };
+                subscription.in_flight.insert(msg_id, delivery);
+            }
+            let deliver = Message {
+                src: self.node_id.clone(),
+                dest: client,
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload: Payload::Deliver {
+                        subscription: id,
+                        msgs,
+                    },
+                },
+            };
+            writer.write_message(&deliver)?;
+        }
+
+        let expired: Vec<usize> = self
+            .gathers
+            .iter()
+            .filter(|(_, gather)| gather.deadline <= now)
+            .map(|(gather_id, _)| *gather_id)
+            .collect();
+        for gather_id in expired {
+            let gather = self
+                .gathers
+                .remove(&gather_id)
+                .expect("gather was just found");
+            for msg_id in &gather.parts {
+                self.gather_parts.remove(msg_id);
+            }
+            let reply = gather.request.into_reply(
+                self.get_msg_id(),
+                Payload::Error {
+                    code: error_code::TIMEOUT,
+                    text: "timed out waiting for the owning nodes".to_string(),
+                },
+            );
+            writer.write_message(&reply)?;
+        }
+
+        let txn_ids: Vec<String> = self.txns.keys().cloned().collect();
+        for txn_id in txn_ids {
+            let Some(txn) = self.txns.get_mut(&txn_id) else {
+                continue;
+            };
+            if txn.decision.is_none() && txn.deadline <= now {
+                tracing::info!("Transaction {} timed out waiting for votes", txn_id);
+                txn.decision = Some(Decision::Abort);
+                let participants: Vec<String> = txn.parts.keys().cloned().collect();
+                for node in participants {
+                    self.send_decision(&txn_id, &node, Decision::Abort, writer)?;
+                }
+                self.advance_txn(&txn_id, writer)?;
+                continue;
+            }
+            if txn.retry_at > now {
+                continue;
+            }
+            txn.retry_at = now + TXN_RETRY_INTERVAL;
+
+            // resend whatever the participants have not answered yet
+            let mut resend = Vec::new();
+            for (node, part) in &txn.parts {
+                let payload = match txn.decision {
+                    None if !txn.votes.contains_key(node) => Payload::TxnPrepare {
+                        txn_id: txn_id.clone(),
+                        msgs: part.clone(),
+                        participants: txn.parts.keys().cloned().collect(),
+                    },
+                    Some(Decision::Commit) if !txn.acks.contains_key(node) => Payload::TxnCommit {
+                        txn_id: txn_id.clone(),
+                    },
+                    Some(Decision::Abort) if !txn.acks.contains_key(node) => Payload::TxnAbort {
+                        txn_id: txn_id.clone(),
+                    },
+                    _ => continue,
+                };
+                resend.push((node.clone(), payload));
+            }
+            for (node, payload) in resend {
+                self.send_to(&node, payload, writer)?;
+            }
+        }
+
+        // participants ask the coordinator about transactions it has gone
+        // quiet on, which also covers one that restarted and forgot them
+        for (txn_id, coordinator) in self.participant.overdue(now, TXN_RETRY_INTERVAL) {
+            if coordinator == self.node_id {
+                self.txn_status(&txn_id, &coordinator, writer)?;
+            } else {
+                self.send_to(&coordinator, Payload::TxnStatus { txn_id }, writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node<Payload> for Kafka {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.tx = Some(tx);
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        self.heard_from(&input_msg.src);
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+                self.load_logs()?;
+                if self.lin_kv.is_none() && self.replication_config.factor > 1 {
+                    self.start_replication(writer)?;
+                }
+
+                let tx = self.tx.clone().expect("node not initialized");
+                if self.retention.is_enabled() {
+                    spawn_ticker(
+                        tx.clone(),
+                        self.node_id.clone(),
+                        self.retention.check_interval,
+                        Payload::ApplyRetention,
+                    );
+                }
+                spawn_ticker(tx, self.node_id.clone(), TICK_INTERVAL, Payload::Tick);
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Send { .. }
+            | Payload::Poll { .. }
+            | Payload::CommitOffsets { .. }
+            | Payload::ListCommittedOffsets { .. }
+            | Payload::ListGroups
+            | Payload::DescribeGroup { .. }
+            | Payload::OffsetsForTimes { .. }
+            | Payload::ListKeys
+            | Payload::DescribeKey { .. }
+            | Payload::DeleteKey { .. }
+            | Payload::Subscribe { .. }
+            | Payload::Unsubscribe { .. } => {
+                tracing::info!(
+                    "Received request from {}: {:?}",
+                    input_msg.src,
+                    input_msg.body.payload
+                );
+                if self.lin_kv.is_some() {
+                    self.kv_request(input_msg, writer)?;
+                } else {
+                    self.route(input_msg, writer)?;
+                }
+            }
+
+            Payload::SendOk { .. }
+            | Payload::PollOk { .. }
+            | Payload::CommitOffsetsOk
+            | Payload::ListCommittedOffsetsOk { .. }
+            | Payload::ListGroupsOk { .. }
+            | Payload::DescribeGroupOk { .. }
+            | Payload::OffsetsForTimesOk { .. }
+            | Payload::ListKeysOk { .. }
+            | Payload::DescribeKeyOk { .. }
+            | Payload::DeleteKeyOk { .. }
+            | Payload::SubscribeOk { .. }
+            | Payload::UnsubscribeOk
+            | Payload::Error { .. } => {
+                if input_msg.src == LIN_KV {
+                    return self.kv_reply(input_msg, writer);
+                }
+                tracing::info!(
+                    "Received reply from {}: {:?}",
+                    input_msg.src,
+                    input_msg.body.payload
+                );
+                self.handle_part_reply(input_msg, writer)?;
+            }
+
+            Payload::SendTxn { .. } => {
+                tracing::info!("Received SendTxn message: {:?}", input_msg.body.payload);
+                if self.lin_kv.is_some() {
+                    self.kv_request(input_msg, writer)?;
+                } else {
+                    self.begin_txn(input_msg, writer)?;
+                }
+            }
+            Payload::SendTxnOk { .. } => {
+                // only clients are sent these; nothing here is waiting on one
+                tracing::info!("Ignoring unexpected SendTxnOk from {}", input_msg.src);
+            }
+
+            Payload::TxnPrepare {
+                ref txn_id,
+                ref msgs,
+                ref participants,
+            } => {
+                let prepared =
+                    self.prepare_txn(txn_id, msgs.clone(), &input_msg.src, participants.clone());
+                let payload = Payload::TxnVote {
+                    txn_id: txn_id.clone(),
+                    prepared,
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+            Payload::TxnVote {
+                ref txn_id,
+                prepared,
+            } => {
+                if let Some(txn) = self.txns.get_mut(txn_id) {
+                    txn.votes.insert(input_msg.src.clone(), prepared);
+                }
+                self.advance_txn(txn_id, writer)?;
+            }
+            Payload::TxnCommit { ref txn_id } => {
+                let payload = self.commit_txn(txn_id)?;
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+            Payload::TxnCommitOk { ref txn_id, .. }
+            | Payload::TxnCommitFailed { ref txn_id, .. }
+            | Payload::TxnAbortOk { ref txn_id } => {
+                let txn_id = txn_id.clone();
+                self.record_ack(&input_msg.src, input_msg.body.payload);
+                self.advance_txn(&txn_id, writer)?;
+            }
+            Payload::TxnAbort { ref txn_id } => {
+                self.abort_txn(txn_id);
+                let payload = Payload::TxnAbortOk {
+                    txn_id: txn_id.clone(),
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+            Payload::TxnStatus { ref txn_id } => self.txn_status(txn_id, &input_msg.src, writer)?,
+            Payload::TxnComplete { ref txn_id } => self.complete_txn(txn_id, writer)?,
+            Payload::TxnOutcome { ref txn_id } => {
+                let payload = Payload::TxnOutcomeOk {
+                    txn_id: txn_id.clone(),
+                    committed: self.participant.committed(txn_id).is_some(),
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+            Payload::TxnOutcomeOk {
+                ref txn_id,
+                committed,
+            } => self.txn_outcome(txn_id, &input_msg.src, committed, writer)?,
+
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk => {
+                self.kv_reply(input_msg, writer)?;
+            }
+            Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {
+                // these are meant for lin-kv, which a kafka node only calls
+                tracing::info!("Unexpected lin-kv request {:?}", input_msg.body.payload);
+                let payload = Payload::Error {
+                    code: error_code::NOT_SUPPORTED,
+                    text: format!("{} is not a key/value store", self.node_id),
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::Fetch { .. } => self.handle_fetch(input_msg, writer)?,
+            Payload::FetchOk { .. } => self.handle_fetch_ok(input_msg, writer)?,
+            Payload::Heartbeat { ref keys } => {
+                self.handle_heartbeat(&input_msg.src, keys, writer)?
+            }
+
+            Payload::Deliver { .. } => {
+                // pushed to subscribing clients only, never to a node
+                tracing::info!("Ignoring unexpected Deliver from {}", input_msg.src);
+            }
+            Payload::DeliverOk => {
+                if let Some(msg_id) = input_msg.body.in_reply_to {
+                    self.handle_deliver_ok(msg_id);
+                }
+            }
+
+            Payload::Watch {
+                ref offsets,
+                wait_ms,
+            } => self.watch(&input_msg.src, offsets, wait_ms, writer)?,
+            Payload::Appended { keys } => self.appended.extend(keys),
+
+            Payload::ApplyRetention => {
+                let now = now_millis();
+                for log in self.logs.values_mut() {
+                    log.apply_retention(&self.retention, now)?;
+                }
+            }
+            Payload::Tick => self.handle_tick(writer)?,
+        };
+        self.wake(writer)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let file_appender =
+        tracing_appender::rolling::daily("/Users/kyle/workspaces/malen/log", "maelstrom.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    tracing_subscriber::fmt().with_writer(non_blocking).init();
+    let mut node = Kafka {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        logs: HashMap::new(),
+        poll_limits: PollLimits::from_env()?,
+        storage: StorageConfig::from_env()?,
+        retention: RetentionPolicy::from_env()?,
+        dedup_window: env_or("MALEN_KAFKA_DEDUP_WINDOW", DEFAULT_DEDUP_WINDOW)?,
+        request_timeout: Duration::from_millis(env_or("MALEN_KAFKA_REQUEST_TIMEOUT_MS", 1000)?),
+        txn_timeout: Duration::from_millis(env_or("MALEN_KAFKA_TXN_TIMEOUT_MS", 1000)?),
+        gathers: HashMap::new(),
+        gather_parts: HashMap::new(),
+        txn_id: 0,
+        started: now_millis(),
+        txns: HashMap::new(),
+        participant: Participant::default(),
+        replication_config: ReplicationConfig::from_env()?,
+        replication: Replication::default(),
+        subscription_id: 0,
+        subscriptions: HashMap::new(),
+        parked_polls: Vec::new(),
+        watches: Vec::new(),
+        appended: HashSet::new(),
+        lin_kv: match env_or("MALEN_KAFKA_BACKEND", Backend::Local)? {
+            Backend::Local => None,
+            Backend::LinKv => Some(LinKv::from_env()?),
+        },
+        tx: None,
+    };
+
+    process_loop(&mut node)
+}
@@ -0,0 +1,689 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    config::env_or,
+    message::{error_code, Body, Message, MessageWriter},
+    node::Node,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Kafka, Payload};
+
+/// How keys are replicated, read from the environment:
+///
+/// - `MALEN_KAFKA_REPLICATION_FACTOR`: nodes holding each key; the replicas
+///   are the key's hash owner and the nodes after it in `node_ids`
+/// - `MALEN_KAFKA_MIN_INSYNC_ACKS`: in-sync replicas, the leader included,
+///   that must have a message before its `send` is acknowledged
+/// - `MALEN_KAFKA_FETCH_MAX_RECORDS`: most records one `fetch_ok` carries
+/// - `MALEN_KAFKA_REPLICA_LAG_MS`: how long a follower counts as in sync
+///   after it last had everything the leader had
+/// - `MALEN_KAFKA_FAILOVER_MS`: how long a node goes unheard before another
+///   replica takes over the keys it leads
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    pub factor: usize,
+    pub min_insync_acks: usize,
+    pub fetch_max_records: usize,
+    pub replica_lag: Duration,
+    pub failover_timeout: Duration,
+}
+
+impl ReplicationConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            factor: env_or("MALEN_KAFKA_REPLICATION_FACTOR", 1)?,
+            min_insync_acks: env_or("MALEN_KAFKA_MIN_INSYNC_ACKS", 1)?,
+            fetch_max_records: env_or("MALEN_KAFKA_FETCH_MAX_RECORDS", 500)?,
+            replica_lag: Duration::from_millis(env_or("MALEN_KAFKA_REPLICA_LAG_MS", 1000)?),
+            failover_timeout: Duration::from_millis(env_or("MALEN_KAFKA_FAILOVER_MS", 1000)?),
+        };
+        if config.min_insync_acks > config.factor.max(1) {
+            anyhow::bail!(
+                "MALEN_KAFKA_MIN_INSYNC_ACKS ({}) is larger than the replication factor ({})",
+                config.min_insync_acks,
+                config.factor
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// What a leader knows about one follower of one key.
+struct FollowerState {
+    /// the follower has every record below this offset
+    next_offset: u64,
+    /// last time the follower had everything the leader had
+    caught_up_at: Option<Instant>,
+    /// when the follower fetched before, and the leader's last offset then
+    last_fetch: Option<(Instant, u64)>,
+}
+
+impl FollowerState {
+    fn in_sync(&self, now: Instant, lag: Duration) -> bool {
+        self.caught_up_at
+            .is_some_and(|caught_up_at| now.duration_since(caught_up_at) <= lag)
+    }
+}
+
+/// What a replica has of one key, sent around in heartbeats so that the
+/// replicas agree on who leads it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaState {
+    /// epoch of the newest record
+    pub epoch: u64,
+    /// offset just past the newest record
+    pub next_offset: u64,
+    /// epoch the replica leads the key in, if it does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leading: Option<u64>,
+}
+
+/// What this node knows about another one.
+struct Peer {
+    heard_at: Instant,
+    // sent a heartbeat since this node started
+    reported: bool,
+    keys: HashMap<String, ReplicaState>,
+}
+
+/// Who gets the `send_ok` once enough replicas have a message.
+pub enum AckWaiter {
+    /// a send forwarded by another node, answered directly
+    Reply(Box<Message<Payload>>),
+    /// the local part of a client request this node gathers
+    Part(usize),
+}
+
+struct PendingAck {
+    key: String,
+    offset: u64,
+    deadline: Instant,
+    waiter: AckWaiter,
+}
+
+/// Replication state, on both the leader and the follower side.
+#[derive(Default)]
+pub struct Replication {
+    // key -> follower -> what the follower has
+    followers: HashMap<String, HashMap<String, FollowerState>>,
+    pending_acks: Vec<PendingAck>,
+    // leaders with an unanswered fetch from this node, and when it was sent
+    fetching: HashMap<String, Instant>,
+    peers: HashMap<String, Peer>,
+    heartbeat_at: Option<Instant>,
+}
+
+impl Kafka {
+    /// Nodes holding `key`, the owner first.
+    pub(super) fn replicas(&self, key: &str) -> Vec<String> {
+        let owner = self.owner_index(key);
+        let count = self
+            .replication_config
+            .factor
+            .clamp(1, self.node_ids.len().max(1));
+        (0..count)
+            .filter_map(|i| {
+                self.node_ids
+                    .get((owner + i) % self.node_ids.len().max(1))
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Start watching the other nodes, with a grace period in which every
+    /// node counts as alive until it reports.
+    pub(super) fn start_replication(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let now = Instant::now();
+        for node in self.node_ids.iter().filter(|node| **node != self.node_id) {
+            self.replication
+                .peers
+                .entry(node.clone())
+                .or_insert_with(|| Peer {
+                    heard_at: now,
+                    reported: false,
+                    keys: HashMap::new(),
+                });
+        }
+        self.heartbeat(now, writer)
+    }
+
+    /// Note that `node` is alive, whatever it sent.
+    pub(super) fn heard_from(&mut self, node: &str) {
+        if let Some(peer) = self.replication.peers.get_mut(node) {
+            peer.heard_at = Instant::now();
+        }
+    }
+
+    /// Whether `node` was heard from recently. Nodes are not watched without
+    /// replication, and always count as alive then.
+    pub(super) fn is_alive(&self, node: &str, now: Instant) -> bool {
+        node == self.node_id
+            || self.replication.peers.get(node).is_none_or(|peer| {
+                now.duration_since(peer.heard_at) <= self.replication_config.failover_timeout
+            })
+    }
+
+    fn replica_state(&self, node: &str, key: &str) -> ReplicaState {
+        if node == self.node_id {
+            return self
+                .logs
+                .get(key)
+                .map_or_else(ReplicaState::default, |log| ReplicaState {
+                    epoch: log.last_epoch(),
+                    next_offset: log.current_offset + 1,
+                    leading: log.leading,
+                });
+        }
+        self.replication
+            .peers
+            .get(node)
+            .and_then(|peer| peer.keys.get(key))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Replica that leads `key`: the live one leading in the newest epoch,
+    /// or if none does, the live one with the most of the log, which then
+    /// takes over. The hash owner comes first on a tie, so it leads as long
+    /// as it is up.
+    pub(super) fn leader(&self, key: &str) -> String {
+        let replicas = self.replicas(key);
+        let now = Instant::now();
+        let candidates: Vec<(usize, &String, ReplicaState)> = replicas
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| self.is_alive(node, now))
+            .map(|(i, node)| (i, node, self.replica_state(node, key)))
+            .collect();
+        let leading = candidates
+            .iter()
+            .filter(|(_, _, state)| state.leading.is_some())
+            .max_by_key(|(_, _, state)| state.leading);
+        let most_complete = candidates
+            .iter()
+            .max_by_key(|(i, _, state)| (state.epoch, state.next_offset, Reverse(*i)));
+        leading
+            .or(most_complete)
+            .map(|(_, node, _)| (*node).clone())
+            .or_else(|| replicas.first().cloned())
+            .unwrap_or_else(|| self.node_id.clone())
+    }
+
+    /// Make sure this node leads `key` before writing to it, taking it over
+    /// in a new epoch if nobody does. Gives the error to reply with when
+    /// another replica leads it, which happens while the nodes disagree
+    /// about a failed leader.
+    pub(super) fn lead(&mut self, key: &str) -> anyhow::Result<Option<Payload>> {
+        if self.replication_config.factor <= 1 {
+            return Ok(None);
+        }
+        let leader = self.leader(key);
+        let not_leading = |text: String| {
+            Ok(Some(Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text,
+            }))
+        };
+        if leader != self.node_id {
+            return not_leading(format!("{} is led by {}", key, leader));
+        }
+        if self.logs.get(key).is_some_and(|log| log.leading.is_some()) {
+            return Ok(None);
+        }
+
+        // a replica that has not reported yet may have records this node
+        // lacks, or lead the key already
+        let now = Instant::now();
+        let replicas = self.replicas(key);
+        let unknown = replicas.iter().find(|node| {
+            self.is_alive(node, now)
+                && self
+                    .replication
+                    .peers
+                    .get(*node)
+                    .is_some_and(|peer| !peer.reported)
+        });
+        if let Some(node) = unknown {
+            return not_leading(format!("waiting to hear from {} about {}", node, key));
+        }
+
+        // epochs only grow, and each node picks from its own residue class
+        // so that two nodes never claim the same one
+        let newest = replicas
+            .iter()
+            .map(|node| self.replica_state(node, key))
+            .map(|state| state.epoch.max(state.leading.unwrap_or(0)))
+            .max()
+            .unwrap_or(0);
+        let count = self.node_ids.len().max(1) as u64;
+        let index = self
+            .node_ids
+            .iter()
+            .position(|node| *node == self.node_id)
+            .unwrap_or(0) as u64;
+        let first = newest + 1;
+        let epoch = first + (index + count - first % count) % count;
+
+        tracing::info!("Leading {} in epoch {}", key, epoch);
+        self.log_mut(key)?.leading = Some(epoch);
+        // what followers fetched from an earlier leader says nothing about
+        // what they have of this one's log
+        self.replication.followers.remove(key);
+        self.replication.heartbeat_at = None;
+        Ok(None)
+    }
+
+    // tell every peer what this node has of each key
+    fn heartbeat(&mut self, now: Instant, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let peers: Vec<String> = self.replication.peers.keys().cloned().collect();
+        for peer in peers {
+            self.send_heartbeat(&peer, writer)?;
+        }
+        self.replication.heartbeat_at = Some(now);
+        Ok(())
+    }
+
+    fn send_heartbeat(&mut self, peer: &str, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let keys = self
+            .logs
+            .keys()
+            .map(|key| (key.clone(), self.replica_state(&self.node_id, key)))
+            .collect();
+        self.send_to(peer, Payload::Heartbeat { keys }, writer)
+    }
+
+    pub(super) fn handle_heartbeat(
+        &mut self,
+        src: &str,
+        keys: &HashMap<String, ReplicaState>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let peer = self
+            .replication
+            .peers
+            .entry(src.to_string())
+            .or_insert_with(|| Peer {
+                heard_at: Instant::now(),
+                reported: false,
+                keys: HashMap::new(),
+            });
+        let first = !peer.reported;
+        peer.reported = true;
+        peer.keys = keys.clone();
+        // a node that just started learns who leads what straight away
+        if first && !self.node_ids.is_empty() {
+            self.send_heartbeat(src, writer)?;
+        }
+        Ok(())
+    }
+
+    /// Replicas of a key this node leads that are in sync, itself included.
+    pub(super) fn in_sync_replicas(&self, key: &str, now: Instant) -> Vec<String> {
+        let mut in_sync = vec![self.node_id.clone()];
+        if let Some(followers) = self.replication.followers.get(key) {
+            let lag = self.replication_config.replica_lag;
+            let mut synced: Vec<String> = followers
+                .iter()
+                .filter(|(_, follower)| follower.in_sync(now, lag))
+                .map(|(node, _)| node.clone())
+                .collect();
+            synced.sort();
+            in_sync.extend(synced);
+        }
+        in_sync
+    }
+
+    // in-sync replicas that have the record at `offset`, the leader included
+    fn acks(&self, key: &str, offset: u64, now: Instant) -> usize {
+        let lag = self.replication_config.replica_lag;
+        let followers = self.replication.followers.get(key).map_or(0, |followers| {
+            followers
+                .values()
+                .filter(|follower| follower.next_offset > offset && follower.in_sync(now, lag))
+                .count()
+        });
+        1 + followers
+    }
+
+    /// Whether the `send_ok` for `offset` has to wait for more in-sync
+    /// replicas to fetch it.
+    pub(super) fn needs_acks(&self, key: &str, offset: u64) -> bool {
+        self.acks(key, offset, Instant::now()) < self.replication_config.min_insync_acks
+    }
+
+    pub(super) fn await_acks(&mut self, key: &str, offset: u64, waiter: AckWaiter) {
+        self.replication.pending_acks.push(PendingAck {
+            key: key.to_string(),
+            offset,
+            deadline: Instant::now() + self.request_timeout,
+            waiter,
+        });
+    }
+
+    // answer the sends that have enough acks now, or ran out of time
+    fn check_acks(&mut self, now: Instant, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        for ack in std::mem::take(&mut self.replication.pending_acks) {
+            let acks = self.acks(&ack.key, ack.offset, now);
+            let payload = if acks >= self.replication_config.min_insync_acks {
+                Payload::SendOk { offset: ack.offset }
+            } else if ack.deadline <= now {
+                Payload::Error {
+                    code: error_code::TEMPORARILY_UNAVAILABLE,
+                    text: format!(
+                        "only {} of {} in-sync replicas have {} offset {}",
+                        acks, self.replication_config.min_insync_acks, ack.key, ack.offset
+                    ),
+                }
+            } else {
+                self.replication.pending_acks.push(ack);
+                continue;
+            };
+            self.resolve_ack(ack.waiter, payload, writer)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_ack(
+        &mut self,
+        waiter: AckWaiter,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match waiter {
+            AckWaiter::Reply(request) => {
+                let reply = request.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)
+            }
+            AckWaiter::Part(part_id) => {
+                let reply = Message {
+                    src: self.node_id.clone(),
+                    dest: self.node_id.clone(),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: Some(part_id),
+                        payload,
+                    },
+                };
+                self.handle_part_reply(reply, writer)
+            }
+        }
+    }
+
+    // nodes that can lead keys this node holds: any replica of a key is
+    // fewer than `factor` places away from the others in `node_ids`
+    fn leaders(&self, now: Instant) -> Vec<String> {
+        let count = self.node_ids.len();
+        let Some(me) = self.node_ids.iter().position(|node| *node == self.node_id) else {
+            return Vec::new();
+        };
+        let mut leaders: Vec<String> = (1..self.replication_config.factor.min(count))
+            .flat_map(|distance| [me + count - distance, me + distance])
+            .map(|i| self.node_ids[i % count].clone())
+            .filter(|node| self.is_alive(node, now))
+            .collect();
+        leaders.sort();
+        leaders.dedup();
+        leaders
+    }
+
+    /// Send a heartbeat when one is due, hand over keys another replica now
+    /// leads, fetch from every leader that has no fetch outstanding, and
+    /// time out sends that are still short of acks.
+    pub(super) fn replicate(
+        &mut self,
+        now: Instant,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let interval = self.replication_config.failover_timeout / 4;
+        if self
+            .replication
+            .heartbeat_at
+            .is_none_or(|sent_at| sent_at + interval <= now)
+        {
+            self.heartbeat(now, writer)?;
+        }
+
+        let led: Vec<String> = self
+            .logs
+            .iter()
+            .filter(|(_, log)| log.leading.is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in led {
+            let leader = self.leader(&key);
+            if leader != self.node_id {
+                tracing::info!("{} took over {}", leader, key);
+                if let Some(log) = self.logs.get_mut(&key) {
+                    log.leading = None;
+                }
+            }
+        }
+
+        for leader in self.leaders(now) {
+            let outstanding = self
+                .replication
+                .fetching
+                .get(&leader)
+                .is_some_and(|sent_at| *sent_at + self.request_timeout > now);
+            if !outstanding {
+                self.fetch(&leader, now, writer)?;
+            }
+        }
+        self.check_acks(now, writer)
+    }
+
+    // ask a leader for whatever this node is missing of the keys it leads;
+    // the leader picks the keys, so every key this node follows is listed
+    fn fetch(
+        &mut self,
+        leader: &str,
+        now: Instant,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let following = self.logs.iter().filter(|(_, log)| log.leading.is_none());
+        let mut offsets = HashMap::new();
+        let mut epochs = HashMap::new();
+        for (key, log) in following {
+            offsets.insert(key.clone(), log.next_offset());
+            epochs.insert(key.clone(), log.last_epoch());
+        }
+        self.replication.fetching.insert(leader.to_string(), now);
+        self.send_to(leader, Payload::Fetch { offsets, epochs }, writer)
+    }
+
+    /// Leader side: note what the follower has and send it the next batch.
+    pub(super) fn handle_fetch(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Payload::Fetch {
+            ref offsets,
+            ref epochs,
+        } = input_msg.body.payload
+        else {
+            anyhow::bail!("not a fetch request");
+        };
+        let follower = input_msg.src.clone();
+        let now = Instant::now();
+
+        // keys the follower does not know about yet are sent from the start
+        let mut keys: Vec<String> = self
+            .logs
+            .iter()
+            .filter(|(key, log)| log.leading.is_some() && self.replicas(key).contains(&follower))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+
+        let mut records = HashMap::new();
+        let mut truncate = HashMap::new();
+        let mut start_offsets = HashMap::new();
+        let mut producers = HashMap::new();
+        let mut committed = HashMap::new();
+        let mut budget = self.replication_config.fetch_max_records;
+        let mut more = false;
+        for key in keys {
+            let log = &self.logs[&key];
+            let mut next_offset = offsets.get(&key).copied().unwrap_or(0);
+            // records the follower has past the end of its newest epoch in
+            // this log were written by a leader this one replaced
+            if let Some(&epoch) = epochs.get(&key) {
+                let end = log.epoch_end(epoch);
+                if next_offset > end {
+                    truncate.insert(key.clone(), end);
+                    next_offset = end;
+                }
+            }
+            if !log.committed_offsets().is_empty() {
+                committed.insert(key.clone(), log.committed_offsets().clone());
+            }
+
+            let state = self
+                .replication
+                .followers
+                .entry(key.clone())
+                .or_default()
+                .entry(follower.clone())
+                .or_insert(FollowerState {
+                    next_offset,
+                    caught_up_at: None,
+                    last_fetch: None,
+                });
+            // a follower that restarted without its data starts over
+            state.next_offset = next_offset;
+            // having everything the leader had at the previous fetch counts
+            // as caught up as of then, so a follower that keeps fetching
+            // stays in sync while sends keep coming
+            if next_offset > log.current_offset {
+                state.caught_up_at = Some(now);
+            } else if let Some((fetched_at, end)) = state.last_fetch {
+                if next_offset > end {
+                    state.caught_up_at = state.caught_up_at.max(Some(fetched_at));
+                }
+            }
+            state.last_fetch = Some((now, log.current_offset));
+            if next_offset > log.current_offset {
+                continue;
+            }
+            if budget == 0 {
+                more = true;
+                continue;
+            }
+
+            let start = log.start_offset();
+            if next_offset < start {
+                start_offsets.insert(key.clone(), start);
+                producers.insert(key.clone(), log.producers.clone());
+            }
+            let batch = log.read(next_offset.max(start), budget)?;
+            if batch.is_empty() {
+                state.caught_up_at = Some(now);
+                continue;
+            }
+            budget -= batch.len();
+            records.insert(key, batch);
+        }
+
+        let payload = Payload::FetchOk {
+            records,
+            truncate,
+            start_offsets,
+            producers,
+            committed,
+            more: more || budget == 0,
+        };
+        let reply = input_msg.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)?;
+        self.check_acks(now, writer)
+    }
+
+    /// Follower side: store the batch and keep fetching while there is more.
+    pub(super) fn handle_fetch_ok(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let leader = input_msg.src;
+        self.replication.fetching.remove(&leader);
+        let Payload::FetchOk {
+            records,
+            truncate,
+            start_offsets,
+            producers,
+            committed,
+            more,
+        } = input_msg.body.payload
+        else {
+            anyhow::bail!("not a fetch_ok reply");
+        };
+        // a key this node took over in the meantime is its own to write
+        let leading =
+            |kafka: &Self, key: &str| kafka.logs.get(key).is_some_and(|log| log.leading.is_some());
+
+        let diverged = !truncate.is_empty();
+        for (key, offset) in truncate {
+            if leading(self, &key) {
+                continue;
+            }
+            tracing::info!(
+                "Dropping {} from offset {}, which diverged from {}",
+                key,
+                offset,
+                leader
+            );
+            self.log_mut(&key)?.truncate_from(offset)?;
+        }
+
+        // the leader's retention removed what we would have fetched next
+        for (key, start) in start_offsets {
+            if leading(self, &key) {
+                continue;
+            }
+            let log = self.log_mut(&key)?;
+            if let Some(producers) = producers.get(&key) {
+                log.merge_producers(producers);
+            }
+            if start > log.start_offset() {
+                tracing::info!("Truncating replica of {} before offset {}", key, start);
+                log.truncate_before(start)?;
+            }
+        }
+
+        let fetched = !records.is_empty();
+        for (key, records) in records {
+            if leading(self, &key) {
+                continue;
+            }
+            let log = self.log_mut(&key)?;
+            for record in &records {
+                log.append_replica(record)?;
+            }
+        }
+
+        // consumer groups carry on from where they were if this node takes over
+        for (key, groups) in committed {
+            if leading(self, &key) {
+                continue;
+            }
+            let log = self.log_mut(&key)?;
+            for (group, offset) in groups {
+                if log.committed_offset(&group) != Some(offset) {
+                    log.commit(&group, offset)?;
+                }
+            }
+        }
+
+        // report the new position straight away, which is what lets the
+        // leader acknowledge sends waiting on this node
+        if fetched || more || diverged {
+            self.fetch(&leader, Instant::now(), writer)?;
+        }
+        Ok(())
+    }
+}
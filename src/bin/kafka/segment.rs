@@ -0,0 +1,595 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::storage::{ProducerWindows, Record, Storage};
+
+// every record is framed as: payload length (u32 LE), crc32 of payload (u32 LE), payload
+const FRAME_HEADER_BYTES: u64 = 8;
+// sparse index entries are: record offset (u64 LE), file position (u64 LE)
+const INDEX_ENTRY_BYTES: u64 = 16;
+// anything claiming to be larger than this is treated as a torn header
+const MAX_RECORD_BYTES: usize = 16 * 1024 * 1024;
+const COMMITTED_OFFSETS_FILE: &str = "offsets.json";
+const PRODUCERS_FILE: &str = "producers.json";
+const START_OFFSET_FILE: &str = "start-offset";
+
+/// When appended data is flushed to disk.
+#[derive(Debug, Clone, Copy)]
+pub enum FsyncPolicy {
+    /// fsync after every append
+    Always,
+    /// fsync after this many appends
+    Every(u64),
+    /// leave flushing to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => {
+                let count = s
+                    .strip_prefix("every:")
+                    .context("expected always, never or every:<messages>")?;
+                Ok(FsyncPolicy::Every(
+                    count.parse().context("fsync message count")?,
+                ))
+            }
+        }
+    }
+}
+
+struct Segment {
+    log_path: PathBuf,
+    index_path: PathBuf,
+    size: u64,
+    first_offset: Option<u64>,
+    last_offset: Option<u64>,
+    max_timestamp: u64,
+    // (record offset, file position), sorted by offset
+    index: Vec<(u64, u64)>,
+    bytes_since_index: u64,
+}
+
+impl Segment {
+    fn new(log_path: PathBuf, index_path: PathBuf, size: u64) -> Self {
+        Segment {
+            log_path,
+            index_path,
+            size,
+            first_offset: None,
+            last_offset: None,
+            max_timestamp: 0,
+            index: Vec::new(),
+            bytes_since_index: 0,
+        }
+    }
+
+    fn track(&mut self, record: &Record) {
+        self.first_offset.get_or_insert(record.offset);
+        self.last_offset = Some(record.offset);
+        self.max_timestamp = self.max_timestamp.max(record.timestamp);
+    }
+
+    fn paths(dir: &Path, base_offset: u64) -> (PathBuf, PathBuf) {
+        (
+            dir.join(format!("{:020}.log", base_offset)),
+            dir.join(format!("{:020}.index", base_offset)),
+        )
+    }
+
+    // file position to start scanning from to find `offset`
+    fn position_for(&self, offset: u64) -> u64 {
+        let i = self.index.partition_point(|(o, _)| *o <= offset);
+        if i == 0 {
+            0
+        } else {
+            self.index[i - 1].1
+        }
+    }
+}
+
+/// Append-only segmented log on disk.
+///
+/// Each key gets its own directory holding `<base offset>.log` segment files,
+/// a sparse `<base offset>.index` per segment, the committed offsets of
+/// every consumer group and a snapshot of the producers' dedup windows. On
+/// open the newest segment is scanned and anything after the last intact
+/// record (a torn write from a crash) is truncated.
+///
+/// Retention removes whole segments; records below the start offset in the
+/// oldest remaining segment are hidden from reads instead.
+pub struct FileStorage {
+    dir: PathBuf,
+    segment_bytes: u64,
+    index_interval_bytes: u64,
+    fsync: FsyncPolicy,
+    segments: BTreeMap<u64, Segment>,
+    // log and index files of the newest segment, opened for appending
+    active: Option<(File, File)>,
+    unsynced: u64,
+    start_offset: u64,
+    committed_offsets: HashMap<String, u64>,
+    producers: ProducerWindows,
+}
+
+impl FileStorage {
+    pub fn open(
+        dir: PathBuf,
+        segment_bytes: u64,
+        index_interval_bytes: u64,
+        fsync: FsyncPolicy,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+
+        let mut base_offsets = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                if let Some(base) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+                {
+                    base_offsets.push(base);
+                }
+            }
+        }
+        base_offsets.sort();
+
+        let mut storage = FileStorage {
+            dir,
+            segment_bytes,
+            index_interval_bytes,
+            fsync,
+            segments: BTreeMap::new(),
+            active: None,
+            unsynced: 0,
+            start_offset: 0,
+            committed_offsets: HashMap::new(),
+            producers: HashMap::new(),
+        };
+
+        if let Some((&newest, older)) = base_offsets.split_last() {
+            for &base in older {
+                let segment = storage.load_segment(base)?;
+                storage.segments.insert(base, segment);
+            }
+            let segment = storage.recover_segment(newest)?;
+            let log = OpenOptions::new().append(true).open(&segment.log_path)?;
+            let index = OpenOptions::new().append(true).open(&segment.index_path)?;
+            storage.active = Some((log, index));
+            storage.segments.insert(newest, segment);
+        }
+
+        let offsets_path = storage.dir.join(COMMITTED_OFFSETS_FILE);
+        if offsets_path.exists() {
+            let contents = std::fs::read(&offsets_path)?;
+            storage.committed_offsets = serde_json::from_slice(&contents)
+                .with_context(|| format!("parse {}", offsets_path.display()))?;
+        }
+        let producers_path = storage.dir.join(PRODUCERS_FILE);
+        if producers_path.exists() {
+            let contents = std::fs::read(&producers_path)?;
+            storage.producers = serde_json::from_slice(&contents)
+                .with_context(|| format!("parse {}", producers_path.display()))?;
+        }
+        let start_path = storage.dir.join(START_OFFSET_FILE);
+        if start_path.exists() {
+            storage.start_offset = std::fs::read_to_string(&start_path)?
+                .trim()
+                .parse()
+                .with_context(|| format!("parse {}", start_path.display()))?;
+        }
+
+        Ok(storage)
+    }
+
+    // an older segment was complete when it was rolled, so its index is
+    // trusted; offsets and timestamps only grow, so the first record and the
+    // records after the last index entry give its bounds
+    fn load_segment(&self, base_offset: u64) -> anyhow::Result<Segment> {
+        let (log_path, index_path) = Segment::paths(&self.dir, base_offset);
+        let size = std::fs::metadata(&log_path)?.len();
+
+        let mut index = Vec::new();
+        if index_path.exists() {
+            let bytes = std::fs::read(&index_path)?;
+            for entry in bytes.chunks_exact(INDEX_ENTRY_BYTES as usize) {
+                let offset = u64::from_le_bytes(entry[..8].try_into()?);
+                let position = u64::from_le_bytes(entry[8..].try_into()?);
+                if position < size {
+                    index.push((offset, position));
+                }
+            }
+        }
+
+        let mut segment = Segment::new(log_path, index_path, size);
+        let (first, _) = scan(&segment.log_path, 0, 0, 1)?;
+        let tail_position = index.last().map_or(0, |(_, position)| *position);
+        let (tail, _) = scan(&segment.log_path, tail_position, 0, usize::MAX)?;
+        for (record, _) in first.iter().chain(&tail) {
+            segment.track(record);
+        }
+        segment.index = index;
+
+        Ok(segment)
+    }
+
+    // the newest segment may end in a torn write: keep the intact prefix and
+    // rebuild its index from scratch
+    fn recover_segment(&self, base_offset: u64) -> anyhow::Result<Segment> {
+        let (log_path, index_path) = Segment::paths(&self.dir, base_offset);
+        let (records, valid_end) = scan(&log_path, 0, 0, usize::MAX)?;
+
+        let log = OpenOptions::new().write(true).open(&log_path)?;
+        if log.metadata()?.len() > valid_end {
+            tracing::warn!(
+                "Truncating torn write in {} at {}",
+                log_path.display(),
+                valid_end
+            );
+            log.set_len(valid_end)?;
+            log.sync_all()?;
+        }
+
+        let mut segment = Segment::new(log_path, index_path, valid_end);
+        let mut index_bytes = Vec::new();
+        for (i, (record, position)) in records.iter().enumerate() {
+            segment.track(record);
+            let next = records.get(i + 1).map_or(valid_end, |(_, p)| *p);
+            if segment.index.is_empty() || segment.bytes_since_index >= self.index_interval_bytes {
+                segment.index.push((record.offset, *position));
+                index_bytes.extend_from_slice(&record.offset.to_le_bytes());
+                index_bytes.extend_from_slice(&position.to_le_bytes());
+                segment.bytes_since_index = 0;
+            }
+            segment.bytes_since_index += next - position;
+        }
+        let mut index = File::create(&segment.index_path)?;
+        index.write_all(&index_bytes)?;
+        index.sync_all()?;
+
+        Ok(segment)
+    }
+
+    fn roll(&mut self, base_offset: u64) -> anyhow::Result<()> {
+        if let Some((log, index)) = self.active.take() {
+            if !matches!(self.fsync, FsyncPolicy::Never) {
+                log.sync_data()?;
+                index.sync_data()?;
+            }
+        }
+
+        let (log_path, index_path) = Segment::paths(&self.dir, base_offset);
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .with_context(|| format!("create {}", log_path.display()))?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)?;
+        self.segments
+            .insert(base_offset, Segment::new(log_path, index_path, 0));
+        self.active = Some((log, index));
+        if !matches!(self.fsync, FsyncPolicy::Never) {
+            self.sync_dir()?;
+        }
+
+        Ok(())
+    }
+
+    // write a new file and rename it over the old one so a crash never
+    // leaves a half-written file behind
+    fn replace_file(&self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let path = self.dir.join(name);
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        if !matches!(self.fsync, FsyncPolicy::Never) {
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &path).with_context(|| format!("replace {}", path.display()))?;
+        if !matches!(self.fsync, FsyncPolicy::Never) {
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+
+    // make created, renamed and removed files in the key's directory durable
+    fn sync_dir(&self) -> anyhow::Result<()> {
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("sync {}", self.dir.display()))
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        if let Some((log, index)) = &self.active {
+            log.sync_data()?;
+            index.sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let needs_roll = match self.segments.values().next_back() {
+            Some(segment) => self.active.is_none() || segment.size >= self.segment_bytes,
+            None => true,
+        };
+        if needs_roll {
+            self.roll(record.offset)?;
+        }
+
+        let payload = serde_json::to_vec(record).context("serialize record")?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_BYTES as usize + payload.len());
+        frame.extend_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        let (log, index) = self.active.as_mut().expect("no active segment");
+        let segment = self
+            .segments
+            .values_mut()
+            .next_back()
+            .expect("no active segment");
+
+        let position = segment.size;
+        log.write_all(&frame).context("append record")?;
+        if segment.index.is_empty() || segment.bytes_since_index >= self.index_interval_bytes {
+            let mut entry = [0u8; INDEX_ENTRY_BYTES as usize];
+            entry[..8].copy_from_slice(&record.offset.to_le_bytes());
+            entry[8..].copy_from_slice(&position.to_le_bytes());
+            index.write_all(&entry).context("append index entry")?;
+            segment.index.push((record.offset, position));
+            segment.bytes_since_index = 0;
+        }
+        segment.size += frame.len() as u64;
+        segment.bytes_since_index += frame.len() as u64;
+        segment.track(record);
+
+        self.unsynced += 1;
+        match self.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Every(count) if self.unsynced >= count => self.sync()?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, from: u64, max: usize) -> anyhow::Result<Vec<Record>> {
+        let from = from.max(self.start_offset);
+        // start with the segment that could hold `from`
+        let start = self
+            .segments
+            .range(..=from)
+            .next_back()
+            .map_or(0, |(base, _)| *base);
+
+        let mut records = Vec::new();
+        for segment in self.segments.range(start..).map(|(_, segment)| segment) {
+            if records.len() >= max {
+                break;
+            }
+            let position = segment.position_for(from);
+            let (scanned, _) = scan(&segment.log_path, position, from, max - records.len())?;
+            records.extend(scanned.into_iter().map(|(record, _)| record));
+        }
+
+        Ok(records)
+    }
+
+    fn last_offset(&self) -> Option<u64> {
+        self.segments
+            .values()
+            .rev()
+            .find_map(|segment| segment.last_offset)
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    fn truncate_before(&mut self, offset: u64) -> anyhow::Result<()> {
+        if offset <= self.start_offset {
+            return Ok(());
+        }
+        self.start_offset = offset;
+        self.replace_file(START_OFFSET_FILE, offset.to_string().as_bytes())?;
+
+        // the newest segment is always kept so the last offset is not lost
+        let newest = self.segments.keys().next_back().copied();
+        let expired: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(base, segment)| {
+                Some(**base) != newest && segment.last_offset.is_none_or(|last| last < offset)
+            })
+            .map(|(base, _)| *base)
+            .collect();
+        for base in expired {
+            if let Some(segment) = self.segments.remove(&base) {
+                tracing::info!("Removing expired segment {}", segment.log_path.display());
+                std::fs::remove_file(&segment.log_path)?;
+                if segment.index_path.exists() {
+                    std::fs::remove_file(&segment.index_path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, offset: u64) -> anyhow::Result<()> {
+        if self.last_offset().is_none_or(|last| last < offset) {
+            return Ok(());
+        }
+        self.sync()?;
+        self.active = None;
+
+        // segments that start at or after the offset go entirely
+        for base in self.segments.split_off(&offset).into_keys() {
+            let (log_path, index_path) = Segment::paths(&self.dir, base);
+            tracing::info!("Removing diverged segment {}", log_path.display());
+            std::fs::remove_file(&log_path)?;
+            if index_path.exists() {
+                std::fs::remove_file(&index_path)?;
+            }
+        }
+
+        // the one holding it is cut after the record before it, and then
+        // recovered like after a crash, which rebuilds its index
+        if let Some((&base, segment)) = self.segments.iter().next_back() {
+            let position = segment.position_for(offset);
+            let (records, _) = scan(&segment.log_path, position, offset, 1)?;
+            if let Some((_, cut)) = records.first() {
+                let log = OpenOptions::new().write(true).open(&segment.log_path)?;
+                log.set_len(*cut)?;
+                log.sync_all()?;
+            }
+            let segment = self.recover_segment(base)?;
+            let log = OpenOptions::new().append(true).open(&segment.log_path)?;
+            let index = OpenOptions::new().append(true).open(&segment.index_path)?;
+            self.active = Some((log, index));
+            self.segments.insert(base, segment);
+        }
+        self.sync_dir()
+    }
+
+    fn offset_for_timestamp(&self, timestamp: u64) -> anyhow::Result<Option<u64>> {
+        // timestamps never decrease, so the first segment that reaches
+        // `timestamp` holds the record
+        let Some(segment) = self
+            .segments
+            .values()
+            .find(|segment| segment.max_timestamp >= timestamp)
+        else {
+            return Ok(None);
+        };
+
+        // binary search the index for the last indexed record that is older,
+        // reading one record per probe, and scan on from there
+        let (mut low, mut high) = (0, segment.index.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let (records, _) = scan(&segment.log_path, segment.index[mid].1, 0, 1)?;
+            if records
+                .first()
+                .is_some_and(|(record, _)| record.timestamp < timestamp)
+            {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let mut position = low.checked_sub(1).map_or(0, |entry| segment.index[entry].1);
+
+        loop {
+            let (records, end) = scan(&segment.log_path, position, self.start_offset, 64)?;
+            if let Some((record, _)) = records
+                .iter()
+                .find(|(record, _)| record.timestamp >= timestamp)
+            {
+                return Ok(Some(record.offset));
+            }
+            if end == position {
+                return Ok(None);
+            }
+            position = end;
+        }
+    }
+
+    fn offset_for_size(&self, max_bytes: u64) -> Option<u64> {
+        // whole segments are the unit of removal, so keep the oldest segments
+        // that still fit
+        let mut bytes = 0;
+        let mut keep_from = None;
+        for segment in self.segments.values().rev() {
+            bytes += segment.size;
+            if bytes > max_bytes {
+                return keep_from.or(segment.last_offset.map(|last| last + 1));
+            }
+            keep_from = segment.first_offset;
+        }
+        None
+    }
+
+    fn commit(&mut self, group: &str, offset: u64) -> anyhow::Result<()> {
+        self.committed_offsets.insert(group.to_string(), offset);
+        let contents = serde_json::to_vec(&self.committed_offsets)?;
+        self.replace_file(COMMITTED_OFFSETS_FILE, &contents)
+    }
+
+    fn committed_offsets(&self) -> &HashMap<String, u64> {
+        &self.committed_offsets
+    }
+
+    fn save_producers(&mut self, producers: &ProducerWindows) -> anyhow::Result<()> {
+        self.producers = producers.clone();
+        let contents = serde_json::to_vec(&self.producers)?;
+        self.replace_file(PRODUCERS_FILE, &contents)
+    }
+
+    fn producers(&self) -> &ProducerWindows {
+        &self.producers
+    }
+}
+
+/// Read up to `max` intact records with an offset `>= from`, starting at file
+/// `position`. Returns each record with its file position, and the position
+/// just past the last intact record that was read.
+fn scan(
+    path: &Path,
+    position: u64,
+    from: u64,
+    max: usize,
+) -> anyhow::Result<(Vec<(Record, u64)>, u64)> {
+    let mut file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    file.seek(SeekFrom::Start(position))?;
+    let mut reader = BufReader::new(file);
+
+    let mut records = Vec::new();
+    let mut position = position;
+    while records.len() < max {
+        let mut header = [0u8; FRAME_HEADER_BYTES as usize];
+        if reader.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(header[..4].try_into()?) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into()?);
+        if len > MAX_RECORD_BYTES {
+            break;
+        }
+
+        let mut payload = vec![0u8; len];
+        if reader.read_exact(&mut payload).is_err() || crc32fast::hash(&payload) != crc {
+            break;
+        }
+        let Ok(record) = serde_json::from_slice::<Record>(&payload) else {
+            break;
+        };
+
+        if record.offset >= from {
+            records.push((record, position));
+        }
+        position += FRAME_HEADER_BYTES + len as u64;
+    }
+
+    Ok((records, position))
+}
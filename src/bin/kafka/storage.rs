@@ -0,0 +1,285 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use malen::config::{env_or, env_var};
+use serde::{Deserialize, Serialize};
+
+use crate::segment::{FileStorage, FsyncPolicy};
+
+/// A single message as it is kept by a storage backend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub offset: u64,
+    pub msg: u64,
+    /// broker time the record was appended, in milliseconds since the epoch;
+    /// never decreases within a key
+    #[serde(default)]
+    pub timestamp: u64,
+    /// time the producer gave for the message, kept as it was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_timestamp: Option<u64>,
+    /// idempotent producer that sent the message, with its sequence number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// transaction the message was committed by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txn_id: Option<String>,
+    /// leader epoch the record was appended in; always 0 without replication
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    /// marks the key as deleted; every record before it is gone
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tombstone: bool,
+}
+
+impl Record {
+    /// Size of the record as it is stored.
+    pub fn bytes(&self) -> u64 {
+        serde_json::to_vec(self).map_or(0, |bytes| bytes.len() as u64)
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Producer id -> recent sequence numbers and the offsets they were stored at.
+pub type ProducerWindows = HashMap<String, BTreeMap<u64, u64>>;
+
+/// Where a key's messages and committed offsets are kept.
+pub trait Storage: Send {
+    /// Append a record. Offsets must be strictly increasing.
+    fn append(&mut self, record: &Record) -> anyhow::Result<()>;
+
+    /// Read up to `max` records starting at the first offset `>= from`.
+    fn read(&self, from: u64, max: usize) -> anyhow::Result<Vec<Record>>;
+
+    /// Offset of the last appended record, if there is one. This survives
+    /// truncation so that offsets are never handed out twice.
+    fn last_offset(&self) -> Option<u64>;
+
+    /// Lowest offset that can still be read; everything below it has been
+    /// removed by retention.
+    fn start_offset(&self) -> u64;
+
+    /// Drop every record with an offset below `offset`.
+    fn truncate_before(&mut self, offset: u64) -> anyhow::Result<()>;
+
+    /// Drop every record at or after `offset`, which a replica does when the
+    /// end of its log diverged from the leader's. Unlike `truncate_before`,
+    /// this moves the last offset back.
+    fn truncate_from(&mut self, offset: u64) -> anyhow::Result<()>;
+
+    /// Offset of the first readable record appended at or after `timestamp`.
+    fn offset_for_timestamp(&self, timestamp: u64) -> anyhow::Result<Option<u64>>;
+
+    /// Lowest offset that has to be kept so the retained records take up at
+    /// most `max_bytes`, or `None` if everything already fits.
+    fn offset_for_size(&self, max_bytes: u64) -> Option<u64>;
+
+    fn commit(&mut self, group: &str, offset: u64) -> anyhow::Result<()>;
+
+    fn committed_offsets(&self) -> &HashMap<String, u64>;
+
+    /// Keep the producers' dedup windows, which outlive the records they were
+    /// built from once those are truncated.
+    fn save_producers(&mut self, producers: &ProducerWindows) -> anyhow::Result<()>;
+
+    /// Dedup windows as they were last saved.
+    fn producers(&self) -> &ProducerWindows;
+}
+
+/// Keeps everything in memory; nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    messages: BTreeMap<u64, Record>,
+    last_offset: Option<u64>,
+    start_offset: u64,
+    committed_offsets: HashMap<String, u64>,
+    producers: ProducerWindows,
+}
+
+impl Storage for MemoryStorage {
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.messages.insert(record.offset, record.clone());
+        self.last_offset = Some(record.offset);
+        Ok(())
+    }
+
+    fn read(&self, from: u64, max: usize) -> anyhow::Result<Vec<Record>> {
+        Ok(self
+            .messages
+            .range(from..)
+            .take(max)
+            .map(|(_, record)| record.clone())
+            .collect())
+    }
+
+    fn last_offset(&self) -> Option<u64> {
+        self.last_offset
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    fn truncate_before(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.start_offset = self.start_offset.max(offset);
+        self.messages = self.messages.split_off(&self.start_offset);
+        Ok(())
+    }
+
+    fn truncate_from(&mut self, offset: u64) -> anyhow::Result<()> {
+        if self.last_offset.is_some_and(|last| last >= offset) {
+            self.messages.split_off(&offset);
+            self.last_offset = offset.checked_sub(1);
+        }
+        Ok(())
+    }
+
+    fn offset_for_timestamp(&self, timestamp: u64) -> anyhow::Result<Option<u64>> {
+        Ok(self
+            .messages
+            .values()
+            .find(|record| record.timestamp >= timestamp)
+            .map(|record| record.offset))
+    }
+
+    fn offset_for_size(&self, max_bytes: u64) -> Option<u64> {
+        let mut bytes = 0;
+        let mut keep_from = None;
+        for record in self.messages.values().rev() {
+            bytes += record.bytes();
+            if bytes > max_bytes {
+                return Some(keep_from.unwrap_or(record.offset + 1));
+            }
+            keep_from = Some(record.offset);
+        }
+        None
+    }
+
+    fn commit(&mut self, group: &str, offset: u64) -> anyhow::Result<()> {
+        self.committed_offsets.insert(group.to_string(), offset);
+        Ok(())
+    }
+
+    fn committed_offsets(&self) -> &HashMap<String, u64> {
+        &self.committed_offsets
+    }
+
+    fn save_producers(&mut self, producers: &ProducerWindows) -> anyhow::Result<()> {
+        self.producers = producers.clone();
+        Ok(())
+    }
+
+    fn producers(&self) -> &ProducerWindows {
+        &self.producers
+    }
+}
+
+/// Storage backend selection, read from the environment:
+///
+/// - `MALEN_KAFKA_STORAGE`: `memory` (default) or `file`
+/// - `MALEN_KAFKA_DATA_DIR`: root directory for the `file` backend
+/// - `MALEN_KAFKA_SEGMENT_BYTES`: size at which a new segment file is started
+/// - `MALEN_KAFKA_INDEX_INTERVAL_BYTES`: bytes between sparse index entries
+/// - `MALEN_KAFKA_FSYNC`: `always`, `never` or `every:<messages>`
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Memory,
+    File {
+        data_dir: PathBuf,
+        segment_bytes: u64,
+        index_interval_bytes: u64,
+        fsync: FsyncPolicy,
+    },
+}
+
+impl StorageConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let backend: String = env_or("MALEN_KAFKA_STORAGE", "memory".to_string())?;
+        match backend.as_str() {
+            "memory" => Ok(StorageConfig::Memory),
+            "file" => Ok(StorageConfig::File {
+                data_dir: env_var("MALEN_KAFKA_DATA_DIR")?
+                    .unwrap_or_else(|| PathBuf::from("kafka-data")),
+                segment_bytes: env_or("MALEN_KAFKA_SEGMENT_BYTES", 1024 * 1024)?,
+                index_interval_bytes: env_or("MALEN_KAFKA_INDEX_INTERVAL_BYTES", 4096)?,
+                fsync: env_or("MALEN_KAFKA_FSYNC", FsyncPolicy::Always)?,
+            }),
+            other => anyhow::bail!("unknown MALEN_KAFKA_STORAGE backend: {}", other),
+        }
+    }
+
+    /// Open the storage for `key`, recovering whatever a previous run left.
+    pub fn open(&self, node_id: &str, key: &str) -> anyhow::Result<Box<dyn Storage>> {
+        match self {
+            StorageConfig::Memory => Ok(Box::<MemoryStorage>::default()),
+            StorageConfig::File {
+                data_dir,
+                segment_bytes,
+                index_interval_bytes,
+                fsync,
+            } => Ok(Box::new(FileStorage::open(
+                data_dir.join(node_id).join(encode_key(key)),
+                *segment_bytes,
+                *index_interval_bytes,
+                *fsync,
+            )?)),
+        }
+    }
+
+    /// Keys that already have data on disk for `node_id`.
+    pub fn existing_keys(&self, node_id: &str) -> anyhow::Result<Vec<String>> {
+        let StorageConfig::File { data_dir, .. } = self else {
+            return Ok(Vec::new());
+        };
+        let node_dir = data_dir.join(node_id);
+        if !node_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(&node_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(key) = entry.file_name().to_str().and_then(decode_key) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+// keys are arbitrary strings, so anything that is not safe in a file name is
+// written as %XX
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,36 @@
+use std::{collections::HashMap, time::Instant};
+
+/// Number of pushed messages a subscriber may leave unacknowledged, unless
+/// the `subscribe` request says otherwise.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 100;
+
+/// A batch pushed to a subscriber that has not been acknowledged yet.
+pub struct Delivery {
+    pub msgs: HashMap<String, Vec<Vec<u64>>>,
+    pub sent_at: Instant,
+}
+
+impl Delivery {
+    fn len(&self) -> usize {
+        self.msgs.values().map(Vec::len).sum()
+    }
+}
+
+/// The part of a `subscribe` this node serves: the subscribed keys it owns,
+/// pushed straight to the client as they are appended.
+pub struct Subscription {
+    pub client: String,
+    /// next offset to push for each key
+    pub offsets: HashMap<String, u64>,
+    pub max_in_flight: usize,
+    /// deliveries waiting for a `deliver_ok`, by msg_id
+    pub in_flight: HashMap<usize, Delivery>,
+}
+
+impl Subscription {
+    /// How many more messages can be pushed before the client acks some.
+    pub fn credit(&self) -> usize {
+        let in_flight: usize = self.in_flight.values().map(Delivery::len).sum();
+        self.max_in_flight.saturating_sub(in_flight)
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use malen::message::Message;
+
+use crate::Payload;
+
+/// Number of committed and of aborted transactions a participant remembers,
+/// so a retried `txn_commit` gets the same offsets back instead of appending
+/// again, and a `txn_prepare` that arrives late is voted down.
+const TXN_HISTORY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// A `send_txn` this node coordinates with two-phase commit across the
+/// owners of its keys.
+pub struct CoordinatedTxn {
+    /// the client request until it is answered: an abort is reported right
+    /// away, a commit once every participant has sent back its offsets
+    pub request: Option<Message<Payload>>,
+    /// owner of each message, in request order
+    pub owners: Vec<String>,
+    /// the messages each owner appends, in request order
+    pub parts: HashMap<String, Vec<(String, u64)>>,
+    pub votes: HashMap<String, bool>,
+    pub decision: Option<Decision>,
+    /// participants that acknowledged the decision, with the offsets they
+    /// assigned on commit
+    pub acks: HashMap<String, Vec<(String, u64)>>,
+    /// participants that could not commit, and why
+    pub failed: Vec<String>,
+    /// the transaction aborts if not every owner has voted by then
+    pub deadline: Instant,
+    pub retry_at: Instant,
+}
+
+impl CoordinatedTxn {
+    /// Decide once the votes allow it: any "no" aborts, all "yes" commits.
+    pub fn decide_from_votes(&mut self) -> Option<Decision> {
+        if self.decision.is_none() {
+            if self.votes.values().any(|prepared| !prepared) {
+                self.decision = Some(Decision::Abort);
+            } else if self.parts.keys().all(|node| self.votes.contains_key(node)) {
+                self.decision = Some(Decision::Commit);
+            } else {
+                return None;
+            }
+            return self.decision;
+        }
+        None
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.decision.is_some() && self.parts.keys().all(|node| self.acks.contains_key(node))
+    }
+
+    /// Offsets in the order the messages were sent.
+    pub fn offsets(&self) -> Vec<(String, u64)> {
+        let mut per_node: HashMap<&String, std::slice::Iter<(String, u64)>> = self
+            .acks
+            .iter()
+            .map(|(node, offsets)| (node, offsets.iter()))
+            .collect();
+        self.owners
+            .iter()
+            .filter_map(|owner| per_node.get_mut(owner)?.next().cloned())
+            .collect()
+    }
+}
+
+/// A transaction this node voted to commit and is waiting on a decision for.
+/// Its messages stay out of the log, and so invisible to `poll`, until then.
+pub struct PreparedTxn {
+    pub msgs: Vec<(String, u64)>,
+    pub coordinator: String,
+    /// every owner taking part, this node included
+    pub participants: Vec<String>,
+    /// participants that said they never committed the transaction, asked
+    /// once the coordinator was done with it without telling this node
+    pub uncommitted: HashSet<String>,
+    /// when to ask the coordinator what became of the transaction
+    pub check_at: Instant,
+}
+
+/// A transaction this node appended its messages for. They stay hidden from
+/// `poll` until the coordinator reports that every participant has appended.
+pub struct CommittingTxn {
+    pub offsets: Vec<(String, u64)>,
+    pub coordinator: String,
+    pub check_at: Instant,
+}
+
+/// The most recent transaction ids of one outcome, oldest dropped first.
+struct History<V> {
+    entries: HashMap<String, V>,
+    order: VecDeque<String>,
+}
+
+impl<V> Default for History<V> {
+    fn default() -> Self {
+        History {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+impl<V> History<V> {
+    fn insert(&mut self, txn_id: &str, value: V) {
+        if self.entries.insert(txn_id.to_string(), value).is_none() {
+            self.order.push_back(txn_id.to_string());
+        }
+        while self.order.len() > TXN_HISTORY {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+}
+
+/// Participant-side transaction state.
+#[derive(Default)]
+pub struct Participant {
+    pub prepared: HashMap<String, PreparedTxn>,
+    pub committing: HashMap<String, CommittingTxn>,
+    committed: History<Vec<(String, u64)>>,
+    aborted: History<()>,
+}
+
+impl Participant {
+    /// Offsets a commit appended at, whether or not they are visible yet.
+    pub fn committed(&self, txn_id: &str) -> Option<&Vec<(String, u64)>> {
+        self.committing
+            .get(txn_id)
+            .map(|txn| &txn.offsets)
+            .or_else(|| self.committed.entries.get(txn_id))
+    }
+
+    /// Whether the transaction was decided here already, one way or the other.
+    pub fn is_decided(&self, txn_id: &str) -> bool {
+        self.committed(txn_id).is_some() || self.aborted.entries.contains_key(txn_id)
+    }
+
+    pub fn remember_commit(&mut self, txn_id: &str, offsets: Vec<(String, u64)>) {
+        self.committed.insert(txn_id, offsets);
+    }
+
+    pub fn remember_abort(&mut self, txn_id: &str) {
+        self.aborted.insert(txn_id, ());
+    }
+
+    /// First offset of `key` that polls must not see yet.
+    pub fn hidden_from(&self, key: &str) -> Option<u64> {
+        self.committing
+            .values()
+            .flat_map(|txn| &txn.offsets)
+            .filter(|(txn_key, _)| txn_key == key)
+            .map(|(_, offset)| *offset)
+            .min()
+    }
+
+    /// Transactions that have, or may yet have, hidden messages on `keys`.
+    pub fn pending_on(&self, keys: &HashSet<&String>) -> HashSet<String> {
+        let prepared = self
+            .prepared
+            .iter()
+            .filter(|(_, txn)| txn.msgs.iter().any(|(key, _)| keys.contains(key)));
+        let committing = self
+            .committing
+            .iter()
+            .filter(|(_, txn)| txn.offsets.iter().any(|(key, _)| keys.contains(key)));
+        prepared
+            .map(|(txn_id, _)| txn_id.clone())
+            .chain(committing.map(|(txn_id, _)| txn_id.clone()))
+            .collect()
+    }
+
+    /// Transactions that waited long enough on their coordinator, with the
+    /// coordinator to ask about each; they are not due again for `retry`.
+    pub fn overdue(&mut self, now: Instant, retry: Duration) -> Vec<(String, String)> {
+        let prepared = self
+            .prepared
+            .iter_mut()
+            .map(|(txn_id, txn)| (txn_id, &txn.coordinator, &mut txn.check_at));
+        let committing = self
+            .committing
+            .iter_mut()
+            .map(|(txn_id, txn)| (txn_id, &txn.coordinator, &mut txn.check_at));
+        prepared
+            .chain(committing)
+            .filter(|(_, _, check_at)| **check_at <= now)
+            .map(|(txn_id, coordinator, check_at)| {
+                *check_at = now + retry;
+                (txn_id.clone(), coordinator.clone())
+            })
+            .collect()
+    }
+}
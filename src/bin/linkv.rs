@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    message::{error_code, Body, Message, MessageWriter},
+    node::{spawn_ticker, Node},
+    process::process_loop,
+    raft::{write_rpcs, Event, Raft, RaftConfig, RaftRpc, StateMachine},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        text: String,
+    },
+    Raft {
+        rpc: RaftRpc<Command>,
+    },
+    Tick,
+}
+
+/// A write to the store, as it is kept in the Raft log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+}
+
+/// The replicated map. Applying a command gives the reply to the client.
+#[derive(Default)]
+struct Store {
+    // keyed by the key's JSON text
+    values: HashMap<String, Value>,
+}
+
+impl Store {
+    fn read(&self, key: &Value) -> Payload {
+        match self.values.get(&key.to_string()) {
+            Some(value) => Payload::ReadOk {
+                value: value.clone(),
+            },
+            None => key_does_not_exist(key),
+        }
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> Payload {
+    Payload::Error {
+        code: error_code::KEY_DOES_NOT_EXIST,
+        text: format!("key {} does not exist", key),
+    }
+}
+
+impl StateMachine for Store {
+    type Command = Command;
+    type Output = Payload;
+
+    fn apply(&mut self, command: &Command) -> Payload {
+        match command {
+            Command::Write { key, value } => {
+                self.values.insert(key.to_string(), value.clone());
+                Payload::WriteOk
+            }
+            Command::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.values.get(&key.to_string()) {
+                Some(current) if current == from => {
+                    self.values.insert(key.to_string(), to.clone());
+                    Payload::CasOk
+                }
+                Some(current) => Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    text: format!("expected {}, but {} is {}", from, key, current),
+                },
+                None if *create_if_not_exists => {
+                    self.values.insert(key.to_string(), to.clone());
+                    Payload::CasOk
+                }
+                None => key_does_not_exist(key),
+            },
+        }
+    }
+}
+
+/// How long a request may wait for the cluster before the client is told
+/// it timed out.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// A linearizable key/value store for Maelstrom's `lin-kv` workload,
+/// replicated with Raft.
+///
+/// The leader puts writes and compare-and-sets in the log and answers once
+/// they are applied. Reads are not logged: the leader answers them from its
+/// copy once a majority confirms it is still leader (the read index). Other
+/// nodes proxy requests from clients to the leader they know of.
+struct LinKvNode {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    raft: Raft<Store>,
+    // log index -> (term, request) for writes waiting to be applied
+    proposals: HashMap<u64, (u64, Message<Payload>, Instant)>,
+    // read id -> request
+    reads: HashMap<u64, (Message<Payload>, Instant)>,
+    // msg_id sent to the leader -> the client's request
+    forwarded: HashMap<usize, (Message<Payload>, Instant)>,
+    tx: Option<Sender<Message<Payload>>>,
+}
+
+impl LinKvNode {
+    fn reply(
+        &mut self,
+        request: Message<Payload>,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let reply = request.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)
+    }
+
+    fn handle_request(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        if !self.raft.is_leader() {
+            return self.forward(input_msg, writer);
+        }
+
+        let command = match input_msg.body.payload {
+            Payload::Read { .. } => {
+                let Ok(id) = self.raft.read_index() else {
+                    anyhow::bail!("leader could not start a read");
+                };
+                self.reads.insert(id, (input_msg, Instant::now()));
+                return Ok(());
+            }
+            Payload::Write { ref key, ref value } => Command::Write {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Payload::Cas {
+                ref key,
+                ref from,
+                ref to,
+                create_if_not_exists,
+            } => Command::Cas {
+                key: key.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                create_if_not_exists,
+            },
+            _ => anyhow::bail!("not a client request"),
+        };
+        let Ok((index, term)) = self.raft.propose(command) else {
+            anyhow::bail!("leader could not propose");
+        };
+        self.proposals
+            .insert(index, (term, input_msg, Instant::now()));
+        Ok(())
+    }
+
+    // pass a client's request to the leader, if there is one to pass it to
+    fn forward(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let leader = self.raft.leader().map(str::to_string);
+        // requests are only passed on once, so they cannot go round in
+        // circles while nodes disagree about the leader
+        let from_node = self.node_ids.contains(&input_msg.src);
+        let Some(leader) = leader.filter(|_| !from_node) else {
+            let payload = Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: format!("{} is not the leader", self.node_id),
+            };
+            return self.reply(input_msg, payload, writer);
+        };
+
+        let msg_id = self.get_msg_id().expect("No message id");
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: leader,
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: input_msg.body.payload.clone(),
+            },
+        };
+        writer.write_message(&message)?;
+        self.forwarded.insert(msg_id, (input_msg, Instant::now()));
+        Ok(())
+    }
+
+    // pass the leader's answer back to the client
+    fn handle_forwarded_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some((request, _)) = input_msg
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.forwarded.remove(&msg_id))
+        else {
+            tracing::info!("Ignoring late reply from the leader: {:?}", input_msg);
+            return Ok(());
+        };
+        self.reply(request, input_msg.body.payload, writer)
+    }
+
+    fn handle_events(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        for event in self.raft.take_events() {
+            match event {
+                Event::Applied {
+                    index,
+                    term,
+                    output,
+                } => {
+                    let Some((proposed_term, request, _)) = self.proposals.remove(&index) else {
+                        continue;
+                    };
+                    // another leader's entry took the slot, so the request
+                    // never will be applied
+                    let payload = match output {
+                        Some(output) if term == proposed_term => output,
+                        _ => Payload::Error {
+                            code: error_code::TEMPORARILY_UNAVAILABLE,
+                            text: "leadership changed before the request committed".to_string(),
+                        },
+                    };
+                    self.reply(request, payload, writer)?;
+                }
+                Event::ReadReady { id } => {
+                    let Some((request, _)) = self.reads.remove(&id) else {
+                        continue;
+                    };
+                    let Payload::Read { ref key } = request.body.payload else {
+                        anyhow::bail!("pending read is not a read request");
+                    };
+                    let payload = self.raft.state_machine.read(key);
+                    self.reply(request, payload, writer)?;
+                }
+                Event::ReadFailed { id } => {
+                    let Some((request, _)) = self.reads.remove(&id) else {
+                        continue;
+                    };
+                    let payload = Payload::Error {
+                        code: error_code::TEMPORARILY_UNAVAILABLE,
+                        text: format!("{} stopped being leader", self.node_id),
+                    };
+                    self.reply(request, payload, writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // writes that may yet be applied get an indefinite timeout; a read that
+    // is not answered had no effect
+    fn expire_requests(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let expired = |since: &Instant| now.duration_since(*since) >= REQUEST_TIMEOUT;
+
+        let mut timed_out = Vec::new();
+        self.proposals.retain(|_, (_, request, since)| {
+            let keep = !expired(since);
+            if !keep {
+                timed_out.push(request.clone());
+            }
+            keep
+        });
+        self.forwarded.retain(|_, (request, since)| {
+            let keep = !expired(since);
+            if !keep {
+                timed_out.push(request.clone());
+            }
+            keep
+        });
+        for request in timed_out {
+            let payload = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: "no answer from the cluster in time".to_string(),
+            };
+            self.reply(request, payload, writer)?;
+        }
+
+        let mut unavailable = Vec::new();
+        self.reads.retain(|_, (request, since)| {
+            let keep = !expired(since);
+            if !keep {
+                unavailable.push(request.clone());
+            }
+            keep
+        });
+        for request in unavailable {
+            let payload = Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: "could not confirm leadership for the read".to_string(),
+            };
+            self.reply(request, payload, writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<Payload> for LinKvNode {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.tx = Some(tx);
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+                self.raft.start(node_id, node_ids);
+
+                let tx = self.tx.clone().expect("node not initialized");
+                let interval = self.raft.config().tick_interval();
+                spawn_ticker(tx, node_id.clone(), interval, Payload::Tick);
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {
+                self.handle_request(input_msg, writer)?;
+            }
+
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk | Payload::Error { .. } => {
+                self.handle_forwarded_reply(input_msg, writer)?
+            }
+
+            Payload::Raft { rpc } => self.raft.handle(&input_msg.src, rpc),
+
+            Payload::Tick => {
+                self.raft.tick();
+                self.expire_requests(writer)?;
+            }
+        };
+
+        write_rpcs(
+            &self.node_id,
+            self.raft.take_rpcs(),
+            |rpc| Payload::Raft { rpc },
+            writer,
+        )?;
+        self.handle_events(writer)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut node = LinKvNode {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        raft: Raft::new(Store::default(), RaftConfig::from_env()?),
+        proposals: HashMap::new(),
+        reads: HashMap::new(),
+        forwarded: HashMap::new(),
+        tx: None,
+    };
+
+    process_loop(&mut node)
+}
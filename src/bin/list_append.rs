@@ -0,0 +1,505 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    str::FromStr,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use malen::{
+    config::env_or,
+    hlc::{HybridClock, Timestamp},
+    message::{error_code, Body, Message, MessageWriter},
+    node::{spawn_ticker, GossipManager, Node},
+    process::process_loop,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A micro-operation of a transaction: `["append", key, element]` or
+/// `["r", key, null]`. Replies fill in reads with the key's list.
+type MicroOp = (String, u64, Value);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    Error {
+        code: u64,
+        text: String,
+    },
+    GossipSend,
+    Gossip {
+        messages: HashSet<Record>,
+    },
+    GossipOk,
+    /// a node's clock, and the last record it took before reading it
+    Heartbeat {
+        clock: Timestamp,
+        last_seq: u64,
+    },
+    Tick,
+}
+
+/// How a node runs transactions, selected with `MALEN_LIST_APPEND_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// the first node runs every transaction, one at a time, and the others
+    /// forward to it; unavailable while it cannot be reached
+    StrictSerializable,
+    /// every node runs transactions on its own copy and gossips the appends
+    Available,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict-serializable" => Ok(Mode::StrictSerializable),
+            "available" => Ok(Mode::Available),
+            other => anyhow::bail!("expected strict-serializable or available, got {}", other),
+        }
+    }
+}
+
+/// The appends of one transaction, numbered by the node that ran it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Record {
+    origin: String,
+    seq: u64,
+    at: Timestamp,
+    appends: Vec<(u64, u64)>,
+}
+
+/// Where an element sits in its list: the timestamp of the transaction that
+/// appended it, then its position among that transaction's appends.
+type Version = (Timestamp, usize);
+
+/// What a node knows about the records of another.
+#[derive(Debug, Default)]
+struct Origin {
+    /// every record up to this seq has arrived
+    through: u64,
+    beyond: BTreeSet<u64>,
+    /// the origin's clock when it had taken records up to `through` or
+    /// fewer, so every record it takes later is newer
+    stable_at: Option<Timestamp>,
+    /// heartbeats waiting for records to arrive: the last seq the origin
+    /// had taken -> its latest clock with that seq
+    pending: BTreeMap<u64, Timestamp>,
+}
+
+impl Origin {
+    fn observe(&mut self, seq: u64) {
+        if seq > self.through {
+            self.beyond.insert(seq);
+        }
+        while self.beyond.remove(&(self.through + 1)) {
+            self.through += 1;
+        }
+        // heartbeats are in the origin's clock order too, so the one with
+        // the highest covered seq is the latest
+        let waiting = self.pending.split_off(&(self.through + 1));
+        let covered = std::mem::replace(&mut self.pending, waiting);
+        if let Some((_, clock)) = covered.into_iter().next_back() {
+            self.stabilize(clock);
+        }
+    }
+
+    fn heartbeat(&mut self, clock: Timestamp, last_seq: u64) {
+        if last_seq <= self.through {
+            self.stabilize(clock);
+        } else if self.stable_at.as_ref().is_none_or(|at| clock > *at) {
+            let latest = self.pending.entry(last_seq).or_insert(clock.clone());
+            if clock > *latest {
+                *latest = clock;
+            }
+        }
+    }
+
+    fn stabilize(&mut self, clock: Timestamp) {
+        if self.stable_at.as_ref().is_none_or(|at| clock > *at) {
+            self.stable_at = Some(clock);
+        }
+    }
+}
+
+/// How often nodes in available mode send heartbeats, which is what lets
+/// reads see new appends.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a forwarded transaction waits for the leader.
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// A store for Maelstrom's `txn-list-append` workload.
+///
+/// In strict-serializable mode one node holds the only copy and runs
+/// transactions in the order they arrive, which puts them in an order
+/// consistent with real time.
+///
+/// In available mode any node answers straight away. Elements are ordered by
+/// the hybrid logical clock timestamp of the transaction that appended them.
+/// An element is only read once it is stable: every node has sent a
+/// heartbeat later than it, and this node has every record each of them took
+/// before that heartbeat, so nothing can be inserted ahead of it anymore.
+/// Reads may then be stale, but all of them agree on the order of every
+/// list, except that a transaction reads its own appends at the end of the
+/// list, where appends from other nodes that were not stable yet can later
+/// come before them.
+struct ListAppendNode {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    mode: Mode,
+    clock: HybridClock,
+    lists: HashMap<u64, BTreeMap<Version, u64>>,
+    // available mode: records this node took, what it knows of the others',
+    // and the records some node may not have yet
+    seq: u64,
+    origins: HashMap<String, Origin>,
+    unsettled: HashSet<Record>,
+    // strict mode: msg_id sent to the leader -> the client's request
+    forwarded: HashMap<usize, (Message<Payload>, Instant)>,
+    gossip_manager: Option<GossipManager<Payload, Record>>,
+    tx: Option<Sender<Message<Payload>>>,
+}
+
+impl ListAppendNode {
+    fn leader(&self) -> &str {
+        self.node_ids.first().map_or(&self.node_id, |leader| leader)
+    }
+
+    /// Elements at or before this timestamp are stable.
+    fn stable_through(&mut self) -> Option<Timestamp> {
+        if self.mode == Mode::StrictSerializable {
+            return None;
+        }
+        // without peers, only this node takes records, and all of them
+        // come before its clock
+        if self.node_ids.iter().all(|node| *node == self.node_id) {
+            return Some(self.clock.now());
+        }
+        self.node_ids
+            .iter()
+            .filter(|node| **node != self.node_id)
+            .map(|node| self.origins.get(node).and_then(|o| o.stable_at.clone()))
+            .min()
+            .unwrap_or(None)
+    }
+
+    fn read(&self, key: u64, stable: &Option<Timestamp>) -> Vec<u64> {
+        let Some(list) = self.lists.get(&key) else {
+            return Vec::new();
+        };
+        list.iter()
+            .filter(|((at, _), _)| match (self.mode, stable) {
+                (Mode::StrictSerializable, _) => true,
+                (Mode::Available, Some(stable)) => at <= stable,
+                (Mode::Available, None) => false,
+            })
+            .map(|(_, element)| *element)
+            .collect()
+    }
+
+    fn apply(&mut self, record: &Record) {
+        self.clock.observe(&record.at);
+        for (index, (key, element)) in record.appends.iter().enumerate() {
+            let list = self.lists.entry(*key).or_default();
+            list.insert((record.at.clone(), index), *element);
+        }
+        if record.origin != self.node_id {
+            self.origins
+                .entry(record.origin.clone())
+                .or_default()
+                .observe(record.seq);
+        }
+    }
+
+    /// Run `txn` and return it with the reads filled in.
+    fn execute(&mut self, mut txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, String> {
+        let stable = self.stable_through();
+        let mut appends: Vec<(u64, u64)> = Vec::new();
+        for (op, key, value) in txn.iter_mut() {
+            match op.as_str() {
+                "r" => {
+                    let mut list = self.read(*key, &stable);
+                    list.extend(appends.iter().filter(|(k, _)| k == key).map(|(_, e)| *e));
+                    *value = list.into();
+                }
+                "append" => {
+                    let element = value
+                        .as_u64()
+                        .ok_or_else(|| format!("cannot append {}", value))?;
+                    appends.push((*key, element));
+                }
+                other => return Err(format!("unknown micro-op {}", other)),
+            }
+        }
+
+        if !appends.is_empty() {
+            // the clock reads after everything applied so far, so the
+            // transaction sorts after all it could have read
+            let at = self.clock.now();
+            self.seq += 1;
+            let record = Record {
+                origin: self.node_id.clone(),
+                seq: self.seq,
+                at,
+                appends,
+            };
+            self.apply(&record);
+            if self.mode == Mode::Available {
+                self.unsettled.insert(record);
+            }
+        }
+        Ok(txn)
+    }
+
+    fn send_to(
+        &mut self,
+        dest: &str,
+        payload: Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<usize> {
+        let msg_id = self.get_msg_id().expect("No message id");
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        writer.write_message(&message)?;
+        Ok(msg_id)
+    }
+
+    fn handle_txn(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Payload::Txn { ref txn } = input_msg.body.payload else {
+            anyhow::bail!("not a txn request");
+        };
+        if self.mode == Mode::StrictSerializable && self.leader() != self.node_id {
+            let leader = self.leader().to_string();
+            let payload = Payload::Txn { txn: txn.clone() };
+            let msg_id = self.send_to(&leader, payload, writer)?;
+            self.forwarded.insert(msg_id, (input_msg, Instant::now()));
+            return Ok(());
+        }
+
+        let payload = match self.execute(txn.clone()) {
+            Ok(txn) => Payload::TxnOk { txn },
+            Err(text) => Payload::Error {
+                code: error_code::MALFORMED_REQUEST,
+                text,
+            },
+        };
+        let reply = input_msg.into_reply(self.get_msg_id(), payload);
+        writer.write_message(&reply)
+    }
+
+    // pass the leader's answer back to the client
+    fn handle_forwarded_reply(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        let Some((request, _)) = input_msg
+            .body
+            .in_reply_to
+            .and_then(|msg_id| self.forwarded.remove(&msg_id))
+        else {
+            tracing::info!("Ignoring late reply from the leader: {:?}", input_msg);
+            return Ok(());
+        };
+        let reply = request.into_reply(self.get_msg_id(), input_msg.body.payload);
+        writer.write_message(&reply)
+    }
+
+    fn handle_tick(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        if self.mode == Mode::Available {
+            let clock = self.clock.now();
+            let peers: Vec<String> = self
+                .node_ids
+                .iter()
+                .filter(|node| **node != self.node_id)
+                .cloned()
+                .collect();
+            for peer in peers {
+                let payload = Payload::Heartbeat {
+                    clock: clock.clone(),
+                    last_seq: self.seq,
+                };
+                self.send_to(&peer, payload, writer)?;
+            }
+            return Ok(());
+        }
+
+        // the leader may or may not have run it, so the client cannot know
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .forwarded
+            .iter()
+            .filter(|(_, (_, sent_at))| now.duration_since(*sent_at) >= FORWARD_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in expired {
+            let (request, _) = self.forwarded.remove(&msg_id).expect("expired forward");
+            let payload = Payload::Error {
+                code: error_code::TIMEOUT,
+                text: format!("no answer from leader {}", self.leader()),
+            };
+            let reply = request.into_reply(self.get_msg_id(), payload);
+            writer.write_message(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// Send peers the appends they may be missing, after dropping those
+    /// every node has.
+    fn gossip(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let Some(mut manager) = self.gossip_manager.take() else {
+            return Ok(());
+        };
+        manager.settle(&mut self.unsettled);
+        let records: Vec<Record> = self.unsettled.iter().cloned().collect();
+        let result = manager.gossip(
+            &records,
+            || self.get_msg_id().expect("No message id"),
+            |messages| Payload::Gossip {
+                messages: messages.into_iter().collect(),
+            },
+            writer,
+        );
+        self.gossip_manager = Some(manager);
+        result
+    }
+}
+
+impl Node<Payload> for ListAppendNode {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.gossip_manager = Some(GossipManager::new(tx.clone()));
+        self.tx = Some(tx);
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+                self.clock = HybridClock::new(node_id);
+
+                let tx = self.tx.clone().expect("node not initialized");
+                if self.mode == Mode::Available {
+                    spawn_ticker(tx, node_id.clone(), HEARTBEAT_INTERVAL, Payload::Tick);
+                    if let Some(manager) = &mut self.gossip_manager {
+                        manager.create_gossip_monitor(
+                            self.node_id.clone(),
+                            self.node_ids.clone(),
+                            Payload::GossipSend,
+                        );
+                    }
+                } else {
+                    spawn_ticker(tx, node_id.clone(), FORWARD_TIMEOUT / 10, Payload::Tick);
+                }
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Txn { .. } => self.handle_txn(input_msg, writer)?,
+            Payload::TxnOk { .. } | Payload::Error { .. } => {
+                self.handle_forwarded_reply(input_msg, writer)?;
+            }
+
+            Payload::GossipSend => self.gossip(writer)?,
+            Payload::Gossip { ref messages } => {
+                // pass the records on too, in case the sender cannot reach
+                // every node
+                for record in messages {
+                    if record.origin != self.node_id {
+                        self.apply(record);
+                        self.unsettled.insert(record.clone());
+                    }
+                }
+
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.verify_messages(&input_msg.src, messages.iter().cloned().collect());
+
+                    let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
+                    writer.write_message(&reply)?;
+                }
+            }
+
+            Payload::GossipOk => {
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.handle_gossip_ok(input_msg);
+                }
+            }
+
+            Payload::Heartbeat {
+                ref clock,
+                last_seq,
+            } => {
+                self.clock.observe(clock);
+                self.origins
+                    .entry(input_msg.src.clone())
+                    .or_default()
+                    .heartbeat(clock.clone(), last_seq);
+            }
+
+            Payload::Tick => self.handle_tick(writer)?,
+        };
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut node = ListAppendNode {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        mode: env_or("MALEN_LIST_APPEND_MODE", Mode::StrictSerializable)?,
+        clock: HybridClock::new("0"),
+        lists: HashMap::new(),
+        seq: 0,
+        origins: HashMap::new(),
+        unsettled: HashSet::new(),
+        forwarded: HashMap::new(),
+        gossip_manager: None,
+        tx: None,
+    };
+
+    process_loop(&mut node)
+}
@@ -0,0 +1,317 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use malen::{
+    message::{Body, Message, MessageWriter},
+    node::{GossipManager, Node},
+    process::process_loop,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Add {
+        element: usize,
+    },
+    AddOk,
+    Remove {
+        element: usize,
+    },
+    RemoveOk,
+    Read,
+    ReadOk {
+        value: HashSet<usize>,
+    },
+    GossipSend,
+    Gossip {
+        messages: HashSet<Op>,
+    },
+    GossipOk,
+}
+
+/// Identifies one `add`: the node that took it and its sequence number there.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Tag {
+    node: String,
+    seq: u64,
+}
+
+/// What nodes gossip: the adds they know of and the adds that were removed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Add { tag: Tag, element: usize },
+    Remove { tag: Tag },
+}
+
+impl Op {
+    fn tag(&self) -> &Tag {
+        match self {
+            Op::Add { tag, .. } | Op::Remove { tag } => tag,
+        }
+    }
+}
+
+/// The tags of one node this node has heard of, either as an add or as a
+/// removal: every sequence number up to `through`, plus `beyond`.
+#[derive(Debug, Default)]
+struct Seen {
+    through: u64,
+    beyond: BTreeSet<u64>,
+}
+
+impl Seen {
+    /// Record `seq`, and return whether it is new.
+    fn observe(&mut self, seq: u64) -> bool {
+        if seq <= self.through || !self.beyond.insert(seq) {
+            return false;
+        }
+        while self.beyond.remove(&(self.through + 1)) {
+            self.through += 1;
+        }
+        true
+    }
+}
+
+/// An observed-remove set.
+///
+/// Each `add` tags the element with a unique `Tag`, and a `remove` deletes
+/// the tags this node has seen for the element, so an add that happened
+/// concurrently with a remove survives it. A removed tag is kept as a
+/// tombstone until every node has it; after that it is dropped, and `seen`
+/// is enough to tell that a late gossip of its add is stale.
+struct ORSetNode {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    // sequence number of the last add this node tagged
+    seq: u64,
+    adds: HashMap<Tag, usize>,
+    tombstones: HashSet<Tag>,
+    seen: HashMap<String, Seen>,
+    gossip_manager: Option<GossipManager<Payload, Op>>,
+}
+
+impl ORSetNode {
+    fn observe(&mut self, tag: &Tag) -> bool {
+        self.seen
+            .entry(tag.node.clone())
+            .or_default()
+            .observe(tag.seq)
+    }
+
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::Add { tag, element } => {
+                if self.observe(&tag) {
+                    self.adds.insert(tag, element);
+                }
+            }
+            Op::Remove { tag } => {
+                let new = self.observe(&tag);
+                // a tombstone that was collected already stays collected
+                if new || self.adds.remove(&tag).is_some() {
+                    self.tombstones.insert(tag);
+                }
+            }
+        }
+    }
+
+    // everything a peer may still be missing
+    fn ops(&self) -> Vec<Op> {
+        let adds = self.adds.iter().map(|(tag, element)| Op::Add {
+            tag: tag.clone(),
+            element: *element,
+        });
+        let removes = self
+            .tombstones
+            .iter()
+            .map(|tag| Op::Remove { tag: tag.clone() });
+        adds.chain(removes).collect()
+    }
+
+    /// Drop the tombstones every node has received.
+    fn collect_tombstones(&mut self) {
+        let Some(manager) = &mut self.gossip_manager else {
+            return;
+        };
+        let collected: Vec<Tag> = self
+            .tombstones
+            .iter()
+            .filter(|tag| {
+                manager.verified_by_all(&Op::Remove {
+                    tag: (*tag).clone(),
+                })
+            })
+            .cloned()
+            .collect();
+        for tag in collected {
+            tracing::info!("Collecting tombstone {:?}", tag);
+            self.tombstones.remove(&tag);
+            // the add and the removal are both settled everywhere
+            manager.forget(|op| *op.tag() == tag);
+        }
+    }
+}
+
+impl Node<Payload> for ORSetNode {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.gossip_manager = Some(GossipManager::new(tx.clone()));
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.create_gossip_monitor(
+                        self.node_id.clone(),
+                        self.node_ids.clone(),
+                        Payload::GossipSend,
+                    );
+                }
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Add { element } => {
+                self.seq += 1;
+                let tag = Tag {
+                    node: self.node_id.clone(),
+                    seq: self.seq,
+                };
+                self.apply(Op::Add { tag, element });
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::AddOk);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::AddOk => panic!("Unexpected AddOk message"),
+
+            Payload::Remove { element } => {
+                let observed: Vec<Tag> = self
+                    .adds
+                    .iter()
+                    .filter(|(_, e)| **e == element)
+                    .map(|(tag, _)| tag.clone())
+                    .collect();
+                for tag in observed {
+                    self.apply(Op::Remove { tag });
+                }
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::RemoveOk);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::RemoveOk => panic!("Unexpected RemoveOk message"),
+
+            Payload::Read => {
+                let value = self.adds.values().cloned().collect();
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::ReadOk { value });
+                writer.write_message(&reply)?;
+            }
+
+            Payload::ReadOk { value: _ } => panic!("Unexpected ReadOk message"),
+
+            Payload::GossipSend => {
+                self.collect_tombstones();
+                let ops = self.ops();
+                let neighbors = self.node_ids.clone();
+                for dest_id in neighbors {
+                    if dest_id == self.node_id {
+                        continue;
+                    }
+                    let msg_id = self.get_msg_id().expect("No message id");
+
+                    if let Some(manager) = &mut self.gossip_manager {
+                        let gossip_messages =
+                            manager.prune_stale_sent_gossips(&dest_id, msg_id, &ops);
+                        if gossip_messages.is_empty() {
+                            tracing::info!("No gossip messages to send to {}", &dest_id);
+                            continue;
+                        }
+
+                        let gossip = Message {
+                            src: self.node_id.clone(),
+                            dest: dest_id.clone(),
+                            body: Body {
+                                msg_id: Some(msg_id),
+                                in_reply_to: None,
+                                payload: Payload::Gossip {
+                                    messages: gossip_messages.into_iter().collect(),
+                                },
+                            },
+                        };
+
+                        writer.write_message(&gossip)?;
+                    }
+                }
+            }
+            Payload::Gossip { ref messages } => {
+                tracing::info!(
+                    "Received gossip messages from {}: {:?}",
+                    input_msg.src.clone(),
+                    &messages
+                );
+
+                for op in messages {
+                    self.apply(op.clone());
+                }
+
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.verify_messages(&input_msg.src, messages.iter().cloned().collect());
+
+                    let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
+                    writer.write_message(&reply)?;
+                }
+            }
+
+            Payload::GossipOk => {
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.handle_gossip_ok(input_msg);
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut node = ORSetNode {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        seq: 0,
+        adds: HashMap::new(),
+        tombstones: HashSet::new(),
+        seen: HashMap::new(),
+        gossip_manager: None,
+    };
+
+    process_loop(&mut node)
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use malen::{
+    config::env_or,
+    hlc::{HybridClock, Timestamp},
+    message::{error_code, Message, MessageWriter},
+    node::{GossipManager, Node},
+    process::process_loop,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A micro-operation of a transaction: `["r", key, null]` or
+/// `["w", key, value]`. Replies fill in the value of reads.
+type MicroOp = (String, u64, Option<u64>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    GossipSend,
+    Gossip {
+        messages: HashSet<Record>,
+    },
+    GossipOk,
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+/// Which anomalies a node rules out, selected with `MALEN_TXN_ISOLATION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isolation {
+    /// no dirty writes (G0): every write replicates on its own, so other
+    /// nodes may read a value the transaction overwrote later
+    ReadUncommitted,
+    /// no dirty writes and no aborted or intermediate reads (G1a, G1b): only
+    /// the last write of each key replicates, along with the rest of the
+    /// transaction's writes
+    ReadCommitted,
+}
+
+impl FromStr for Isolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-uncommitted" => Ok(Isolation::ReadUncommitted),
+            "read-committed" => Ok(Isolation::ReadCommitted),
+            other => anyhow::bail!("expected read-uncommitted or read-committed, got {}", other),
+        }
+    }
+}
+
+/// Writes that replicate together. Versions are ordered by the timestamp of
+/// their transaction, then by `index`, the position of the write within it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Record {
+    at: Timestamp,
+    index: usize,
+    writes: Vec<(u64, u64)>,
+}
+
+type Version = (Timestamp, usize);
+
+/// A totally available key/value store for Maelstrom's `txn-rw-register`
+/// workload.
+///
+/// Every node runs transactions against its own copy and answers straight
+/// away, then gossips the writes. Each key keeps the write with the highest
+/// version. Versions come from a hybrid logical clock that has seen every
+/// write the node applied, so write-write and write-read dependencies always
+/// point forward in version order and can never form a cycle.
+struct TxnNode {
+    msg_id: usize,
+    node_id: String,
+    node_ids: Vec<String>,
+    isolation: Isolation,
+    clock: HybridClock,
+    store: HashMap<u64, (Version, u64)>,
+    // records some node may not have yet
+    unsettled: HashSet<Record>,
+    gossip_manager: Option<GossipManager<Payload, Record>>,
+}
+
+impl TxnNode {
+    fn apply(&mut self, record: &Record) {
+        self.clock.observe(&record.at);
+        for (key, value) in &record.writes {
+            let version = (record.at.clone(), record.index);
+            let newer = self
+                .store
+                .get(key)
+                .is_none_or(|(current, _)| version > *current);
+            if newer {
+                self.store.insert(*key, (version, *value));
+            }
+        }
+    }
+
+    fn record(&mut self, record: Record) {
+        self.apply(&record);
+        self.unsettled.insert(record);
+    }
+
+    /// Run `txn` against the local copy, and return it with the reads
+    /// filled in. A transaction with a micro-op the node cannot run is
+    /// rejected before any of it runs.
+    fn execute(&mut self, mut txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, String> {
+        if let Some(op) = txn
+            .iter()
+            .find(|(op, _, value)| !matches!((op.as_str(), value), ("r", _) | ("w", Some(_))))
+        {
+            return Err(format!("cannot run micro-op {:?}", op));
+        }
+        let at = self.clock.now();
+        // the last value this transaction wrote to each key, in write order
+        let mut written: Vec<(u64, u64)> = Vec::new();
+        for (index, (op, key, value)) in txn.iter_mut().enumerate() {
+            match (op.as_str(), *value) {
+                ("r", _) => {
+                    *value = written
+                        .iter()
+                        .rev()
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| *v)
+                        .or_else(|| self.store.get(key).map(|(_, v)| *v));
+                }
+                ("w", Some(v)) => {
+                    if self.isolation == Isolation::ReadUncommitted {
+                        self.record(Record {
+                            at: at.clone(),
+                            index,
+                            writes: vec![(*key, v)],
+                        });
+                    }
+                    written.retain(|(k, _)| k != key);
+                    written.push((*key, v));
+                }
+                _ => unreachable!("micro-ops are checked before the transaction runs"),
+            }
+        }
+        if self.isolation == Isolation::ReadCommitted && !written.is_empty() {
+            self.record(Record {
+                at,
+                index: 0,
+                writes: written,
+            });
+        }
+        Ok(txn)
+    }
+
+    /// Gossip the records some node may not have yet, once the ones every
+    /// node has are settled.
+    fn gossip(&mut self, writer: &mut MessageWriter) -> anyhow::Result<()> {
+        let Some(mut manager) = self.gossip_manager.take() else {
+            return Ok(());
+        };
+        manager.settle(&mut self.unsettled);
+        let records: Vec<Record> = self.unsettled.iter().cloned().collect();
+        let result = manager.gossip(
+            &records,
+            || self.get_msg_id().expect("No message id"),
+            |messages| Payload::Gossip {
+                messages: messages.into_iter().collect(),
+            },
+            writer,
+        );
+        self.gossip_manager = Some(manager);
+        result
+    }
+}
+
+impl Node<Payload> for TxnNode {
+    fn init(&mut self, tx: std::sync::mpsc::Sender<Message<Payload>>) {
+        self.gossip_manager = Some(GossipManager::new(tx.clone()));
+    }
+
+    fn get_msg_id(&mut self) -> Option<usize> {
+        self.msg_id += 1;
+
+        Some(self.msg_id)
+    }
+
+    fn handle(
+        &mut self,
+        input_msg: Message<Payload>,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()> {
+        match input_msg.body.payload {
+            Payload::Init {
+                ref node_id,
+                ref node_ids,
+            } => {
+                self.node_id = node_id.clone();
+                self.node_ids = node_ids.clone();
+                self.clock = HybridClock::new(node_id);
+
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.create_gossip_monitor(
+                        self.node_id.clone(),
+                        self.node_ids.clone(),
+                        Payload::GossipSend,
+                    );
+                }
+
+                let reply = input_msg.into_reply(self.get_msg_id(), Payload::InitOk);
+
+                writer.write_message(&reply)?;
+            }
+
+            Payload::InitOk => panic!("Unexpected InitOk message"),
+
+            Payload::Txn { ref txn } => {
+                let payload = match self.execute(txn.clone()) {
+                    Ok(txn) => Payload::TxnOk { txn },
+                    Err(text) => Payload::Error {
+                        code: error_code::MALFORMED_REQUEST,
+                        text,
+                    },
+                };
+                let reply = input_msg.into_reply(self.get_msg_id(), payload);
+                writer.write_message(&reply)?;
+            }
+
+            Payload::TxnOk { .. } | Payload::Error { .. } => {
+                panic!("Unexpected {:?} message", input_msg.body.payload)
+            }
+
+            Payload::GossipSend => self.gossip(writer)?,
+            Payload::Gossip { ref messages } => {
+                // pass the records on too, in case the sender cannot reach
+                // every node
+                for record in messages {
+                    self.record(record.clone());
+                }
+
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.verify_messages(&input_msg.src, messages.iter().cloned().collect());
+
+                    let reply = input_msg.into_reply(self.get_msg_id(), Payload::GossipOk);
+                    writer.write_message(&reply)?;
+                }
+            }
+
+            Payload::GossipOk => {
+                if let Some(manager) = &mut self.gossip_manager {
+                    manager.handle_gossip_ok(input_msg);
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut node = TxnNode {
+        msg_id: 0,
+        node_id: "0".to_string(),
+        node_ids: Vec::new(),
+        isolation: env_or("MALEN_TXN_ISOLATION", Isolation::ReadCommitted)?,
+        clock: HybridClock::new("0"),
+        store: HashMap::new(),
+        unsettled: HashSet::new(),
+        gossip_manager: None,
+    };
+
+    process_loop(&mut node)
+}
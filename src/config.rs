@@ -0,0 +1,32 @@
+use anyhow::Context;
+use std::{fmt::Display, str::FromStr};
+
+/// Read an optional setting from the environment.
+///
+/// Maelstrom starts node binaries without arguments, so run-time options are
+/// passed as environment variables (usually from a small wrapper script).
+pub fn env_var<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("{}", e))
+            .with_context(|| format!("invalid value for {}: {:?}", name, value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read {}", name)),
+    }
+}
+
+/// Read a setting from the environment, falling back to `default` when unset.
+pub fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    Ok(env_var(name)?.unwrap_or(default))
+}
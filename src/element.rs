@@ -0,0 +1,135 @@
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Number, Value};
+
+/// Anything a node can store in a set or broadcast: it crosses the wire as
+/// JSON and is compared by value.
+pub trait Element:
+    Serialize + DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static
+{
+}
+
+impl<T> Element for T where
+    T: Serialize + DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static
+{
+}
+
+/// The element type a node runs with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementKind {
+    /// non-negative integers, which is what the Maelstrom workloads send
+    Integer,
+    /// any JSON value
+    Json,
+}
+
+impl FromStr for ElementKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "integer" => Ok(ElementKind::Integer),
+            "json" => Ok(ElementKind::Json),
+            other => anyhow::bail!("expected integer or json, got {}", other),
+        }
+    }
+}
+
+/// A JSON value usable as a set element.
+///
+/// Two values are the same element when they mean the same JSON: object keys
+/// may come in any order, and a number written as `1.0` equals `1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Value", into = "Value")]
+pub struct Json(Value);
+
+impl Json {
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+// integral floats become integers, so that equal numbers compare equal
+fn normalize(value: Value) -> Value {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            let float = number.as_f64().unwrap_or_default();
+            let integer = if float.fract() != 0.0 {
+                None
+            } else if float >= 0.0 && float < u64::MAX as f64 {
+                Some(Number::from(float as u64))
+            } else if float < 0.0 && float >= i64::MIN as f64 {
+                Some(Number::from(float as i64))
+            } else {
+                None
+            };
+            Value::Number(integer.unwrap_or(number))
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(normalize).collect()),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, normalize(value)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    match value {
+        Value::Null => state.write_u8(0),
+        Value::Bool(b) => {
+            state.write_u8(1);
+            b.hash(state);
+        }
+        Value::Number(number) => {
+            state.write_u8(2);
+            number.hash(state);
+        }
+        Value::String(s) => {
+            state.write_u8(3);
+            s.hash(state);
+        }
+        Value::Array(values) => {
+            state.write_u8(4);
+            state.write_usize(values.len());
+            for value in values {
+                hash_value(value, state);
+            }
+        }
+        Value::Object(map) => {
+            state.write_u8(5);
+            state.write_usize(map.len());
+            // equal maps hash the same whatever order they keep their keys in
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, value) in entries {
+                key.hash(state);
+                hash_value(value, state);
+            }
+        }
+    }
+}
+
+impl Hash for Json {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+impl From<Value> for Json {
+    fn from(value: Value) -> Self {
+        Json(normalize(value))
+    }
+}
+
+impl From<Json> for Value {
+    fn from(json: Json) -> Self {
+        json.0
+    }
+}
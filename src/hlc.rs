@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::id::now_millis;
+
+/// A hybrid logical clock reading: wall-clock milliseconds, a counter that
+/// orders events within a millisecond (or while the wall clock lags behind a
+/// timestamp from another node), and the node, which breaks the remaining
+/// ties. Timestamps sort in that order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u32,
+    pub node: String,
+}
+
+/// Hybrid logical clock (Kulkarni et al.). Its timestamps stay close to
+/// wall-clock time but never go backwards, and an event always gets a later
+/// timestamp than any event the node has heard of.
+#[derive(Debug)]
+pub struct HybridClock {
+    node: String,
+    wall: u64,
+    logical: u32,
+}
+
+impl HybridClock {
+    pub fn new(node: &str) -> Self {
+        Self {
+            node: node.to_string(),
+            wall: 0,
+            logical: 0,
+        }
+    }
+
+    /// Timestamp for a local event.
+    pub fn now(&mut self) -> Timestamp {
+        let physical = now_millis();
+        if physical > self.wall {
+            self.wall = physical;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+        self.timestamp()
+    }
+
+    /// Move the clock past a timestamp received from another node.
+    pub fn observe(&mut self, remote: &Timestamp) {
+        let physical = now_millis();
+        let wall = physical.max(self.wall).max(remote.wall);
+        self.logical = match (wall == self.wall, wall == remote.wall) {
+            (true, true) => self.logical.max(remote.logical) + 1,
+            (true, false) => self.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        self.wall = wall;
+    }
+
+    fn timestamp(&self) -> Timestamp {
+        Timestamp {
+            wall: self.wall,
+            logical: self.logical,
+            node: self.node.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(wall: u64, logical: u32) -> Timestamp {
+        Timestamp {
+            wall,
+            logical,
+            node: "n2".to_string(),
+        }
+    }
+
+    #[test]
+    fn now_never_goes_backwards() {
+        let mut clock = HybridClock::new("n1");
+        let mut last = clock.now();
+        for _ in 0..10_000 {
+            let next = clock.now();
+            assert!(next > last, "{:?} is not after {:?}", next, last);
+            last = next;
+        }
+    }
+
+    #[test]
+    fn now_tracks_the_wall_clock() {
+        let mut clock = HybridClock::new("n1");
+        let before = now_millis();
+        let at = clock.now();
+        assert!(at.wall >= before && at.wall <= now_millis());
+        assert_eq!(at.logical, 0);
+    }
+
+    #[test]
+    fn observing_a_timestamp_from_the_future_orders_later_events_after_it() {
+        let mut clock = HybridClock::new("n1");
+        let ahead = remote(now_millis() + 60_000, 7);
+        clock.observe(&ahead);
+        let first = clock.now();
+        assert_eq!((first.wall, first.logical), (ahead.wall, 9));
+        assert!(clock.now() > first);
+    }
+
+    #[test]
+    fn observing_a_timestamp_from_the_past_keeps_the_clock() {
+        let mut clock = HybridClock::new("n1");
+        let ahead = remote(now_millis() + 60_000, 3);
+        clock.observe(&ahead);
+        clock.observe(&remote(ahead.wall - 1, 50));
+        clock.observe(&remote(0, 0));
+        // each observation is an event of its own
+        let at = clock.now();
+        assert_eq!((at.wall, at.logical), (ahead.wall, 7));
+    }
+
+    #[test]
+    fn observing_the_same_millisecond_takes_the_larger_counter() {
+        let mut clock = HybridClock::new("n1");
+        let wall = now_millis() + 60_000;
+        clock.observe(&remote(wall, 2));
+        clock.observe(&remote(wall, 10));
+        assert_eq!((clock.wall, clock.logical), (wall, 11));
+        clock.observe(&remote(wall, 4));
+        assert_eq!((clock.wall, clock.logical), (wall, 12));
+    }
+
+    #[test]
+    fn the_node_breaks_ties() {
+        let a = Timestamp {
+            wall: 5,
+            logical: 1,
+            node: "n1".to_string(),
+        };
+        let b = remote(5, 1);
+        assert!(a < b);
+        assert!(remote(5, 0) < a);
+    }
+}
@@ -0,0 +1,549 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rand::Rng;
+
+use crate::config::{env_or, env_var};
+
+/// Source of unique IDs for the `generate` workload.
+pub trait IdGenerator: Send {
+    fn next_id(&mut self) -> anyhow::Result<String>;
+}
+
+/// The kind of ID a node hands out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdMode {
+    /// 64-bit integers: timestamp, node ordinal and sequence number
+    Snowflake,
+    /// RFC 9562 version 7 UUIDs
+    UuidV7,
+    /// Crockford base32 ULIDs
+    Ulid,
+    /// `<node>-<n>` from a counter whose high-water mark is kept on disk
+    Block,
+    /// dense integers from ranges leased off a shared counter in a KV
+    /// service; the node does the leasing, as it needs to message the service
+    Leased,
+}
+
+/// How `leased` IDs from different nodes are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdOrder {
+    /// increasing per node only: nodes lease ahead and serve from their
+    /// leases, so a node can hand out an ID below one another node already
+    /// did
+    Node,
+    /// increasing across the cluster: an ID is larger than every ID handed
+    /// out before its `generate` arrived, which costs one CAS per batch of
+    /// waiting requests
+    Global,
+}
+
+impl FromStr for IdOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "node" => Ok(IdOrder::Node),
+            "global" => Ok(IdOrder::Global),
+            other => anyhow::bail!("expected node or global, got {}", other),
+        }
+    }
+}
+
+impl FromStr for IdMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snowflake" => Ok(IdMode::Snowflake),
+            "uuidv7" => Ok(IdMode::UuidV7),
+            "ulid" => Ok(IdMode::Ulid),
+            "block" => Ok(IdMode::Block),
+            "leased" => Ok(IdMode::Leased),
+            other => anyhow::bail!(
+                "expected snowflake, uuidv7, ulid, block or leased, got {}",
+                other
+            ),
+        }
+    }
+}
+
+/// ID generator settings, read from the environment:
+///
+/// - `MALEN_GENERATE_IDS`: `snowflake` (default), `uuidv7`, `ulid`, `block`
+///   or `leased`
+/// - `MALEN_GENERATE_DATA_DIR`: where `block` keeps its high-water marks
+/// - `MALEN_GENERATE_BLOCK_SIZE`: IDs `block` reserves with each write, and
+///   `leased` with each lease
+/// - `MALEN_GENERATE_KV`: KV service `leased` takes its leases from,
+///   `lin-kv` (default) or `seq-kv`
+/// - `MALEN_GENERATE_ORDER`: how `leased` IDs are ordered, `node` (default)
+///   or `global`, which needs `lin-kv`
+/// - `MALEN_GENERATE_TIMEOUT_MS`: how long a `leased` request waits for the
+///   KV service before it fails
+#[derive(Debug, Clone)]
+pub struct IdConfig {
+    pub mode: IdMode,
+    pub data_dir: PathBuf,
+    pub block_size: u64,
+    pub kv_service: String,
+    pub order: IdOrder,
+    pub timeout: Duration,
+}
+
+impl IdConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            mode: env_or("MALEN_GENERATE_IDS", IdMode::Snowflake)?,
+            data_dir: env_var("MALEN_GENERATE_DATA_DIR")?
+                .unwrap_or_else(|| PathBuf::from("generate-data")),
+            block_size: env_or("MALEN_GENERATE_BLOCK_SIZE", 1000)?,
+            kv_service: env_or("MALEN_GENERATE_KV", "lin-kv".to_string())?,
+            order: env_or("MALEN_GENERATE_ORDER", IdOrder::Node)?,
+            timeout: Duration::from_millis(env_or("MALEN_GENERATE_TIMEOUT_MS", 1000)?),
+        };
+        // the order seq-kv applies operations in need not follow real time,
+        // which global order promises
+        if config.order == IdOrder::Global && config.kv_service != "lin-kv" {
+            anyhow::bail!(
+                "MALEN_GENERATE_ORDER=global needs lin-kv, not {}",
+                config.kv_service
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Build the generator `config` asks for on the node `node_id` of the
+/// cluster `node_ids`.
+pub fn generator(
+    config: &IdConfig,
+    node_id: &str,
+    node_ids: &[String],
+) -> anyhow::Result<Box<dyn IdGenerator>> {
+    Ok(match config.mode {
+        IdMode::Snowflake => {
+            let ordinal = node_ids
+                .iter()
+                .position(|id| id == node_id)
+                .with_context(|| format!("{} is not in node_ids", node_id))?;
+            Box::new(Snowflake::new(ordinal as u64)?)
+        }
+        IdMode::UuidV7 => Box::<UuidV7>::default(),
+        IdMode::Ulid => Box::<Ulid>::default(),
+        IdMode::Block => Box::new(BlockIds::open(
+            node_id,
+            config.data_dir.join(format!("{}.hwm", node_id)),
+            config.block_size,
+        )?),
+        IdMode::Leased => anyhow::bail!("leased IDs are allocated by the node, not a generator"),
+    })
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Millisecond clock that never goes backwards and can be pushed ahead when
+/// a generator runs out of IDs for the current millisecond.
+///
+/// If the system clock steps back, the last millisecond handed out is kept
+/// until the clock catches up again, so IDs stay unique and ordered.
+#[derive(Debug, Default)]
+struct MonotonicMillis {
+    last: u64,
+}
+
+impl MonotonicMillis {
+    /// Returns the current millisecond and whether it is a new one.
+    fn tick(&mut self) -> (u64, bool) {
+        let now = now_millis();
+        if now > self.last {
+            self.last = now;
+            return (now, true);
+        }
+        (self.last, false)
+    }
+
+    /// Move on to the next millisecond without waiting for the clock.
+    fn advance(&mut self) -> u64 {
+        self.last += 1;
+        self.last
+    }
+}
+
+/// Twitter-style Snowflake IDs: 41 bits of milliseconds since
+/// `SNOWFLAKE_EPOCH_MS`, 10 bits of node ordinal and 12 bits of sequence.
+/// IDs from one node are strictly increasing; across nodes they sort by
+/// time to within clock skew.
+pub struct Snowflake {
+    node: u64,
+    clock: MonotonicMillis,
+    sequence: u64,
+}
+
+/// 2024-01-01T00:00:00Z, which leaves the 41 timestamp bits good for ~69 years.
+pub const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+impl Snowflake {
+    pub fn new(node: u64) -> anyhow::Result<Self> {
+        if node >= 1 << NODE_BITS {
+            anyhow::bail!("node ordinal {} does not fit in {} bits", node, NODE_BITS);
+        }
+        Ok(Self {
+            node,
+            clock: MonotonicMillis::default(),
+            sequence: 0,
+        })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let (mut millis, new) = self.clock.tick();
+        if new {
+            self.sequence = 0;
+        } else if self.sequence == MAX_SEQUENCE {
+            // every sequence number of this millisecond is used up
+            millis = self.clock.advance();
+            self.sequence = 0;
+        } else {
+            self.sequence += 1;
+        }
+
+        let elapsed = millis.saturating_sub(SNOWFLAKE_EPOCH_MS);
+        (elapsed << (NODE_BITS + SEQUENCE_BITS)) | (self.node << SEQUENCE_BITS) | self.sequence
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn next_id(&mut self) -> anyhow::Result<String> {
+        Ok(self.next_u64().to_string())
+    }
+}
+
+/// Version 7 UUIDs: 48 bits of Unix milliseconds, then a 12-bit counter in
+/// `rand_a` that keeps IDs from one generator ordered within a millisecond,
+/// then 62 random bits.
+#[derive(Default)]
+pub struct UuidV7 {
+    clock: MonotonicMillis,
+    counter: u16,
+}
+
+const UUID_COUNTER_BITS: u32 = 12;
+
+impl UuidV7 {
+    pub fn next_u128(&mut self) -> u128 {
+        let mut rng = rand::thread_rng();
+        let (mut millis, new) = self.clock.tick();
+        if new {
+            // start low in the counter range so there is room to count up
+            self.counter = rng.gen_range(0..1 << (UUID_COUNTER_BITS - 1));
+        } else if u32::from(self.counter) == (1 << UUID_COUNTER_BITS) - 1 {
+            millis = self.clock.advance();
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+
+        let rand_b: u64 = rng.gen::<u64>() & ((1 << 62) - 1);
+        (u128::from(millis & ((1 << 48) - 1)) << 80)
+            | (0x7 << 76)
+            | (u128::from(self.counter) << 64)
+            | (0b10 << 62)
+            | u128::from(rand_b)
+    }
+}
+
+impl IdGenerator for UuidV7 {
+    fn next_id(&mut self) -> anyhow::Result<String> {
+        let hex = format!("{:032x}", self.next_u128());
+        Ok(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        ))
+    }
+}
+
+/// ULIDs: 48 bits of Unix milliseconds and 80 random bits. Within a
+/// millisecond the random part is incremented, so IDs from one generator
+/// are strictly increasing.
+#[derive(Default)]
+pub struct Ulid {
+    clock: MonotonicMillis,
+    random: u128,
+}
+
+const ULID_RANDOM_BITS: u32 = 80;
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl Ulid {
+    pub fn next_u128(&mut self) -> u128 {
+        let max_random = (1u128 << ULID_RANDOM_BITS) - 1;
+        let (mut millis, new) = self.clock.tick();
+        if new {
+            self.random = rand::thread_rng().gen::<u128>() & max_random;
+        } else if self.random == max_random {
+            millis = self.clock.advance();
+            self.random = rand::thread_rng().gen::<u128>() & max_random;
+        } else {
+            self.random += 1;
+        }
+        (u128::from(millis & ((1 << 48) - 1)) << ULID_RANDOM_BITS) | self.random
+    }
+}
+
+impl IdGenerator for Ulid {
+    fn next_id(&mut self) -> anyhow::Result<String> {
+        let value = self.next_u128();
+        // 26 characters of 5 bits each; the first one only carries 3
+        Ok((0..26)
+            .rev()
+            .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+            .collect())
+    }
+}
+
+/// `<node>-<n>` IDs that stay unique across restarts.
+///
+/// The counter's high-water mark is written to disk a block of IDs ahead of
+/// use, so only one write in `block_size` requests touches the disk. After a
+/// crash the node carries on from the high-water mark, skipping whatever was
+/// left of the block it had reserved.
+pub struct BlockIds {
+    node_id: String,
+    path: PathBuf,
+    block_size: u64,
+    next: u64,
+    // first value not covered by the reservation on disk
+    reserved: u64,
+}
+
+impl BlockIds {
+    pub fn open(node_id: &str, path: PathBuf, block_size: u64) -> anyhow::Result<Self> {
+        if block_size == 0 {
+            anyhow::bail!("block size must be at least 1");
+        }
+        let high_water_mark = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .with_context(|| format!("corrupt high-water mark in {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        tracing::info!(
+            "Resuming IDs from {} in {}",
+            high_water_mark,
+            path.display()
+        );
+
+        Ok(Self {
+            node_id: node_id.to_string(),
+            path,
+            block_size,
+            next: high_water_mark,
+            reserved: high_water_mark,
+        })
+    }
+
+    // write the new high-water mark before any ID below it is handed out
+    fn reserve(&mut self) -> anyhow::Result<()> {
+        let reserved = self.next + self.block_size;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+        file.write_all(reserved.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path).with_context(|| format!("replace {}", self.path.display()))?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+impl IdGenerator for BlockIds {
+    fn next_id(&mut self) -> anyhow::Result<String> {
+        if self.next == self.reserved {
+            self.reserve()?;
+        }
+        let id = format!("{}-{}", self.node_id, self.next);
+        self.next += 1;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // a clock that has handed out a millisecond a second from now looks to
+    // the generator like the system clock stepped back a second
+    fn step_back(clock: &mut MonotonicMillis) -> u64 {
+        clock.last = now_millis() + 1000;
+        clock.last
+    }
+
+    fn assert_increasing(ids: &[u128]) {
+        for pair in ids.windows(2) {
+            assert!(
+                pair[0] < pair[1],
+                "{:x} is not below {:x}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    fn snowflake_millis(id: u64) -> u64 {
+        (id >> (NODE_BITS + SEQUENCE_BITS)) + SNOWFLAKE_EPOCH_MS
+    }
+
+    fn uuid_millis(id: u128) -> u64 {
+        (id >> 80) as u64
+    }
+
+    fn ulid_millis(id: u128) -> u64 {
+        (id >> ULID_RANDOM_BITS) as u64
+    }
+
+    #[test]
+    fn snowflakes_increase_and_differ_between_nodes() {
+        let mut first = Snowflake::new(0).unwrap();
+        let mut second = Snowflake::new(1).unwrap();
+        let ids: Vec<u128> = (0..10_000).map(|_| first.next_u64().into()).collect();
+        assert_increasing(&ids);
+
+        let others: HashSet<u128> = (0..10_000).map(|_| second.next_u64().into()).collect();
+        assert!(ids.iter().all(|id| !others.contains(id)));
+        assert!(Snowflake::new(1 << NODE_BITS).is_err());
+    }
+
+    #[test]
+    fn snowflakes_keep_the_last_millisecond_when_the_clock_steps_back() {
+        let mut generator = Snowflake::new(3).unwrap();
+        let before = generator.next_u64();
+        let last = step_back(&mut generator.clock);
+        let ids: Vec<u64> = (0..100).map(|_| generator.next_u64()).collect();
+        assert!(before < ids[0]);
+        assert_increasing(&ids.iter().map(|id| u128::from(*id)).collect::<Vec<_>>());
+        assert!(ids.iter().all(|id| snowflake_millis(*id) == last));
+    }
+
+    #[test]
+    fn snowflakes_move_to_the_next_millisecond_when_the_sequence_runs_out() {
+        let mut generator = Snowflake::new(3).unwrap();
+        let last = step_back(&mut generator.clock);
+        generator.sequence = MAX_SEQUENCE - 1;
+
+        let full = generator.next_u64();
+        assert_eq!(snowflake_millis(full), last);
+        assert_eq!(full & MAX_SEQUENCE, MAX_SEQUENCE);
+        let next = generator.next_u64();
+        assert_eq!(snowflake_millis(next), last + 1);
+        assert_eq!(next & MAX_SEQUENCE, 0);
+        assert_eq!((next >> SEQUENCE_BITS) & ((1 << NODE_BITS) - 1), 3);
+    }
+
+    #[test]
+    fn uuids_increase_and_are_version_7() {
+        let mut generator = UuidV7::default();
+        let ids: Vec<u128> = (0..10_000).map(|_| generator.next_u128()).collect();
+        assert_increasing(&ids);
+        for id in &ids {
+            assert_eq!((id >> 76) & 0xf, 7);
+            assert_eq!((id >> 62) & 0b11, 0b10);
+        }
+
+        let strings: Vec<String> = (0..1000).map(|_| generator.next_id().unwrap()).collect();
+        let mut sorted = strings.clone();
+        sorted.sort();
+        assert_eq!(strings, sorted);
+        assert_eq!(strings.iter().collect::<HashSet<_>>().len(), strings.len());
+    }
+
+    #[test]
+    fn uuids_keep_the_last_millisecond_when_the_clock_steps_back() {
+        let mut generator = UuidV7::default();
+        let before = generator.next_u128();
+        let last = step_back(&mut generator.clock);
+        let mut ids = vec![before];
+        ids.extend((0..100).map(|_| generator.next_u128()));
+        assert_increasing(&ids);
+        assert!(ids[1..].iter().all(|id| uuid_millis(*id) == last));
+    }
+
+    #[test]
+    fn uuids_move_to_the_next_millisecond_when_the_counter_runs_out() {
+        let mut generator = UuidV7::default();
+        let last = step_back(&mut generator.clock);
+        generator.counter = (1 << UUID_COUNTER_BITS) - 2;
+
+        let full = generator.next_u128();
+        assert_eq!(uuid_millis(full), last);
+        let next = generator.next_u128();
+        assert_eq!(uuid_millis(next), last + 1);
+        assert_eq!((next >> 64) & 0xfff, 0);
+        assert!(full < next);
+    }
+
+    #[test]
+    fn ulids_increase() {
+        let mut generator = Ulid::default();
+        let ids: Vec<u128> = (0..10_000).map(|_| generator.next_u128()).collect();
+        assert_increasing(&ids);
+
+        let strings: Vec<String> = (0..1000).map(|_| generator.next_id().unwrap()).collect();
+        assert!(strings
+            .iter()
+            .all(|id| id.len() == 26 && id.bytes().all(|c| CROCKFORD.contains(&c))));
+        let mut sorted = strings.clone();
+        sorted.sort();
+        assert_eq!(strings, sorted);
+        assert_eq!(strings.iter().collect::<HashSet<_>>().len(), strings.len());
+    }
+
+    #[test]
+    fn ulids_keep_the_last_millisecond_when_the_clock_steps_back() {
+        let mut generator = Ulid::default();
+        let before = generator.next_u128();
+        let last = step_back(&mut generator.clock);
+        let mut ids = vec![before];
+        ids.extend((0..100).map(|_| generator.next_u128()));
+        assert_increasing(&ids);
+        assert!(ids[1..].iter().all(|id| ulid_millis(*id) == last));
+    }
+
+    #[test]
+    fn ulids_move_to_the_next_millisecond_when_the_random_part_runs_out() {
+        let mut generator = Ulid::default();
+        let last = step_back(&mut generator.clock);
+        generator.random = (1 << ULID_RANDOM_BITS) - 1;
+
+        let next = generator.next_u128();
+        assert_eq!(ulid_millis(next), last + 1);
+        let after = generator.next_u128();
+        assert!(next < after);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod config;
+pub mod element;
+pub mod hlc;
+pub mod id;
 pub mod message;
 pub mod node;
 pub mod process;
+pub mod raft;
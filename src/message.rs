@@ -83,3 +83,19 @@ impl Default for MessageReader {
         Self::new()
     }
 }
+
+/// Error codes defined by the Maelstrom protocol. Codes from 1000 upwards are
+/// free for application-specific errors.
+pub mod error_code {
+    pub const TIMEOUT: u64 = 0;
+    pub const NODE_NOT_FOUND: u64 = 1;
+    pub const NOT_SUPPORTED: u64 = 10;
+    pub const TEMPORARILY_UNAVAILABLE: u64 = 11;
+    pub const MALFORMED_REQUEST: u64 = 12;
+    pub const CRASH: u64 = 13;
+    pub const ABORT: u64 = 14;
+    pub const KEY_DOES_NOT_EXIST: u64 = 20;
+    pub const KEY_ALREADY_EXISTS: u64 = 21;
+    pub const PRECONDITION_FAILED: u64 = 22;
+    pub const TXN_CONFLICT: u64 = 30;
+}
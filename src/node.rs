@@ -4,6 +4,8 @@ use std::{
     time::Duration,
 };
 
+use serde::Serialize;
+
 use crate::message::{Body, Message, MessageWriter};
 
 pub trait Node<Payload>
@@ -19,6 +21,34 @@ where
     ) -> anyhow::Result<()>;
 }
 
+/// Deliver `payload` to the node itself every `interval`, for work that has
+/// to happen on a timer (gossip, retention, timeouts). The thread stops once
+/// the node's message loop has gone away.
+pub fn spawn_ticker<Payload>(
+    tx: Sender<Message<Payload>>,
+    node_id: String,
+    interval: Duration,
+    payload: Payload,
+) where
+    Payload: Send + Clone + 'static,
+{
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let tick = Message {
+            src: node_id.clone(),
+            dest: node_id.clone(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                payload: payload.clone(),
+            },
+        };
+        if tx.send(tick).is_err() {
+            return;
+        }
+    });
+}
+
 pub struct GossipManager<Payload, T>
 where
     Payload: Send + Clone + 'static,
@@ -55,30 +85,20 @@ where
         payload: Payload,
     ) {
         self.node_id = node_id.clone();
-        self.node_ids = node_ids.clone();
-        let tx = self.tx.clone();
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(5000));
-            let gossip = Message {
-                src: node_id.clone(),
-                dest: node_id.clone(),
-                body: Body {
-                    msg_id: None,
-                    in_reply_to: None,
-                    payload: payload.clone(),
-                },
-            };
-            if tx.send(gossip).is_err() {
-                return Ok::<_, anyhow::Error>(());
-            }
-        });
+        self.node_ids = node_ids;
+        spawn_ticker(
+            self.tx.clone(),
+            node_id,
+            Duration::from_millis(5000),
+            payload,
+        );
     }
 
     pub fn prune_stale_sent_gossips(
         &mut self,
         dest_id: &str,
         msg_id: usize,
-        values: &Vec<T>,
+        values: &[T],
     ) -> Vec<T> {
         // check for stale gossip messages
         let current_gossip_msg_ids = self
@@ -130,6 +150,79 @@ where
             .extend(messages);
     }
 
+    /// Whether every other node has received `item`.
+    pub fn verified_by_all(&self, item: &T) -> bool {
+        self.node_ids
+            .iter()
+            .filter(|node| **node != self.node_id)
+            .all(|node| {
+                self.verified
+                    .get(node)
+                    .is_some_and(|verified| verified.contains(item))
+            })
+    }
+
+    /// Stop tracking the items `forgotten` matches, once they will not be
+    /// gossiped again.
+    pub fn forget(&mut self, forgotten: impl Fn(&T) -> bool) {
+        for verified in self.verified.values_mut() {
+            verified.retain(|v| !forgotten(v));
+        }
+        for sent in self.gossips_sent.values_mut() {
+            sent.retain(|v| !forgotten(v));
+        }
+    }
+
+    /// Drop the items of `unsettled` every other node has received, and
+    /// stop tracking them.
+    pub fn settle(&mut self, unsettled: &mut HashSet<T>) {
+        let settled: Vec<T> = unsettled
+            .iter()
+            .filter(|item| self.verified_by_all(item))
+            .cloned()
+            .collect();
+        for item in settled {
+            self.forget(|v| *v == item);
+            unsettled.remove(&item);
+        }
+    }
+
+    /// Send every other node the `items` it is not known to have yet, in a
+    /// message numbered by `next_msg_id` with the payload `gossip` makes.
+    pub fn gossip(
+        &mut self,
+        items: &[T],
+        mut next_msg_id: impl FnMut() -> usize,
+        gossip: impl Fn(Vec<T>) -> Payload,
+        writer: &mut MessageWriter,
+    ) -> anyhow::Result<()>
+    where
+        Payload: Serialize,
+    {
+        for dest_id in self.node_ids.clone() {
+            if dest_id == self.node_id {
+                continue;
+            }
+            let msg_id = next_msg_id();
+            let gossip_messages = self.prune_stale_sent_gossips(&dest_id, msg_id, items);
+            if gossip_messages.is_empty() {
+                continue;
+            }
+
+            let message = Message {
+                src: self.node_id.clone(),
+                dest: dest_id,
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload: gossip(gossip_messages),
+                },
+            };
+            writer.write_message(&message)?;
+        }
+        Ok(())
+    }
+
     pub fn handle_gossip_ok(&mut self, input_msg: Message<Payload>) {
         if let Some(msg_id) = input_msg.body.in_reply_to {
             tracing::info!("Received GossipOk from {}", input_msg.src.clone());
@@ -0,0 +1,1051 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::env_or,
+    message::{Body, Message, MessageWriter},
+};
+
+/// The replicated state a node embeds. Commands are applied in log order on
+/// every node once they are committed.
+pub trait StateMachine: Send {
+    type Command: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+    type Output;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+}
+
+/// One slot of the log. Leaders start their term with an entry that has no
+/// command, which is what lets them find out what is committed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "C: Serialize + DeserializeOwned")]
+pub struct Entry<C> {
+    pub term: u64,
+    pub command: Option<C>,
+}
+
+/// Messages between Raft peers. Binaries carry them in their own payload,
+/// e.g. `Raft { rpc: RaftRpc<Command> }`. Replies say everything the sender
+/// needs, so they are sent without a msg_id and matched by `src`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+#[serde(bound = "C: Serialize + DeserializeOwned")]
+pub enum RaftRpc<C> {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+        leader_commit: u64,
+        /// heartbeat round, echoed back to confirm leadership for reads
+        round: u64,
+    },
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        /// on success the last index the follower shares with the leader,
+        /// otherwise the last one it might share
+        match_index: u64,
+        round: u64,
+    },
+}
+
+/// Something that happened that the embedding node may be waiting on.
+#[derive(Debug)]
+pub enum Event<O> {
+    /// the entry at `index` was committed and applied; `output` is `None`
+    /// for a leader's empty entry
+    Applied {
+        index: u64,
+        term: u64,
+        output: Option<O>,
+    },
+    /// the read from `read_index` may be served from the state machine
+    ReadReady { id: u64 },
+    /// the node stopped being leader before the read could be confirmed
+    ReadFailed { id: u64 },
+}
+
+/// Raft timing, read from the environment:
+///
+/// - `MALEN_RAFT_ELECTION_TIMEOUT_MS`: the shortest time a follower waits to
+///   hear from a leader before it stands for election; each wait is drawn
+///   between this and twice this
+/// - `MALEN_RAFT_HEARTBEAT_MS`: how often a leader sends `append_entries`
+///   when it has nothing new
+/// - `MALEN_RAFT_MAX_BATCH`: most entries one `append_entries` carries
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    pub max_batch: usize,
+}
+
+impl RaftConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = Self {
+            election_timeout: Duration::from_millis(env_or(
+                "MALEN_RAFT_ELECTION_TIMEOUT_MS",
+                1000,
+            )?),
+            heartbeat_interval: Duration::from_millis(env_or("MALEN_RAFT_HEARTBEAT_MS", 100)?),
+            max_batch: env_or("MALEN_RAFT_MAX_BATCH", 100)?,
+        };
+        if config.heartbeat_interval >= config.election_timeout {
+            anyhow::bail!(
+                "MALEN_RAFT_HEARTBEAT_MS ({:?}) must be shorter than MALEN_RAFT_ELECTION_TIMEOUT_MS ({:?})",
+                config.heartbeat_interval,
+                config.election_timeout
+            );
+        }
+        Ok(config)
+    }
+
+    /// How often the embedding node should call `tick`.
+    pub fn tick_interval(&self) -> Duration {
+        self.heartbeat_interval / 2
+    }
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        next_index: HashMap<String, u64>,
+        match_index: HashMap<String, u64>,
+        /// latest heartbeat round each peer answered in this term
+        acked_round: HashMap<String, u64>,
+        round: u64,
+        last_broadcast: Instant,
+    },
+}
+
+struct PendingRead {
+    id: u64,
+    read_index: u64,
+    round: u64,
+}
+
+/// The node was asked to do something only the leader can do.
+#[derive(Debug, Clone)]
+pub struct NotLeader {
+    /// the leader of the current term, if this node knows it
+    pub leader: Option<String>,
+}
+
+/// Raft consensus (Ongaro and Ousterhout) for one node: leader election, log
+/// replication and commitment, with linearizable reads through a read index.
+///
+/// It does no I/O of its own. The embedding node hands it the RPCs it
+/// receives and calls `tick` on a timer, then sends what `take_rpcs` returns
+/// (`write_rpcs` does that) and answers clients from `take_events`.
+///
+/// Term, vote and log live in memory only, so a node that restarts must not
+/// rejoin the cluster under the same ID.
+pub struct Raft<S: StateMachine> {
+    pub state_machine: S,
+    config: RaftConfig,
+    node_id: String,
+    node_ids: Vec<String>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    // log[0] is a placeholder, so entries are numbered from 1
+    log: Vec<Entry<S::Command>>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    reads: Vec<PendingRead>,
+    next_read_id: u64,
+    events: Vec<Event<S::Output>>,
+    outbox: Vec<(String, RaftRpc<S::Command>)>,
+}
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(state_machine: S, config: RaftConfig) -> Self {
+        Self {
+            state_machine,
+            config,
+            node_id: "0".to_string(),
+            node_ids: Vec::new(),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: vec![Entry {
+                term: 0,
+                command: None,
+            }],
+            commit_index: 0,
+            last_applied: 0,
+            election_deadline: Instant::now(),
+            reads: Vec::new(),
+            next_read_id: 0,
+            events: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Join the cluster `node_ids` as `node_id`, as a follower.
+    pub fn start(&mut self, node_id: &str, node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+        self.node_ids = node_ids.to_vec();
+        self.reset_election_deadline();
+    }
+
+    pub fn config(&self) -> &RaftConfig {
+        &self.config
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader of the current term, if this node knows it.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// Events since the last call, in the order they happened.
+    pub fn take_events(&mut self) -> Vec<Event<S::Output>> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// RPCs to send since the last call, with their destinations.
+    pub fn take_rpcs(&mut self) -> Vec<(String, RaftRpc<S::Command>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn send(&mut self, dest: &str, rpc: RaftRpc<S::Command>) {
+        self.outbox.push((dest.to_string(), rpc));
+    }
+
+    fn not_leader(&self) -> NotLeader {
+        NotLeader {
+            leader: self.leader.clone(),
+        }
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|node| **node != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    fn majority(&self) -> usize {
+        self.node_ids.len() / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64 - 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        self.log.get(index as usize).map(|entry| entry.term)
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = self.config.election_timeout;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..timeout);
+        self.election_deadline = Instant::now() + timeout + jitter;
+    }
+
+    /// Append `command` to the log. Returns the index and term it was given;
+    /// it took effect if an `Applied` event with both comes back.
+    pub fn propose(&mut self, command: S::Command) -> Result<(u64, u64), NotLeader> {
+        if !self.is_leader() {
+            return Err(self.not_leader());
+        }
+        self.log.push(Entry {
+            term: self.term,
+            command: Some(command),
+        });
+        let index = self.last_index();
+        // on its own the leader commits straight away
+        self.advance_commit_index();
+        self.broadcast();
+        Ok((index, self.term))
+    }
+
+    /// Start a linearizable read. Once a `ReadReady` event with the returned
+    /// ID comes back, the state machine holds every write that completed
+    /// before this call.
+    pub fn read_index(&mut self) -> Result<u64, NotLeader> {
+        let Role::Leader { round, .. } = self.role else {
+            return Err(self.not_leader());
+        };
+        self.next_read_id += 1;
+        let id = self.next_read_id;
+        // before this term's first entry commits, what earlier leaders
+        // committed is only known to be somewhere in the log
+        let read_index = if self.term_at(self.commit_index) == Some(self.term) {
+            self.commit_index
+        } else {
+            self.last_index()
+        };
+        self.reads.push(PendingRead {
+            id,
+            read_index,
+            // only acks to a heartbeat sent from now on show the node was
+            // still leader when the read arrived
+            round: round + 1,
+        });
+        self.broadcast();
+        self.check_reads();
+        Ok(id)
+    }
+
+    /// Start an election or send heartbeats, whichever is due.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        match self.role {
+            Role::Leader { last_broadcast, .. }
+                if now.duration_since(last_broadcast) >= self.config.heartbeat_interval =>
+            {
+                self.broadcast()
+            }
+            Role::Leader { .. } => {}
+            _ if now >= self.election_deadline => self.start_election(),
+            _ => {}
+        }
+    }
+
+    /// Handle an RPC from the peer `src`.
+    pub fn handle(&mut self, src: &str, rpc: RaftRpc<S::Command>) {
+        let term = match &rpc {
+            RaftRpc::RequestVote { term, .. }
+            | RaftRpc::RequestVoteOk { term, .. }
+            | RaftRpc::AppendEntries { term, .. }
+            | RaftRpc::AppendEntriesOk { term, .. } => *term,
+        };
+        if term > self.term {
+            self.become_follower(term, None);
+        }
+
+        match rpc {
+            RaftRpc::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(src, term, last_log_index, last_log_term),
+            RaftRpc::RequestVoteOk { term, vote_granted } => {
+                self.handle_request_vote_ok(src, term, vote_granted)
+            }
+            RaftRpc::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+            } => self.handle_append_entries(
+                src,
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                round,
+            ),
+            RaftRpc::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+                round,
+            } => self.handle_append_entries_ok(src, term, success, match_index, round),
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.is_leader() {
+            tracing::info!("{} steps down in term {}", self.node_id, self.term);
+            for read in std::mem::take(&mut self.reads) {
+                self.events.push(Event::ReadFailed { id: read.id });
+            }
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+    }
+
+    fn start_election(&mut self) {
+        self.term += 1;
+        self.voted_for = Some(self.node_id.clone());
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.node_id.clone()]),
+        };
+        self.reset_election_deadline();
+        tracing::info!("{} stands for election in term {}", self.node_id, self.term);
+
+        if self.majority() <= 1 {
+            self.become_leader();
+            return;
+        }
+        let rpc = RaftRpc::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(&peer, rpc.clone());
+        }
+    }
+
+    fn become_leader(&mut self) {
+        tracing::info!("{} leads term {}", self.node_id, self.term);
+        let next = self.last_index() + 1;
+        let peers = self.peers();
+        self.role = Role::Leader {
+            next_index: peers.iter().map(|peer| (peer.clone(), next)).collect(),
+            match_index: peers.iter().map(|peer| (peer.clone(), 0)).collect(),
+            acked_round: HashMap::new(),
+            round: 0,
+            last_broadcast: Instant::now(),
+        };
+        self.leader = Some(self.node_id.clone());
+        // entries of earlier terms only count as committed once one from
+        // this term is
+        self.log.push(Entry {
+            term: self.term,
+            command: None,
+        });
+        self.advance_commit_index();
+        self.broadcast();
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        src: &str,
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) {
+        let up_to_date = (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+        let free = self.voted_for.as_ref().is_none_or(|voted| voted == src);
+        let vote_granted = term == self.term && free && up_to_date;
+        if vote_granted {
+            self.voted_for = Some(src.to_string());
+            self.reset_election_deadline();
+        }
+        let reply = RaftRpc::RequestVoteOk {
+            term: self.term,
+            vote_granted,
+        };
+        self.send(src, reply);
+    }
+
+    fn handle_request_vote_ok(&mut self, src: &str, term: u64, vote_granted: bool) {
+        let majority = self.majority();
+        let Role::Candidate { votes } = &mut self.role else {
+            return;
+        };
+        if term != self.term || !vote_granted {
+            return;
+        }
+        votes.insert(src.to_string());
+        if votes.len() >= majority {
+            self.become_leader();
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_append_entries(
+        &mut self,
+        src: &str,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<S::Command>>,
+        leader_commit: u64,
+        round: u64,
+    ) {
+        let reply = |term, success, match_index| RaftRpc::AppendEntriesOk {
+            term,
+            success,
+            match_index,
+            round,
+        };
+        if term < self.term {
+            self.send(src, reply(self.term, false, 0));
+            return;
+        }
+        // a candidate that lost to the sender, or a follower hearing from it
+        self.become_follower(term, Some(src.to_string()));
+        self.reset_election_deadline();
+
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            let hint = prev_log_index.saturating_sub(1).min(self.last_index());
+            self.send(src, reply(self.term, false, hint));
+            return;
+        }
+
+        let mut index = prev_log_index;
+        for entry in entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(existing) if existing == entry.term => continue,
+                Some(_) => {
+                    // the leader has something else here; what follows it
+                    // cannot be committed, so it goes too
+                    tracing::info!("{} truncates its log from {}", self.node_id, index);
+                    self.log.truncate(index as usize);
+                }
+                None => {}
+            }
+            self.log.push(entry);
+        }
+
+        // only up to `index` is known to match the leader's log, which an
+        // older or backed-up request may put below what is committed already
+        if leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(leader_commit.min(index));
+            self.apply_committed();
+        }
+        self.send(src, reply(self.term, true, index));
+    }
+
+    fn handle_append_entries_ok(
+        &mut self,
+        src: &str,
+        term: u64,
+        success: bool,
+        match_index: u64,
+        round: u64,
+    ) {
+        if term != self.term {
+            return;
+        }
+        let Role::Leader {
+            next_index,
+            match_index: matched,
+            acked_round,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+
+        // failed or not, the reply shows the peer still follows this term
+        let acked = acked_round.entry(src.to_string()).or_insert(0);
+        *acked = (*acked).max(round);
+
+        let next = next_index.entry(src.to_string()).or_insert(1);
+        if success {
+            let matched = matched.entry(src.to_string()).or_insert(0);
+            *matched = (*matched).max(match_index);
+            *next = (*next).max(*matched + 1);
+            self.advance_commit_index();
+            self.check_reads();
+            return;
+        }
+
+        // back up to where the logs might agree and try again
+        let matched = matched.get(src).copied().unwrap_or(0);
+        *next = (match_index + 1).min(*next - 1).max(matched + 1);
+        if let Some((peer, rpc)) = self.append_entries_for(src, round) {
+            self.send(&peer, rpc);
+        }
+    }
+
+    fn append_entries_for(&self, peer: &str, round: u64) -> Option<(String, RaftRpc<S::Command>)> {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return None;
+        };
+        let next = next_index.get(peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next - 1;
+        let entries = self
+            .log
+            .iter()
+            .skip(next as usize)
+            .take(self.config.max_batch)
+            .cloned()
+            .collect();
+        let rpc = RaftRpc::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+            round,
+        };
+        Some((peer.to_string(), rpc))
+    }
+
+    // send every peer what it is missing, as a new heartbeat round
+    fn broadcast(&mut self) {
+        let Role::Leader {
+            round,
+            last_broadcast,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+        *round += 1;
+        *last_broadcast = Instant::now();
+        let round = *round;
+        for peer in self.peers() {
+            if let Some((peer, rpc)) = self.append_entries_for(&peer, round) {
+                self.send(&peer, rpc);
+            }
+        }
+    }
+
+    // the highest index of this term a majority has, and everything before it
+    fn advance_commit_index(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let mut indexes: Vec<u64> = match_index.values().copied().collect();
+        indexes.push(self.last_index());
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(&index) = indexes.get(self.majority() - 1) else {
+            return;
+        };
+        if index > self.commit_index && self.term_at(index) == Some(self.term) {
+            self.commit_index = index;
+            self.apply_committed();
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied as usize];
+            let output = entry
+                .command
+                .as_ref()
+                .map(|command| self.state_machine.apply(command));
+            self.events.push(Event::Applied {
+                index: self.last_applied,
+                term: entry.term,
+                output,
+            });
+        }
+    }
+
+    // reads whose round a majority answered, once the state machine has
+    // caught up with their read index
+    fn check_reads(&mut self) {
+        let Role::Leader { acked_round, .. } = &self.role else {
+            return;
+        };
+        let mut acked: Vec<u64> = acked_round.values().copied().collect();
+        acked.push(u64::MAX);
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed = acked.get(self.majority() - 1).copied().unwrap_or(0);
+
+        let last_applied = self.last_applied;
+        let (ready, waiting): (Vec<PendingRead>, Vec<PendingRead>) =
+            std::mem::take(&mut self.reads)
+                .into_iter()
+                .partition(|read| read.round <= confirmed && read.read_index <= last_applied);
+        self.reads = waiting;
+        for read in ready {
+            self.events.push(Event::ReadReady { id: read.id });
+        }
+    }
+}
+
+/// Send `rpcs` from `node_id`, each wrapped in the node's own payload.
+pub fn write_rpcs<C, Payload>(
+    node_id: &str,
+    rpcs: Vec<(String, RaftRpc<C>)>,
+    wrap: impl Fn(RaftRpc<C>) -> Payload,
+    writer: &mut MessageWriter,
+) -> anyhow::Result<()>
+where
+    Payload: Serialize,
+{
+    for (dest, rpc) in rpcs {
+        let message = Message {
+            src: node_id.to_string(),
+            dest,
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                payload: wrap(rpc),
+            },
+        };
+        writer.write_message(&message)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Appends every command to a list.
+    #[derive(Default)]
+    struct Log(Vec<u64>);
+
+    impl StateMachine for Log {
+        type Command = u64;
+        type Output = usize;
+
+        fn apply(&mut self, command: &u64) -> usize {
+            self.0.push(*command);
+            self.0.len()
+        }
+    }
+
+    /// Nodes wired together in memory. Nothing happens until the test ticks
+    /// a node, and messages only move on `deliver`.
+    struct Network {
+        nodes: BTreeMap<String, Raft<Log>>,
+        dropped: HashSet<(String, String)>,
+    }
+
+    impl Network {
+        fn new(count: usize) -> Self {
+            // timeouts this short have always passed by the time a test
+            // ticks a node, so only the node ticked stands for election
+            let config = RaftConfig {
+                election_timeout: Duration::from_millis(1),
+                heartbeat_interval: Duration::ZERO,
+                max_batch: 100,
+            };
+            let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            let nodes = node_ids
+                .iter()
+                .map(|node_id| {
+                    let mut raft = Raft::new(Log::default(), config.clone());
+                    raft.start(node_id, &node_ids);
+                    (node_id.clone(), raft)
+                })
+                .collect();
+            Self {
+                nodes,
+                dropped: HashSet::new(),
+            }
+        }
+
+        fn node(&mut self, node_id: &str) -> &mut Raft<Log> {
+            self.nodes.get_mut(node_id).expect("known node")
+        }
+
+        /// Hand every RPC to its destination, and the RPCs that causes, until
+        /// none are left.
+        fn deliver(&mut self) {
+            loop {
+                let mut rpcs = Vec::new();
+                for (src, raft) in &mut self.nodes {
+                    for (dest, rpc) in raft.take_rpcs() {
+                        rpcs.push((src.clone(), dest, rpc));
+                    }
+                }
+                if rpcs.is_empty() {
+                    return;
+                }
+                for (src, dest, rpc) in rpcs {
+                    if !self.dropped.contains(&(src.clone(), dest.clone())) {
+                        self.node(&dest).handle(&src, rpc);
+                    }
+                }
+            }
+        }
+
+        /// Tick `node_id` once its election timeout has run out, and settle
+        /// what follows.
+        fn tick(&mut self, node_id: &str) {
+            std::thread::sleep(Duration::from_millis(3));
+            self.node(node_id).tick();
+            self.deliver();
+        }
+
+        fn elect(&mut self, node_id: &str) {
+            self.tick(node_id);
+            assert!(
+                self.node(node_id).is_leader(),
+                "{} was not elected",
+                node_id
+            );
+        }
+
+        fn isolate(&mut self, node_id: &str) {
+            for other in self.nodes.keys() {
+                self.dropped.insert((node_id.to_string(), other.clone()));
+                self.dropped.insert((other.clone(), node_id.to_string()));
+            }
+        }
+
+        fn heal(&mut self) {
+            self.dropped.clear();
+        }
+
+        fn applied(&mut self, node_id: &str) -> Vec<u64> {
+            self.node(node_id).state_machine.0.clone()
+        }
+
+        fn log_terms(&mut self, node_id: &str) -> Vec<u64> {
+            self.node(node_id)
+                .log
+                .iter()
+                .skip(1)
+                .map(|entry| entry.term)
+                .collect()
+        }
+    }
+
+    fn vote_reply(raft: &mut Raft<Log>) -> (u64, bool) {
+        match raft.take_rpcs().pop() {
+            Some((_, RaftRpc::RequestVoteOk { term, vote_granted })) => (term, vote_granted),
+            other => panic!("expected a vote, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replicates_and_applies_commands_on_every_node() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        for command in 1..=5 {
+            net.node("n1").propose(command).expect("n1 leads");
+            net.deliver();
+        }
+        assert_eq!(net.node("n1").commit_index(), 6);
+        // followers learn the commit index with the next heartbeat
+        net.tick("n1");
+        for node in ["n1", "n2", "n3"] {
+            assert_eq!(net.applied(node), vec![1, 2, 3, 4, 5], "{}", node);
+            assert_eq!(net.node(node).leader(), Some("n1"));
+        }
+
+        let err = net.node("n2").propose(6).expect_err("n2 follows");
+        assert_eq!(err.leader.as_deref(), Some("n1"));
+    }
+
+    #[test]
+    fn votes_once_per_term_for_candidates_at_least_as_up_to_date() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        net.node("n1").propose(1).expect("n1 leads");
+        net.deliver();
+        let n2 = net.node("n2");
+        assert_eq!((n2.last_index(), n2.last_term()), (2, 1));
+
+        // a log with a lower last term is behind, however long it is
+        let request = |term, last_log_index, last_log_term| RaftRpc::RequestVote {
+            term,
+            last_log_index,
+            last_log_term,
+        };
+        n2.handle("n3", request(2, 10, 0));
+        assert_eq!(vote_reply(n2), (2, false));
+        // and so is a shorter one with the same last term
+        n2.handle("n3", request(3, 1, 1));
+        assert_eq!(vote_reply(n2), (3, false));
+
+        n2.handle("n3", request(4, 2, 1));
+        assert_eq!(vote_reply(n2), (4, true));
+        n2.handle("n1", request(4, 5, 1));
+        assert_eq!(vote_reply(n2), (4, false), "voted twice in a term");
+        // asking again is fine, in case the first answer was lost
+        n2.handle("n3", request(4, 2, 1));
+        assert_eq!(vote_reply(n2), (4, true));
+        // a candidate from an old term gets nothing
+        n2.handle("n1", request(3, 5, 1));
+        assert_eq!(vote_reply(n2), (4, false));
+    }
+
+    #[test]
+    fn a_candidate_with_a_stale_log_loses() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        net.isolate("n3");
+        net.node("n1").propose(1).expect("n1 leads");
+        net.deliver();
+        net.heal();
+
+        net.tick("n3");
+        assert!(!net.node("n3").is_leader());
+        // its higher term made the leader step down; n2 wins the next term
+        net.elect("n2");
+        net.node("n2").propose(2).expect("n2 leads");
+        net.deliver();
+        net.tick("n2");
+        assert_eq!(net.applied("n3"), vec![1, 2]);
+    }
+
+    #[test]
+    fn a_deposed_leader_truncates_what_it_wrote_alone() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        net.isolate("n1");
+        net.node("n1").propose(1).expect("n1 still thinks it leads");
+        net.node("n1").propose(2).expect("n1 still thinks it leads");
+        net.deliver();
+        assert_eq!(net.log_terms("n1"), vec![1, 1, 1]);
+
+        net.elect("n2");
+        net.node("n2").propose(3).expect("n2 leads");
+        net.deliver();
+        assert_eq!(net.applied("n2"), vec![3]);
+
+        net.heal();
+        net.tick("n2");
+        assert!(!net.node("n1").is_leader());
+        assert_eq!(net.log_terms("n1"), vec![1, 2, 2]);
+        assert_eq!(net.applied("n1"), vec![3]);
+        assert_eq!(net.log_terms("n1"), net.log_terms("n3"));
+    }
+
+    #[test]
+    fn only_entries_of_the_current_term_are_committed_by_counting() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        // n1 and n2 get 1, but n1 never hears that n2 has it
+        net.dropped.insert(("n1".to_string(), "n3".to_string()));
+        net.dropped.insert(("n2".to_string(), "n1".to_string()));
+        net.node("n1").propose(1).expect("n1 leads");
+        net.deliver();
+        assert_eq!(net.node("n1").commit_index(), 1);
+        net.heal();
+
+        // n2 takes over without its first entry reaching anyone
+        net.isolate("n2");
+        net.node("n2").start_election();
+        for peer in ["n1", "n3"] {
+            let rpc = RaftRpc::RequestVoteOk {
+                term: 2,
+                vote_granted: true,
+            };
+            net.node("n2").handle(peer, rpc);
+        }
+        assert!(net.node("n2").is_leader());
+        assert_eq!(net.log_terms("n2"), vec![1, 1, 2]);
+        net.node("n2").take_rpcs();
+
+        // a majority holds entry 2, but it is from term 1
+        let ack = RaftRpc::AppendEntriesOk {
+            term: 2,
+            success: true,
+            match_index: 2,
+            round: 1,
+        };
+        net.node("n2").handle("n1", ack);
+        assert_eq!(net.node("n2").commit_index(), 1);
+        assert!(net.applied("n2").is_empty());
+
+        // once the term's own entry is on a majority, both commit
+        net.heal();
+        net.node("n2").propose(2).expect("n2 leads");
+        net.deliver();
+        assert_eq!(net.node("n2").commit_index(), 4);
+        assert_eq!(net.applied("n2"), vec![1, 2]);
+    }
+
+    #[test]
+    fn a_follower_never_moves_its_commit_index_back() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        for command in 1..=3 {
+            net.node("n1").propose(command).expect("n1 leads");
+        }
+        net.deliver();
+        net.tick("n1");
+        assert_eq!(net.node("n2").commit_index(), 4);
+
+        // a request backed up to the start of the log, with more committed
+        // since
+        let rpc = RaftRpc::AppendEntries {
+            term: 1,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: Vec::new(),
+            leader_commit: 5,
+            round: 1,
+        };
+        net.node("n2").handle("n1", rpc);
+        assert_eq!(net.node("n2").commit_index(), 4);
+    }
+
+    #[test]
+    fn reads_wait_for_a_majority_to_confirm_the_leader() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        net.node("n1").take_events();
+
+        let id = net.node("n1").read_index().expect("n1 leads");
+        assert!(net.node("n1").take_events().is_empty());
+        // one follower answering makes a majority with the leader
+        net.dropped.insert(("n1".to_string(), "n3".to_string()));
+        net.deliver();
+        let events = net.node("n1").take_events();
+        assert!(
+            matches!(events[..], [Event::ReadReady { id: ready }] if ready == id),
+            "unexpected events: {:?}",
+            events
+        );
+
+        assert!(net.node("n2").read_index().is_err());
+    }
+
+    #[test]
+    fn reads_fail_when_the_leader_is_deposed() {
+        let mut net = Network::new(3);
+        net.elect("n1");
+        net.isolate("n1");
+        net.node("n1").take_events();
+        let id = net
+            .node("n1")
+            .read_index()
+            .expect("n1 still thinks it leads");
+        net.deliver();
+        assert!(net.node("n1").take_events().is_empty());
+
+        net.elect("n2");
+        net.heal();
+        net.tick("n2");
+        let events = net.node("n1").take_events();
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, Event::ReadFailed { id: failed } if *failed == id)),
+            "unexpected events: {:?}",
+            events
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::ReadReady { .. })));
+    }
+}
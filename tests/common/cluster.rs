@@ -0,0 +1,222 @@
+//! Several nodes wired together: messages between nodes are delivered
+//! directly, unless a partition drops them, and replies to clients come
+//! back to the test.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use super::REPLY_TIMEOUT;
+
+type Stdins = Arc<Mutex<HashMap<String, ChildStdin>>>;
+type Blocked = Arc<Mutex<HashSet<(String, String)>>>;
+
+pub struct Cluster {
+    pub node_ids: Vec<String>,
+    children: Vec<Child>,
+    stdins: Stdins,
+    blocked: Blocked,
+    // messages the nodes sent to clients
+    output: Receiver<Value>,
+    // client replies that arrived while waiting for another one
+    unclaimed: Vec<Value>,
+    msg_id: u64,
+}
+
+fn deliver(stdins: &Stdins, dest: &str, message: &Value) {
+    if let Some(stdin) = stdins.lock().expect("stdins lock").get_mut(dest) {
+        // a node that was stopped just misses the message
+        let _ = writeln!(stdin, "{}", message).and_then(|_| stdin.flush());
+    }
+}
+
+impl Cluster {
+    /// Start `count` nodes named `n1`, `n2`, ... running `binary`, and
+    /// initialize them.
+    pub fn start(binary: &str, count: usize, envs: &[(&str, &str)]) -> Self {
+        let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+        let stdins: Stdins = Arc::default();
+        let blocked: Blocked = Arc::default();
+        let (tx, output) = mpsc::channel();
+
+        let mut children = Vec::new();
+        for node_id in &node_ids {
+            let mut child = Command::new(binary)
+                .envs(envs.iter().copied())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap_or_else(|e| panic!("spawn {}: {}", binary, e));
+            let stdin = child.stdin.take().expect("stdin is piped");
+            let stdout = child.stdout.take().expect("stdout is piped");
+            stdins
+                .lock()
+                .expect("stdins lock")
+                .insert(node_id.clone(), stdin);
+
+            let (stdins, blocked, tx) = (stdins.clone(), blocked.clone(), tx.clone());
+            let node_ids = node_ids.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    let message: Value =
+                        serde_json::from_str(&line).expect("node wrote invalid JSON");
+                    let src = message["src"].as_str().unwrap_or_default().to_string();
+                    let dest = message["dest"].as_str().unwrap_or_default().to_string();
+                    if !node_ids.contains(&dest) {
+                        if tx.send(message).is_err() {
+                            break;
+                        }
+                    } else if !blocked
+                        .lock()
+                        .expect("blocked lock")
+                        .contains(&(src, dest.clone()))
+                    {
+                        deliver(&stdins, &dest, &message);
+                    }
+                }
+            });
+            children.push(child);
+        }
+
+        let mut cluster = Cluster {
+            node_ids,
+            children,
+            stdins,
+            blocked,
+            output,
+            unclaimed: Vec::new(),
+            msg_id: 0,
+        };
+        let node_ids = cluster.node_ids.clone();
+        for node_id in &node_ids {
+            let body = json!({"type": "init", "node_id": node_id, "node_ids": node_ids});
+            let reply = cluster.call(node_id, body);
+            assert_eq!(reply["type"], "init_ok", "unexpected init reply: {}", reply);
+        }
+        cluster
+    }
+
+    /// Send `body` from client `c1` to `node_id` and return its msg_id.
+    pub fn send(&mut self, node_id: &str, mut body: Value) -> u64 {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let message = json!({"src": "c1", "dest": node_id, "body": body});
+        deliver(&self.stdins, node_id, &message);
+        self.msg_id
+    }
+
+    /// Wait for the body of the reply to `msg_id`.
+    pub fn reply(&mut self, msg_id: u64) -> Value {
+        let matches = |message: &Value| message["body"]["in_reply_to"] == json!(msg_id);
+        if let Some(i) = self.unclaimed.iter().position(matches) {
+            return self.unclaimed.remove(i)["body"].clone();
+        }
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let message = self
+                .output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or_else(|_| panic!("no reply to msg {}", msg_id));
+            if matches(&message) {
+                return message["body"].clone();
+            }
+            self.unclaimed.push(message);
+        }
+    }
+
+    /// Wait for the next message a node sends to a client that `matches`,
+    /// keeping the ones before it for `reply`.
+    pub fn receive(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        if let Some(i) = self.unclaimed.iter().position(&matches) {
+            return self.unclaimed.remove(i);
+        }
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let message = self
+                .output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or_else(|_| panic!("no expected message"));
+            if matches(&message) {
+                return message;
+            }
+            self.unclaimed.push(message);
+        }
+    }
+
+    /// Send `body` to `node_id` and wait for the body of the reply.
+    pub fn call(&mut self, node_id: &str, body: Value) -> Value {
+        let msg_id = self.send(node_id, body);
+        self.reply(msg_id)
+    }
+
+    /// Call every node with `body` until `check` holds for all the replies,
+    /// for at most `timeout`; returns the last replies.
+    pub fn wait_for(
+        &mut self,
+        body: Value,
+        timeout: Duration,
+        check: impl Fn(&[Value]) -> bool,
+    ) -> Vec<Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let node_ids = self.node_ids.clone();
+            let replies: Vec<Value> = node_ids
+                .iter()
+                .map(|node_id| self.call(node_id, body.clone()))
+                .collect();
+            if check(&replies) || Instant::now() >= deadline {
+                return replies;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Drop every message between the nodes in `side` and the rest.
+    pub fn partition(&mut self, side: &[&str]) {
+        let mut blocked = self.blocked.lock().expect("blocked lock");
+        for a in side {
+            for b in self.node_ids.iter().filter(|b| !side.contains(&b.as_str())) {
+                blocked.insert((a.to_string(), b.clone()));
+                blocked.insert((b.clone(), a.to_string()));
+            }
+        }
+    }
+
+    /// Drop every message `src` sends to `dest`, but not the other way.
+    pub fn block(&mut self, src: &str, dest: &str) {
+        let mut blocked = self.blocked.lock().expect("blocked lock");
+        blocked.insert((src.to_string(), dest.to_string()));
+    }
+
+    pub fn heal(&mut self) {
+        self.blocked.lock().expect("blocked lock").clear();
+    }
+
+    /// Stop `node_id` for good; messages sent to it from now on are lost.
+    pub fn kill(&mut self, node_id: &str) {
+        self.stdins.lock().expect("stdins lock").remove(node_id);
+        if let Some(i) = self.node_ids.iter().position(|id| id == node_id) {
+            let _ = self.children[i].kill();
+            let _ = self.children[i].wait();
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
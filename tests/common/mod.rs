@@ -0,0 +1,140 @@
+//! Runs node binaries as child processes and talks to them over
+//! stdin/stdout the way Maelstrom does.
+
+// each test crate uses only part of the harness
+#![allow(dead_code)]
+
+pub mod cluster;
+pub mod set;
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+/// How long `call` waits for a reply.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for gossip to spread. Nodes gossip every five seconds,
+/// so allow for a few rounds.
+pub const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct TestNode {
+    pub node_id: String,
+    child: Child,
+    stdin: ChildStdin,
+    output: Receiver<Value>,
+    msg_id: u64,
+}
+
+impl TestNode {
+    /// Start `binary` (a path from `env!("CARGO_BIN_EXE_<name>")`) with the
+    /// extra environment variables in `envs`.
+    pub fn spawn(binary: &str, node_id: &str, envs: &[(&str, &str)]) -> Self {
+        let mut child = Command::new(binary)
+            .envs(envs.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("spawn {}: {}", binary, e));
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let (tx, output) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let message: Value = serde_json::from_str(&line).expect("node wrote invalid JSON");
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        TestNode {
+            node_id: node_id.to_string(),
+            child,
+            stdin,
+            output,
+            msg_id: 0,
+        }
+    }
+
+    /// Send the Maelstrom `init` message and wait for `init_ok`.
+    pub fn init(&mut self, node_ids: &[&str]) {
+        let body = json!({"type": "init", "node_id": self.node_id, "node_ids": node_ids});
+        let reply = self.call(body);
+        assert_eq!(reply["type"], "init_ok", "unexpected init reply: {}", reply);
+    }
+
+    /// Send `body` from client `c1` and return its msg_id.
+    pub fn send(&mut self, body: Value) -> u64 {
+        self.send_from("c1", body)
+    }
+
+    /// Send `body` as `src`, which lets a test stand in for another node.
+    pub fn send_from(&mut self, src: &str, mut body: Value) -> u64 {
+        self.msg_id += 1;
+        body["msg_id"] = json!(self.msg_id);
+        let message = json!({"src": src, "dest": self.node_id, "body": body});
+        writeln!(self.stdin, "{}", message).expect("write to node");
+        self.stdin.flush().expect("flush node stdin");
+        self.msg_id
+    }
+
+    /// Send `body` and wait for the body of the reply to it.
+    pub fn call(&mut self, body: Value) -> Value {
+        let msg_id = self.send(body);
+        self.receive(|message| message["body"]["in_reply_to"] == json!(msg_id))["body"].clone()
+    }
+
+    /// Wait for the next message the node sends that `matches`, skipping
+    /// the ones before it.
+    pub fn receive(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        self.receive_within(REPLY_TIMEOUT, matches)
+            .unwrap_or_else(|| panic!("{} sent no expected message", self.node_id))
+    }
+
+    /// Like `receive`, but gives up after `timeout` and returns `None`.
+    pub fn receive_within(
+        &mut self,
+        timeout: Duration,
+        matches: impl Fn(&Value) -> bool,
+    ) -> Option<Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let message = self
+                .output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok()?;
+            if matches(&message) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Stop the node the way a crash would, without letting it clean up.
+    pub fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// A fresh, empty directory for one test.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("malen-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("create test directory");
+    dir
+}
@@ -0,0 +1,57 @@
+//! Requests and convergence checks shared by the set workloads.
+
+use serde_json::{json, Value};
+
+use super::{cluster::Cluster, CONVERGENCE_TIMEOUT};
+
+pub fn add(cluster: &mut Cluster, node: &str, element: u64) {
+    let reply = cluster.call(node, json!({"type": "add", "element": element}));
+    assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+}
+
+pub fn remove(cluster: &mut Cluster, node: &str, element: u64) {
+    let reply = cluster.call(node, json!({"type": "remove", "element": element}));
+    assert_eq!(reply["type"], "remove_ok", "unexpected reply: {}", reply);
+}
+
+/// The elements of a `read_ok`, in order.
+pub fn sorted(reply: &Value) -> Vec<u64> {
+    let mut value: Vec<u64> = reply["value"]
+        .as_array()
+        .expect("read_ok has a value")
+        .iter()
+        .map(|element| element.as_u64().expect("elements are integers"))
+        .collect();
+    value.sort();
+    value
+}
+
+/// Read every node until they all agree, and return what they agree on.
+pub fn converge(cluster: &mut Cluster) -> Vec<u64> {
+    let replies = cluster.wait_for(json!({"type": "read"}), CONVERGENCE_TIMEOUT, |replies| {
+        replies
+            .windows(2)
+            .all(|pair| sorted(&pair[0]) == sorted(&pair[1]))
+    });
+    let values: Vec<Vec<u64>> = replies.iter().map(sorted).collect();
+    assert!(
+        values.windows(2).all(|pair| pair[0] == pair[1]),
+        "nodes did not converge: {:?}",
+        values
+    );
+    values[0].clone()
+}
+
+/// Read every node until they all hold `expected`.
+pub fn converge_to(cluster: &mut Cluster, expected: &[u64]) {
+    let replies = cluster.wait_for(json!({"type": "read"}), CONVERGENCE_TIMEOUT, |replies| {
+        replies.iter().all(|reply| sorted(reply) == expected)
+    });
+    let values: Vec<Vec<u64>> = replies.iter().map(sorted).collect();
+    assert!(
+        values.iter().all(|value| value == expected),
+        "nodes did not converge to {:?}: {:?}",
+        expected,
+        values
+    );
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{cluster::Cluster, TestNode, CONVERGENCE_TIMEOUT};
+use serde_json::{json, Value};
+
+const COUNTER: &str = env!("CARGO_BIN_EXE_counter");
+
+/// The same adds, spread over two nodes: (node, key, delta).
+const WORKLOAD: &[(&str, &str, i64)] = &[
+    ("n1", "a", 5),
+    ("n2", "b", -3),
+    ("n2", "a", 7),
+    ("n1", "a", -2),
+    ("n1", "b", 10),
+    ("n2", "default", 4),
+];
+
+/// The test stands in for Maelstrom's seq-kv service, keeping its values
+/// here; serving every request from the latest state is one valid seq-kv.
+#[derive(Default)]
+struct FakeKv {
+    values: HashMap<String, Value>,
+}
+
+impl FakeKv {
+    fn answer(&mut self, body: &Value) -> Value {
+        let key = body["key"].as_str().expect("key").to_string();
+        let missing = json!({"type": "error", "code": 20, "text": "not found"});
+        let mut reply = match body["type"].as_str() {
+            Some("read") => match self.values.get(&key) {
+                Some(value) => json!({"type": "read_ok", "value": value}),
+                None => missing,
+            },
+            Some("write") => {
+                self.values.insert(key, body["value"].clone());
+                json!({"type": "write_ok"})
+            }
+            Some("cas") => match self.values.get(&key) {
+                Some(value) if *value != body["from"] => {
+                    json!({"type": "error", "code": 22, "text": "expected a different value"})
+                }
+                None if body["create_if_not_exists"] != true => missing,
+                _ => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({"type": "cas_ok"})
+                }
+            },
+            other => panic!("unexpected seq-kv request {:?}", other),
+        };
+        reply["in_reply_to"] = body["msg_id"].clone();
+        reply
+    }
+}
+
+fn to_kv(message: &Value) -> bool {
+    message["dest"] == "seq-kv"
+}
+
+fn start_seq_kv(node_id: &str) -> TestNode {
+    let mut node = TestNode::spawn(COUNTER, node_id, &[("MALEN_COUNTER_BACKEND", "seq-kv")]);
+    node.init(&["n1", "n2"]);
+    node
+}
+
+/// Send `body` to `node` and answer seq-kv for it until it replies.
+fn call(node: &mut TestNode, kv: &mut FakeKv, body: Value) -> Value {
+    let msg_id = node.send(body);
+    loop {
+        let message =
+            node.receive(|message| to_kv(message) || message["body"]["in_reply_to"] == msg_id);
+        if !to_kv(&message) {
+            return message["body"].clone();
+        }
+        let reply = kv.answer(&message["body"]);
+        node.send_from("seq-kv", reply);
+    }
+}
+
+fn add(key: &str, delta: i64) -> Value {
+    json!({"type": "add", "key": key, "delta": delta})
+}
+
+fn read(key: &str) -> Value {
+    json!({"type": "read", "key": key})
+}
+
+#[test]
+fn both_backends_count_the_same() {
+    let keys = ["a", "b", "default"];
+
+    let mut cluster = Cluster::start(COUNTER, 2, &[]);
+    for (node, key, delta) in WORKLOAD {
+        let reply = cluster.call(node, add(key, *delta));
+        assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+    }
+    let mut gossiped = HashMap::new();
+    for key in keys {
+        let replies = cluster.wait_for(read(key), CONVERGENCE_TIMEOUT, |replies| {
+            replies
+                .windows(2)
+                .all(|pair| pair[0]["value"] == pair[1]["value"])
+        });
+        gossiped.insert(key, replies[0]["value"].clone());
+    }
+
+    let mut kv = FakeKv::default();
+    let mut nodes = HashMap::from([("n1", start_seq_kv("n1")), ("n2", start_seq_kv("n2"))]);
+    for (node, key, delta) in WORKLOAD {
+        let node = nodes.get_mut(node).expect("known node");
+        let reply = call(node, &mut kv, add(key, *delta));
+        assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+    }
+    for key in keys {
+        for node in nodes.values_mut() {
+            let reply = call(node, &mut kv, read(key));
+            assert_eq!(reply["value"], gossiped[key], "{} on {}", key, node.node_id);
+        }
+    }
+    assert_eq!(
+        gossiped,
+        HashMap::from([("a", json!(10)), ("b", json!(7)), ("default", json!(4))])
+    );
+}
+
+#[test]
+fn an_unexpected_seq_kv_reply_fails_only_its_request() {
+    let mut node = start_seq_kv("n1");
+    let mut kv = FakeKv::default();
+
+    let msg_id = node.send(add("a", 1));
+    let request = node.receive(to_kv);
+    // a write_ok makes no sense for the read an add starts with
+    node.send_from(
+        "seq-kv",
+        json!({"type": "write_ok", "in_reply_to": request["body"]["msg_id"]}),
+    );
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == msg_id);
+    assert_eq!(
+        reply["body"]["type"], "error",
+        "unexpected reply: {}",
+        reply
+    );
+    assert_eq!(reply["body"]["code"], 13);
+
+    // the node is still up
+    let reply = call(&mut node, &mut kv, add("a", 2));
+    assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+    assert_eq!(call(&mut node, &mut kv, read("a"))["value"], 2);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use common::{cluster::Cluster, CONVERGENCE_TIMEOUT};
+use serde_json::{json, Value};
+
+const COUNTER: &str = env!("CARGO_BIN_EXE_counter");
+
+fn add(cluster: &mut Cluster, node: &str, key: &str, delta: i64) -> Value {
+    cluster.call(node, json!({"type": "add", "key": key, "delta": delta}))
+}
+
+fn assert_added(reply: &Value) {
+    assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+}
+
+#[test]
+fn keys_count_and_bound_independently() {
+    let mut cluster = Cluster::start(COUNTER, 2, &[("MALEN_COUNTER_BOUNDED", "true")]);
+    assert_added(&add(&mut cluster, "n1", "tenant/a", 10));
+    assert_added(&add(&mut cluster, "n2", "tenant/b", 5));
+    assert_added(&add(&mut cluster, "n1", "other", 3));
+
+    let tenants = json!({"tenant/a": 10, "tenant/b": 5});
+    let replies = cluster.wait_for(
+        json!({"type": "read_all", "prefix": "tenant/"}),
+        CONVERGENCE_TIMEOUT,
+        |replies| replies.iter().all(|reply| reply["values"] == tenants),
+    );
+    for reply in &replies {
+        assert_eq!(reply["values"], tenants, "unexpected reply: {}", reply);
+    }
+    let reply = cluster.call("n2", json!({"type": "read_all", "prefix": ""}));
+    assert_eq!(
+        reply["values"],
+        json!({"tenant/a": 10, "tenant/b": 5, "other": 3})
+    );
+
+    // n2 holds the rights to its own increments of tenant/b, and only those:
+    // n1's rights on tenant/a and other do not count towards tenant/b
+    assert_added(&add(&mut cluster, "n2", "tenant/b", -5));
+    let reply = add(&mut cluster, "n2", "tenant/b", -1);
+    assert_eq!(reply["type"], "error", "unexpected reply: {}", reply);
+    assert_eq!(reply["code"], 22);
+
+    // n1 gave none of its tenant/a rights away above, so it can spend them
+    // all on tenant/a, and none are left for n2
+    assert_added(&add(&mut cluster, "n1", "tenant/a", -10));
+    let reply = add(&mut cluster, "n2", "tenant/a", -1);
+    assert_eq!(reply["type"], "error", "unexpected reply: {}", reply);
+    // while other still has its 3
+    assert_added(&add(&mut cluster, "n2", "other", -3));
+
+    let expected = json!({"tenant/a": 0, "tenant/b": 0, "other": 0});
+    let replies = cluster.wait_for(
+        json!({"type": "read_all", "prefix": ""}),
+        CONVERGENCE_TIMEOUT,
+        |replies| replies.iter().all(|reply| reply["values"] == expected),
+    );
+    for reply in &replies {
+        assert_eq!(reply["values"], expected, "unexpected reply: {}", reply);
+    }
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const COUNTER: &str = env!("CARGO_BIN_EXE_counter");
+
+/// Error code the counter replies with when a result leaves the i64 range.
+const OVERFLOW: u64 = 1000;
+
+fn start(envs: &[(&str, &str)]) -> TestNode {
+    let mut node = TestNode::spawn(COUNTER, "n1", envs);
+    node.init(&["n1", "n2"]);
+    node
+}
+
+fn add(node: &mut TestNode, delta: i64) -> Value {
+    node.call(json!({"type": "add", "delta": delta}))
+}
+
+fn read(node: &mut TestNode) -> Value {
+    node.call(json!({"type": "read"}))
+}
+
+fn assert_overflow(reply: &Value) {
+    assert_eq!(reply["type"], "error", "unexpected reply: {}", reply);
+    assert_eq!(reply["code"], OVERFLOW, "unexpected reply: {}", reply);
+}
+
+// the test stands in for n2, which counted `inc` and `dec` so far
+fn gossip_from_n2(node: &mut TestNode, inc: i64, dec: i64) {
+    node.send_from(
+        "n2",
+        json!({
+            "type": "gossip",
+            "inc_messages": {"default": {"n2": inc}},
+            "dec_messages": {"default": {"n2": dec}},
+        }),
+    );
+    node.receive(|message| message["dest"] == "n2" && message["body"]["type"] == "gossip_ok");
+}
+
+#[test]
+fn rejects_adds_that_overflow_and_leaves_the_value_alone() {
+    let mut node = start(&[]);
+    assert_eq!(add(&mut node, i64::MAX)["type"], "add_ok");
+    assert_overflow(&add(&mut node, 1));
+    assert_overflow(&add(&mut node, i64::MIN));
+    assert_eq!(read(&mut node)["value"], i64::MAX);
+
+    // n2's decrements bring the value back down, but this node's own
+    // increments still cannot grow
+    gossip_from_n2(&mut node, 0, 10);
+    assert_eq!(read(&mut node)["value"], i64::MAX - 10);
+    assert_overflow(&add(&mut node, 1));
+    assert_eq!(add(&mut node, -5)["type"], "add_ok");
+    assert_eq!(read(&mut node)["value"], i64::MAX - 15);
+}
+
+#[test]
+fn refuses_sums_past_i64_without_i128_reads() {
+    let mut node = start(&[]);
+    assert_eq!(add(&mut node, i64::MAX)["type"], "add_ok");
+    gossip_from_n2(&mut node, i64::MAX, 0);
+    assert_overflow(&read(&mut node));
+    let reply = node.call(json!({"type": "read_all"}));
+    assert_overflow(&reply);
+}
+
+#[test]
+fn reads_exact_sums_past_i64_with_i128_reads() {
+    let mut node = start(&[("MALEN_COUNTER_I128_READS", "true")]);
+    assert_eq!(add(&mut node, i64::MAX)["type"], "add_ok");
+    gossip_from_n2(&mut node, i64::MAX, 0);
+
+    let sum = 2 * i128::from(i64::MAX);
+    let reply = read(&mut node);
+    assert_eq!(reply["value"].to_string(), sum.to_string());
+    // adds may take the value past i64 too, as long as each node's own
+    // count stays in range
+    assert_eq!(add(&mut node, -1)["type"], "add_ok");
+    let reply = node.call(json!({"type": "read_all"}));
+    assert_eq!(
+        reply["values"]["default"].to_string(),
+        (sum - 1).to_string()
+    );
+
+    // each node's own count still has to fit in an i64
+    assert_overflow(&add(&mut node, 1));
+}
+
+#[test]
+fn reads_exact_sums_below_i64_with_i128_reads() {
+    let mut node = start(&[("MALEN_COUNTER_I128_READS", "true")]);
+    assert_eq!(add(&mut node, -i64::MAX)["type"], "add_ok");
+    gossip_from_n2(&mut node, 0, i64::MAX);
+    assert_overflow(&add(&mut node, -1));
+    // serde_json reads integers below i64::MIN back as floats, so this can
+    // only check the sum to f64 precision
+    let expected = -2.0 * i64::MAX as f64;
+    assert_eq!(read(&mut node)["value"].as_f64(), Some(expected));
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const GENERATE: &str = env!("CARGO_BIN_EXE_generate");
+
+/// The test stands in for Maelstrom's lin-kv service, keeping its values
+/// here.
+#[derive(Default)]
+struct FakeKv {
+    values: HashMap<String, Value>,
+}
+
+impl FakeKv {
+    fn answer(&mut self, body: &Value) -> Value {
+        let key = body["key"].as_str().expect("key").to_string();
+        let missing = json!({"type": "error", "code": 20, "text": "not found"});
+        let mut reply = match body["type"].as_str() {
+            Some("read") => match self.values.get(&key) {
+                Some(value) => json!({"type": "read_ok", "value": value}),
+                None => missing,
+            },
+            Some("cas") => match self.values.get(&key) {
+                Some(value) if *value != body["from"] => {
+                    json!({"type": "error", "code": 22, "text": "expected a different value"})
+                }
+                None if body["create_if_not_exists"] != true => missing,
+                _ => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({"type": "cas_ok"})
+                }
+            },
+            other => panic!("unexpected lin-kv request {:?}", other),
+        };
+        reply["in_reply_to"] = body["msg_id"].clone();
+        reply
+    }
+}
+
+fn to_kv(message: &Value) -> bool {
+    message["dest"] == "lin-kv"
+}
+
+fn start(node_id: &str, envs: &[(&str, &str)]) -> TestNode {
+    let mut all = vec![("MALEN_GENERATE_IDS", "leased")];
+    all.extend_from_slice(envs);
+    let mut node = TestNode::spawn(GENERATE, node_id, &all);
+    node.init(&["n1", "n2"]);
+    node
+}
+
+/// Ask `node` for an ID, answering lin-kv for it until it replies.
+fn generate(node: &mut TestNode, kv: &mut FakeKv) -> u64 {
+    let msg_id = node.send(json!({"type": "generate"}));
+    loop {
+        let message =
+            node.receive(|message| to_kv(message) || message["body"]["in_reply_to"] == msg_id);
+        if !to_kv(&message) {
+            let body = &message["body"];
+            assert_eq!(body["type"], "generate_ok", "unexpected reply: {}", body);
+            return body["id"].as_u64().expect("id is a number");
+        }
+        let reply = kv.answer(&message["body"]);
+        node.send_from("lin-kv", reply);
+    }
+}
+
+#[test]
+fn hands_out_dense_ids_from_its_leases() {
+    let mut node = start("n1", &[("MALEN_GENERATE_BLOCK_SIZE", "10")]);
+    let mut kv = FakeKv::default();
+    let ids: Vec<u64> = (0..25).map(|_| generate(&mut node, &mut kv)).collect();
+    assert_eq!(ids, (1..=25).collect::<Vec<_>>());
+}
+
+#[test]
+fn node_order_only_orders_the_ids_of_each_node() {
+    let mut n1 = start("n1", &[("MALEN_GENERATE_BLOCK_SIZE", "10")]);
+    let mut n2 = start("n2", &[("MALEN_GENERATE_BLOCK_SIZE", "10")]);
+    let mut kv = FakeKv::default();
+    assert_eq!(generate(&mut n1, &mut kv), 1);
+    assert_eq!(generate(&mut n2, &mut kv), 11);
+    // n1 still serves from the lease it took before n2's
+    assert_eq!(generate(&mut n1, &mut kv), 2);
+}
+
+#[test]
+fn global_order_follows_the_order_requests_were_answered_in() {
+    let envs = [("MALEN_GENERATE_ORDER", "global")];
+    let mut n1 = start("n1", &envs);
+    let mut n2 = start("n2", &envs);
+    let mut kv = FakeKv::default();
+    let ids: Vec<u64> = (0..20)
+        .map(|i| {
+            let node = if i % 3 == 0 { &mut n2 } else { &mut n1 };
+            generate(node, &mut kv)
+        })
+        .collect();
+    assert_eq!(ids, (1..=20).collect::<Vec<_>>());
+}
+
+#[test]
+fn global_order_leases_again_for_requests_that_arrive_during_a_cas() {
+    let mut node = start("n1", &[("MALEN_GENERATE_ORDER", "global")]);
+    let mut kv = FakeKv::default();
+
+    let first = node.send(json!({"type": "generate"}));
+    let cas = node.receive(to_kv);
+    assert_eq!(cas["body"]["to"], 2, "leased more than one ID: {}", cas);
+    let second = node.send(json!({"type": "generate"}));
+    node.send_from("lin-kv", kv.answer(&cas["body"]));
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == first);
+    assert_eq!(reply["body"]["id"], 1);
+
+    let cas = node.receive(to_kv);
+    assert_eq!(
+        (&cas["body"]["from"], &cas["body"]["to"]),
+        (&json!(2), &json!(3))
+    );
+    node.send_from("lin-kv", kv.answer(&cas["body"]));
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == second);
+    assert_eq!(reply["body"]["id"], 2);
+}
+
+#[test]
+fn fails_requests_the_kv_service_never_answers_for() {
+    let mut node = start("n1", &[("MALEN_GENERATE_TIMEOUT_MS", "300")]);
+    let msg_id = node.send(json!({"type": "generate"}));
+    // lin-kv is down: its requests are dropped
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == msg_id);
+    assert_eq!(
+        reply["body"]["type"], "error",
+        "unexpected reply: {}",
+        reply
+    );
+    assert_eq!(reply["body"]["code"], 0);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::collections::HashSet;
+
+use common::{temp_dir, TestNode};
+use serde_json::json;
+
+const GENERATE: &str = env!("CARGO_BIN_EXE_generate");
+
+fn generate(node: &mut TestNode, count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let reply = node.call(json!({"type": "generate"}));
+            assert_eq!(reply["type"], "generate_ok", "unexpected reply: {}", reply);
+            reply["id"].as_str().expect("id is a string").to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn block_ids_stay_unique_across_crashes() {
+    let dir = temp_dir("generate-restart");
+    let data_dir = dir.to_str().expect("temp dir is valid UTF-8");
+    let envs = [
+        ("MALEN_GENERATE_IDS", "block"),
+        ("MALEN_GENERATE_DATA_DIR", data_dir),
+        ("MALEN_GENERATE_BLOCK_SIZE", "10"),
+    ];
+
+    let mut seen = HashSet::new();
+    // crash in the middle of a block, on a block boundary and right after
+    // reserving a new one
+    for count in [25, 10, 1, 7] {
+        let mut node = TestNode::spawn(GENERATE, "n1", &envs);
+        node.init(&["n1"]);
+        for id in generate(&mut node, count) {
+            assert!(seen.insert(id.clone()), "{} was handed out twice", id);
+        }
+        node.kill();
+    }
+    assert_eq!(seen.len(), 43);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
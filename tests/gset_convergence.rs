@@ -0,0 +1,155 @@
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{
+    cluster::Cluster,
+    set::{add, converge, remove, sorted},
+    TestNode, CONVERGENCE_TIMEOUT,
+};
+use serde_json::json;
+
+const GSET: &str = env!("CARGO_BIN_EXE_gset");
+
+#[test]
+fn grow_only_converges() {
+    let mut cluster = Cluster::start(GSET, 3, &[("MALEN_GSET_MODE", "grow-only")]);
+    add(&mut cluster, "n1", 1);
+    add(&mut cluster, "n2", 2);
+    add(&mut cluster, "n3", 3);
+    add(&mut cluster, "n3", 1);
+
+    let reply = cluster.call("n1", json!({"type": "remove", "element": 1}));
+    assert_eq!(reply["type"], "error", "unexpected reply: {}", reply);
+    assert_eq!(converge(&mut cluster), vec![1, 2, 3]);
+}
+
+#[test]
+fn two_phase_removal_is_permanent() {
+    let mut cluster = Cluster::start(GSET, 3, &[("MALEN_GSET_MODE", "two-phase")]);
+    add(&mut cluster, "n1", 1);
+    add(&mut cluster, "n2", 2);
+    remove(&mut cluster, "n3", 1);
+    // a concurrent add on another node does not bring it back
+    add(&mut cluster, "n2", 1);
+    add(&mut cluster, "n3", 3);
+    assert_eq!(converge(&mut cluster), vec![2, 3]);
+
+    add(&mut cluster, "n1", 1);
+    assert_eq!(converge(&mut cluster), vec![2, 3]);
+}
+
+#[test]
+fn lww_keeps_the_latest_operation() {
+    let mut cluster = Cluster::start(GSET, 3, &[("MALEN_GSET_MODE", "lww")]);
+    let pause = || std::thread::sleep(Duration::from_millis(20));
+    add(&mut cluster, "n1", 1);
+    add(&mut cluster, "n1", 2);
+    pause();
+    // removed after the add, on a node that has not seen it yet
+    remove(&mut cluster, "n2", 1);
+    remove(&mut cluster, "n3", 3);
+    pause();
+    add(&mut cluster, "n1", 3);
+    assert_eq!(converge(&mut cluster), vec![2, 3]);
+
+    // later operations win, whichever node they were taken on
+    remove(&mut cluster, "n3", 2);
+    add(&mut cluster, "n2", 1);
+    assert_eq!(converge(&mut cluster), vec![1, 3]);
+}
+
+#[test]
+fn lww_orders_after_what_a_node_has_observed() {
+    let mut node = TestNode::spawn(GSET, "n1", &[("MALEN_GSET_MODE", "lww")]);
+    node.init(&["n1", "n2"]);
+
+    // n2's clock runs an hour ahead of n1's
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_millis() as u64;
+    let at = json!({"wall": now + 3_600_000, "logical": 0, "node": "n2"});
+    node.send_from(
+        "n2",
+        json!({
+            "type": "gossip",
+            "messages": [{"op": "stamp", "element": 7, "at": at, "removed": false}],
+        }),
+    );
+    node.receive(|message| message["dest"] == "n2" && message["body"]["type"] == "gossip_ok");
+    assert_eq!(sorted(&node.call(json!({"type": "read"}))), vec![7]);
+
+    // n1 removes the add it has seen, behind n2's clock by the wall clock
+    // but after the add by its own
+    let reply = node.call(json!({"type": "remove", "element": 7}));
+    assert_eq!(reply["type"], "remove_ok", "unexpected reply: {}", reply);
+    assert_eq!(
+        sorted(&node.call(json!({"type": "read"}))),
+        Vec::<u64>::new()
+    );
+
+    // so the removal n1 gossips back wins over the add on n2 as well
+    let gossip = node
+        .receive_within(Duration::from_secs(10), |message| {
+            message["dest"] == "n2" && message["body"]["type"] == "gossip"
+        })
+        .expect("n1 gossips to n2");
+    let stamp = gossip["body"]["messages"]
+        .as_array()
+        .expect("gossip has messages")
+        .iter()
+        .find(|update| update["element"] == 7)
+        .unwrap_or_else(|| panic!("no stamp for 7 in {}", gossip))
+        .clone();
+    assert_eq!(stamp["removed"], true, "unexpected stamp: {}", stamp);
+    let wall = stamp["at"]["wall"].as_u64().expect("wall");
+    let logical = stamp["at"]["logical"].as_u64().expect("logical");
+    assert!(
+        (wall, logical) > (now + 3_600_000, 0),
+        "the removal is stamped before the add: {}",
+        stamp
+    );
+}
+
+#[test]
+fn json_elements_compare_by_value() {
+    let envs = [
+        ("MALEN_GSET_MODE", "two-phase"),
+        ("MALEN_GSET_ELEMENTS", "json"),
+    ];
+    let mut cluster = Cluster::start(GSET, 2, &envs);
+    let elements = [
+        json!({"id": 1, "tags": ["a", "b"]}),
+        // the same element, with its keys in another order and a float id
+        json!({"tags": ["a", "b"], "id": 1.0}),
+        json!({"id": 2, "tags": ["b", "a"]}),
+        json!("1"),
+        json!(1),
+    ];
+    for (i, element) in elements.iter().enumerate() {
+        let node = if i % 2 == 0 { "n1" } else { "n2" };
+        let reply = cluster.call(node, json!({"type": "add", "element": element}));
+        assert_eq!(reply["type"], "add_ok", "unexpected reply: {}", reply);
+    }
+    let reply = cluster.call("n2", json!({"type": "remove", "element": 1.0}));
+    assert_eq!(reply["type"], "remove_ok", "unexpected reply: {}", reply);
+
+    let replies = cluster.wait_for(json!({"type": "read"}), CONVERGENCE_TIMEOUT, |replies| {
+        replies
+            .iter()
+            .all(|reply| reply["value"].as_array().map(Vec::len) == Some(3))
+    });
+    for reply in replies {
+        let value = reply["value"].as_array().expect("read_ok has a value");
+        assert_eq!(value.len(), 3, "unexpected set: {}", reply);
+        for element in [&elements[0], &elements[2], &elements[3]] {
+            assert!(
+                value.contains(element),
+                "{} is missing from {}",
+                element,
+                reply
+            );
+        }
+    }
+}
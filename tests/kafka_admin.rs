@@ -0,0 +1,107 @@
+mod common;
+
+use common::cluster::Cluster;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+fn send(cluster: &mut Cluster, key: &str, msg: u64) -> u64 {
+    let reply = cluster.call("n1", json!({"type": "send", "key": key, "msg": msg}));
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+    reply["offset"].as_u64().expect("offset")
+}
+
+fn list_keys(cluster: &mut Cluster, node: &str) -> Vec<String> {
+    let reply = cluster.call(node, json!({"type": "list_keys"}));
+    assert_eq!(reply["type"], "list_keys_ok", "unexpected reply: {}", reply);
+    let mut keys: Vec<String> = serde_json::from_value(reply["keys"].clone()).expect("keys");
+    keys.sort();
+    keys
+}
+
+fn describe(cluster: &mut Cluster, node: &str, key: &str) -> Value {
+    cluster.call(node, json!({"type": "describe_key", "key": key}))
+}
+
+/// Keys `a`, `b` and `c` with three, one and two messages, sent through n1.
+fn start() -> Cluster {
+    let mut cluster = Cluster::start(KAFKA, 2, &[]);
+    for (key, count) in [("a", 3), ("b", 1), ("c", 2)] {
+        for msg in 1..=count {
+            send(&mut cluster, key, msg);
+        }
+    }
+    cluster
+}
+
+#[test]
+fn lists_and_describes_keys_across_nodes() {
+    let mut cluster = start();
+    for node in ["n1", "n2"] {
+        assert_eq!(list_keys(&mut cluster, node), vec!["a", "b", "c"]);
+    }
+
+    let body = json!({"type": "commit_offsets", "offsets": {"a": 2}, "group": "g1"});
+    assert_eq!(cluster.call("n2", body)["type"], "commit_offsets_ok");
+    let reply = describe(&mut cluster, "n2", "a");
+    assert_eq!(
+        reply["type"], "describe_key_ok",
+        "unexpected reply: {}",
+        reply
+    );
+    assert_eq!(reply["key"], "a");
+    assert!(
+        ["n1", "n2"].contains(&reply["owner"].as_str().unwrap_or_default()),
+        "unexpected reply: {}",
+        reply
+    );
+    let stats = [
+        &reply["first_offset"],
+        &reply["last_offset"],
+        &reply["count"],
+    ];
+    assert_eq!(stats, [1, 3, 3], "unexpected reply: {}", reply);
+    assert_eq!(reply["committed"], json!({"g1": 2}));
+    assert!(
+        reply["bytes"].as_u64() > Some(0),
+        "unexpected reply: {}",
+        reply
+    );
+
+    let reply = describe(&mut cluster, "n1", "missing");
+    assert_eq!(reply["code"], 20, "unexpected reply: {}", reply);
+}
+
+#[test]
+fn a_deleted_key_is_gone_until_it_is_sent_to_again() {
+    let mut cluster = start();
+    let reply = cluster.call("n2", json!({"type": "delete_key", "key": "a"}));
+    assert_eq!(
+        reply["type"], "delete_key_ok",
+        "unexpected reply: {}",
+        reply
+    );
+    // the tombstone takes the next offset
+    assert_eq!(reply["offset"], 4);
+
+    for node in ["n1", "n2"] {
+        assert_eq!(list_keys(&mut cluster, node), vec!["b", "c"]);
+        let reply = describe(&mut cluster, node, "a");
+        assert_eq!(reply["code"], 20, "unexpected reply: {}", reply);
+        let reply = cluster.call(node, json!({"type": "poll", "offsets": {"a": 1}}));
+        assert_eq!(reply["msgs"]["a"], json!([]), "unexpected reply: {}", reply);
+    }
+    let reply = cluster.call("n1", json!({"type": "delete_key", "key": "a"}));
+    assert_eq!(reply["code"], 20, "unexpected reply: {}", reply);
+
+    // offsets keep counting up past the tombstone
+    assert_eq!(send(&mut cluster, "a", 9), 5);
+    let reply = describe(&mut cluster, "n1", "a");
+    let stats = [
+        &reply["first_offset"],
+        &reply["last_offset"],
+        &reply["count"],
+    ];
+    assert_eq!(stats, [5, 5, 1], "unexpected reply: {}", reply);
+    assert_eq!(list_keys(&mut cluster, "n2"), vec!["a", "b", "c"]);
+}
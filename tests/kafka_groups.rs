@@ -0,0 +1,86 @@
+mod common;
+
+use common::cluster::Cluster;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+/// Two nodes, with messages 1 to 5 sent to keys `a` and `b`.
+fn start() -> Cluster {
+    let mut cluster = Cluster::start(KAFKA, 2, &[]);
+    for key in ["a", "b"] {
+        for msg in 1..=5 {
+            let reply = cluster.call("n1", json!({"type": "send", "key": key, "msg": msg}));
+            assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+        }
+    }
+    cluster
+}
+
+fn commit(cluster: &mut Cluster, group: Option<&str>, offsets: Value) {
+    let mut body = json!({"type": "commit_offsets", "offsets": offsets});
+    if let Some(group) = group {
+        body["group"] = json!(group);
+    }
+    let reply = cluster.call("n2", body);
+    assert_eq!(
+        reply["type"], "commit_offsets_ok",
+        "unexpected reply: {}",
+        reply
+    );
+}
+
+fn committed(cluster: &mut Cluster, group: Option<&str>) -> Value {
+    let mut body = json!({"type": "list_committed_offsets", "keys": ["a", "b"]});
+    if let Some(group) = group {
+        body["group"] = json!(group);
+    }
+    let reply = cluster.call("n1", body);
+    assert_eq!(
+        reply["type"], "list_committed_offsets_ok",
+        "unexpected reply: {}",
+        reply
+    );
+    reply["offsets"].clone()
+}
+
+#[test]
+fn groups_keep_their_own_committed_offsets() {
+    let mut cluster = start();
+    commit(&mut cluster, Some("g1"), json!({"a": 3, "b": 1}));
+    commit(&mut cluster, Some("g2"), json!({"a": 5}));
+    commit(&mut cluster, None, json!({"b": 2}));
+    // committing for one group leaves the others alone
+    commit(&mut cluster, Some("g1"), json!({"a": 4}));
+
+    assert_eq!(committed(&mut cluster, Some("g1")), json!({"a": 4, "b": 1}));
+    // keys a group never committed are left out
+    assert_eq!(committed(&mut cluster, Some("g2")), json!({"a": 5}));
+    assert_eq!(committed(&mut cluster, None), json!({"b": 2}));
+    assert_eq!(committed(&mut cluster, Some("default")), json!({"b": 2}));
+    assert_eq!(committed(&mut cluster, Some("g3")), json!({}));
+}
+
+#[test]
+fn lag_counts_the_messages_after_the_committed_offset() {
+    let mut cluster = start();
+    commit(&mut cluster, Some("g1"), json!({"a": 3, "b": 1}));
+    commit(&mut cluster, Some("g2"), json!({"a": 5}));
+
+    let reply = cluster.call("n2", json!({"type": "describe_group", "group": "g1"}));
+    assert_eq!(reply["offsets"], json!({"a": 3, "b": 1}), "{}", reply);
+    assert_eq!(reply["lag"], json!({"a": 2, "b": 4}), "{}", reply);
+    // a key the group never committed lags by all of its messages
+    let reply = cluster.call("n2", json!({"type": "describe_group", "group": "g2"}));
+    assert_eq!(reply["offsets"], json!({"a": 5}), "{}", reply);
+    assert_eq!(reply["lag"], json!({"a": 0, "b": 5}), "{}", reply);
+
+    // listing groups covers only the keys each committed
+    let reply = cluster.call("n1", json!({"type": "list_groups"}));
+    assert_eq!(
+        reply["groups"],
+        json!({"g1": {"a": 2, "b": 4}, "g2": {"a": 0}}),
+        "unexpected reply: {}",
+        reply
+    );
+}
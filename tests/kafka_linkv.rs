@@ -0,0 +1,218 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+/// The test stands in for Maelstrom's lin-kv service, keeping its values
+/// here.
+#[derive(Default)]
+struct FakeKv {
+    values: HashMap<String, Value>,
+}
+
+impl FakeKv {
+    fn answer(&mut self, body: &Value) -> Value {
+        let key = body["key"].as_str().expect("key").to_string();
+        let missing = json!({"type": "error", "code": 20, "text": "not found"});
+        match body["type"].as_str() {
+            Some("read") => match self.values.get(&key) {
+                Some(value) => json!({"type": "read_ok", "value": value}),
+                None => missing,
+            },
+            Some("write") => {
+                self.values.insert(key, body["value"].clone());
+                json!({"type": "write_ok"})
+            }
+            Some("cas") => match self.values.get(&key) {
+                Some(value) if *value != body["from"] => {
+                    json!({"type": "error", "code": 22, "text": "expected a different value"})
+                }
+                None if body["create_if_not_exists"] != true => missing,
+                _ => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({"type": "cas_ok"})
+                }
+            },
+            other => panic!("unexpected lin-kv request {:?}", other),
+        }
+    }
+}
+
+fn start(extra_envs: &[(&str, &str)]) -> TestNode {
+    let mut envs = vec![("MALEN_KAFKA_BACKEND", "lin-kv")];
+    envs.extend_from_slice(extra_envs);
+    let mut node = TestNode::spawn(KAFKA, "n1", &envs);
+    node.init(&["n1"]);
+    node
+}
+
+/// Send `body` and answer lin-kv for the node until it replies, letting
+/// `fault` answer a lin-kv request in place of `kv`.
+fn call(
+    node: &mut TestNode,
+    kv: &mut FakeKv,
+    body: Value,
+    mut fault: impl FnMut(&Value, &mut FakeKv) -> Option<Value>,
+) -> Value {
+    let msg_id = node.send(body);
+    loop {
+        let message = node.receive(|message| {
+            message["dest"] == "lin-kv" || message["body"]["in_reply_to"] == json!(msg_id)
+        });
+        let body = &message["body"];
+        if message["dest"] != "lin-kv" {
+            return body.clone();
+        }
+        let mut reply = fault(body, kv).unwrap_or_else(|| kv.answer(body));
+        reply["in_reply_to"] = body["msg_id"].clone();
+        node.send_from("lin-kv", reply);
+    }
+}
+
+fn no_fault(_: &Value, _: &mut FakeKv) -> Option<Value> {
+    None
+}
+
+fn is_store(body: &Value) -> bool {
+    body["type"] == "cas"
+        && body["key"]
+            .as_str()
+            .is_some_and(|key| key.starts_with("msgs/"))
+}
+
+fn send(node: &mut TestNode, kv: &mut FakeKv, msg: u64) -> u64 {
+    let reply = call(
+        node,
+        kv,
+        json!({"type": "send", "key": "k", "msg": msg}),
+        no_fault,
+    );
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+    reply["offset"].as_u64().expect("offset")
+}
+
+fn poll(node: &mut TestNode, kv: &mut FakeKv) -> Value {
+    let body = json!({"type": "poll", "offsets": {"k": 1}, "max_messages_per_key": 100});
+    let reply = call(node, kv, body, no_fault);
+    assert_eq!(reply["type"], "poll_ok", "unexpected reply: {}", reply);
+    reply["msgs"]["k"].clone()
+}
+
+#[test]
+fn polls_a_chunk_of_messages_with_one_read() {
+    let mut node = start(&[]);
+    let mut kv = FakeKv::default();
+    for msg in 1..=40 {
+        assert_eq!(send(&mut node, &mut kv, msg * 10), msg);
+    }
+
+    let mut reads = 0;
+    let body = json!({"type": "poll", "offsets": {"k": 1}, "max_messages_per_key": 100});
+    let reply = call(&mut node, &mut kv, body, |body, _| {
+        reads += (body["type"] == "read") as usize;
+        None
+    });
+    let expected: Vec<Value> = (1..=40)
+        .map(|offset| json!([offset, offset * 10]))
+        .collect();
+    assert_eq!(
+        reply["msgs"]["k"],
+        json!(expected),
+        "unexpected reply: {}",
+        reply
+    );
+    // the latest offset, and the two chunks holding offsets 1 to 40
+    assert_eq!(reads, 3);
+}
+
+#[test]
+fn gives_up_on_an_offset_its_sender_never_wrote() {
+    let mut node = start(&[("MALEN_KAFKA_GAP_TIMEOUT_MS", "200")]);
+    let mut kv = FakeKv::default();
+    // offset 2 was allocated by a sender that crashed before writing it
+    kv.values.insert("offset/k".to_string(), json!(3));
+    kv.values
+        .insert("msgs/k/0".to_string(), json!([[1, 10], [3, 30]]));
+
+    assert_eq!(poll(&mut node, &mut kv), json!([[1, 10]]));
+    std::thread::sleep(Duration::from_millis(300));
+    // this poll still stops at the gap, but gives up on it
+    assert_eq!(poll(&mut node, &mut kv), json!([[1, 10]]));
+    assert_eq!(poll(&mut node, &mut kv), json!([[1, 10], [3, 30]]));
+    assert_eq!(kv.values["msgs/k/0"], json!([[1, 10], [2, null], [3, 30]]));
+}
+
+#[test]
+fn a_send_whose_offset_was_given_up_on_takes_the_next_one() {
+    let mut node = start(&[]);
+    let mut kv = FakeKv::default();
+    let mut gave_up = false;
+    let body = json!({"type": "send", "key": "k", "msg": 5});
+    let reply = call(&mut node, &mut kv, body, |body, kv| {
+        // a poll gives up on offset 1 just before the message gets there
+        if is_store(body) && !gave_up {
+            gave_up = true;
+            kv.values.insert("msgs/k/0".to_string(), json!([[1, null]]));
+        }
+        None
+    });
+    assert_eq!(reply["offset"], 2, "unexpected reply: {}", reply);
+    assert_eq!(poll(&mut node, &mut kv), json!([[2, 5]]));
+}
+
+#[test]
+fn retries_a_failed_write_with_backoff() {
+    let mut node = start(&[]);
+    let mut kv = FakeKv::default();
+    let mut tries = Vec::new();
+    let body = json!({"type": "send", "key": "k", "msg": 5});
+    let reply = call(&mut node, &mut kv, body, |body, _| {
+        if !is_store(body) {
+            return None;
+        }
+        tries.push(Instant::now());
+        (tries.len() <= 2).then(|| json!({"type": "error", "code": 11, "text": "busy"}))
+    });
+    assert_eq!(reply["offset"], 1, "unexpected reply: {}", reply);
+    assert_eq!(tries.len(), 3);
+    assert!(tries[1] - tries[0] >= Duration::from_millis(50));
+    assert!(tries[2] - tries[1] >= Duration::from_millis(100));
+    assert_eq!(poll(&mut node, &mut kv), json!([[1, 5]]));
+}
+
+#[test]
+fn gives_up_on_a_write_after_a_few_tries() {
+    let mut node = start(&[("MALEN_KAFKA_REQUEST_TIMEOUT_MS", "5000")]);
+    let mut kv = FakeKv::default();
+    for (code, expected_tries) in [(11, 5), (12, 1)] {
+        let mut tries = 0;
+        let body = json!({"type": "send", "key": "k", "msg": 5});
+        let reply = call(&mut node, &mut kv, body, |body, _| {
+            if !is_store(body) {
+                return None;
+            }
+            tries += 1;
+            Some(json!({"type": "error", "code": code, "text": "failed"}))
+        });
+        assert_eq!(reply["code"], code, "unexpected reply: {}", reply);
+        assert_eq!(tries, expected_tries, "tries after error {}", code);
+    }
+}
+
+#[test]
+fn refuses_key_value_requests_meant_for_lin_kv() {
+    let mut node = start(&[]);
+    let mut kv = FakeKv::default();
+    let reply = node.call(json!({"type": "read", "key": "k"}));
+    assert_eq!(reply["code"], 10, "unexpected reply: {}", reply);
+    // and keeps serving its clients
+    send(&mut node, &mut kv, 1);
+    assert_eq!(poll(&mut node, &mut kv), json!([[1, 1]]));
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+// the test stands in for n2
+fn to_n2(message: &Value) -> bool {
+    message["dest"] == "n2"
+}
+
+fn start() -> TestNode {
+    let mut node = TestNode::spawn(KAFKA, "n1", &[]);
+    node.init(&["n1", "n2"]);
+    node
+}
+
+/// A key n1 owns if `on_n1`, or else one n2 owns.
+fn find_key(node: &mut TestNode, on_n1: bool) -> String {
+    (0..)
+        .map(|i| format!("k{}", i))
+        .find(|key| {
+            let msg_id = node.send(json!({"type": "describe_key", "key": key}));
+            let message = node.receive(|message| {
+                to_n2(message) || message["body"]["in_reply_to"] == json!(msg_id)
+            });
+            to_n2(&message) != on_n1
+        })
+        .expect("a key")
+}
+
+#[test]
+fn a_long_poll_waits_for_the_owner_to_wake_it() {
+    let mut node = start();
+    let key = find_key(&mut node, false);
+
+    let poll = node.send(json!({"type": "poll", "offsets": {&key: 1}, "wait_ms": 3000}));
+    let part = node.receive(to_n2);
+    assert_eq!(part["body"]["type"], "poll");
+    let body =
+        json!({"type": "poll_ok", "in_reply_to": part["body"]["msg_id"], "msgs": {&key: []}});
+    node.send_from("n2", body);
+    let watch = node.receive(to_n2);
+    assert_eq!(
+        watch["body"]["type"], "watch",
+        "unexpected message: {}",
+        watch
+    );
+    assert_eq!(watch["body"]["offsets"], json!({&key: 1}));
+
+    // n1 does not keep polling n2 while it waits
+    let message = node.receive_within(Duration::from_millis(500), to_n2);
+    assert!(message.is_none(), "unexpected message: {:?}", message);
+
+    node.send_from("n2", json!({"type": "appended", "keys": [key]}));
+    let part = node.receive(to_n2);
+    assert_eq!(part["body"]["type"], "poll");
+    let body =
+        json!({"type": "poll_ok", "in_reply_to": part["body"]["msg_id"], "msgs": {&key: [[1, 7]]}});
+    node.send_from("n2", body);
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == json!(poll));
+    assert_eq!(
+        reply["body"]["msgs"][&key],
+        json!([[1, 7]]),
+        "unexpected reply: {}",
+        reply
+    );
+}
+
+#[test]
+fn an_owner_tells_a_watching_node_about_appends() {
+    let mut node = start();
+    let key = find_key(&mut node, true);
+    let appended = |message: &Value| to_n2(message) && message["body"]["type"] == "appended";
+
+    node.send_from(
+        "n2",
+        json!({"type": "watch", "offsets": {&key: 1}, "wait_ms": 3000}),
+    );
+    let message = node.receive_within(Duration::from_millis(300), appended);
+    assert!(message.is_none(), "unexpected message: {:?}", message);
+    let reply = node.call(json!({"type": "send", "key": key, "msg": 7}));
+    assert_eq!(reply["offset"], 1, "unexpected reply: {}", reply);
+    let message = node.receive(appended);
+    assert_eq!(message["body"]["keys"], json!([key]));
+
+    // a watch from an offset that is already there is answered right away
+    node.send_from(
+        "n2",
+        json!({"type": "watch", "offsets": {&key: 1}, "wait_ms": 3000}),
+    );
+    let message = node.receive(appended);
+    assert_eq!(message["body"]["keys"], json!([key]));
+    node.send_from(
+        "n2",
+        json!({"type": "watch", "offsets": {&key: 2}, "wait_ms": 3000}),
+    );
+    let message = node.receive_within(Duration::from_millis(300), appended);
+    assert!(message.is_none(), "unexpected message: {:?}", message);
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+fn start(envs: &[(&str, &str)]) -> TestNode {
+    let mut node = TestNode::spawn(KAFKA, "n1", envs);
+    node.init(&["n1"]);
+    for (key, count) in [("a", 20), ("b", 20)] {
+        for msg in 0..count {
+            let reply = node.call(json!({"type": "send", "key": key, "msg": msg}));
+            assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+        }
+    }
+    node
+}
+
+fn counts(reply: &Value) -> (usize, usize) {
+    assert_eq!(reply["type"], "poll_ok", "unexpected reply: {}", reply);
+    let count = |key: &str| reply["msgs"][key].as_array().map_or(0, Vec::len);
+    (count("a"), count("b"))
+}
+
+#[test]
+fn default_limit_matches_the_old_window() {
+    let mut node = start(&[]);
+    let reply = node.call(json!({"type": "poll", "offsets": {"a": 0, "b": 5}}));
+    assert_eq!(counts(&reply), (11, 11));
+}
+
+#[test]
+fn zero_message_limits_return_nothing() {
+    let mut node = start(&[]);
+    let poll = |limit: &str| json!({"type": "poll", "offsets": {"a": 0, "b": 0}, limit: 0});
+    assert_eq!(counts(&node.call(poll("max_messages"))), (0, 0));
+    assert_eq!(counts(&node.call(poll("max_messages_per_key"))), (0, 0));
+}
+
+#[test]
+fn total_limit_is_shared_round_robin() {
+    let mut node = start(&[("MALEN_KAFKA_POLL_MAX_MESSAGES", "5")]);
+    let reply = node.call(json!({"type": "poll", "offsets": {"a": 0, "b": 0}}));
+    assert_eq!(counts(&reply), (3, 2));
+}
@@ -0,0 +1,250 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::cluster::Cluster;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+/// Longer than failing over and a follower catching up take.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn start(count: usize, envs: &[(&str, &str)]) -> Cluster {
+    let mut all = vec![
+        ("MALEN_KAFKA_REPLICATION_FACTOR", "3"),
+        ("MALEN_KAFKA_FAILOVER_MS", "500"),
+    ];
+    all.extend_from_slice(envs);
+    Cluster::start(KAFKA, count, &all)
+}
+
+/// Call `node` until the reply is not an error, which it is while a failed
+/// leader is being replaced.
+fn call_ok(cluster: &mut Cluster, node: &str, body: Value) -> Value {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    loop {
+        let reply = cluster.call(node, body.clone());
+        if reply["type"] != "error" {
+            return reply;
+        }
+        assert!(Instant::now() < deadline, "still failing: {}", reply);
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn send(cluster: &mut Cluster, node: &str, msg: u64) -> u64 {
+    let reply = call_ok(
+        cluster,
+        node,
+        json!({"type": "send", "key": "k", "msg": msg}),
+    );
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+    reply["offset"].as_u64().expect("offset")
+}
+
+fn poll(cluster: &mut Cluster, node: &str) -> Value {
+    let body = json!({"type": "poll", "offsets": {"k": 1}, "max_messages_per_key": 1000});
+    let reply = call_ok(cluster, node, body);
+    assert_eq!(reply["type"], "poll_ok", "unexpected reply: {}", reply);
+    reply["msgs"]["k"].clone()
+}
+
+fn describe(cluster: &mut Cluster, node: &str) -> Value {
+    call_ok(cluster, node, json!({"type": "describe_key", "key": "k"}))
+}
+
+// the node leading "k" as `via` sees it, and one that does not lead it
+fn leader_and_other(cluster: &mut Cluster, via: &str) -> (String, String) {
+    let leader = describe(cluster, via)["owner"]
+        .as_str()
+        .expect("owner")
+        .to_string();
+    let other = cluster
+        .node_ids
+        .iter()
+        .find(|node| **node != leader)
+        .expect("more than one node")
+        .clone();
+    (leader, other)
+}
+
+fn entries(msgs: &[(u64, u64)]) -> Value {
+    json!(msgs
+        .iter()
+        .map(|(offset, msg)| json!([offset, msg]))
+        .collect::<Vec<_>>())
+}
+
+#[test]
+fn keeps_acknowledging_sends_while_they_keep_coming() {
+    let mut cluster = start(
+        2,
+        &[
+            ("MALEN_KAFKA_REPLICATION_FACTOR", "2"),
+            ("MALEN_KAFKA_MIN_INSYNC_ACKS", "2"),
+            ("MALEN_KAFKA_REPLICA_LAG_MS", "100"),
+        ],
+    );
+    send(&mut cluster, "n1", 0);
+
+    // keep 30 sends outstanding, well past the replica lag, so the leader
+    // has new records at nearly every fetch of its follower
+    let mut outstanding = std::collections::VecDeque::new();
+    let mut offsets = Vec::new();
+    let until = Instant::now() + Duration::from_secs(2);
+    let mut msg = 1;
+    while Instant::now() < until || !outstanding.is_empty() {
+        while Instant::now() < until && outstanding.len() < 30 {
+            let node = if msg % 2 == 0 { "n1" } else { "n2" };
+            outstanding
+                .push_back(cluster.send(node, json!({"type": "send", "key": "k", "msg": msg})));
+            msg += 1;
+        }
+        let msg_id = outstanding.pop_front().expect("a send is outstanding");
+        let reply = cluster.reply(msg_id);
+        assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+        offsets.push(reply["offset"].as_u64().expect("offset"));
+    }
+    offsets.sort();
+    offsets.dedup();
+    assert_eq!(offsets.len() as u64, msg - 1);
+    assert!(offsets.len() > 100, "only {} sends", offsets.len());
+}
+
+#[test]
+fn a_follower_catches_up_after_a_partition() {
+    let mut cluster = start(
+        2,
+        &[
+            ("MALEN_KAFKA_REPLICATION_FACTOR", "2"),
+            ("MALEN_KAFKA_FETCH_MAX_RECORDS", "10"),
+            ("MALEN_KAFKA_REPLICA_LAG_MS", "200"),
+            ("MALEN_KAFKA_FAILOVER_MS", "3000"),
+        ],
+    );
+    send(&mut cluster, "n1", 1);
+    let (leader, follower) = leader_and_other(&mut cluster, "n1");
+
+    cluster.partition(&[follower.as_str()]);
+    for msg in 2..=50 {
+        assert_eq!(send(&mut cluster, &leader, msg), msg);
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(describe(&mut cluster, &leader)["in_sync"], json!([leader]));
+    cluster.heal();
+
+    let both = json!([leader, follower]);
+    let replies = cluster.wait_for(
+        json!({"type": "describe_key", "key": "k"}),
+        SETTLE_TIMEOUT,
+        |replies| replies.iter().all(|reply| reply["in_sync"] == both),
+    );
+    assert_eq!(replies[0]["in_sync"], both, "{:?}", replies);
+
+    // only the follower's copy is left to read from
+    cluster.kill(&leader);
+    let expected: Vec<(u64, u64)> = (1..=50).map(|msg| (msg, msg)).collect();
+    assert_eq!(poll(&mut cluster, &follower), entries(&expected));
+}
+
+#[test]
+fn a_replica_takes_over_when_the_leader_dies() {
+    let mut cluster = start(3, &[("MALEN_KAFKA_MIN_INSYNC_ACKS", "2")]);
+    let producer =
+        |msg: u64| json!({"type": "send", "key": "k", "msg": msg, "producer_id": "p1", "seq": msg});
+    for msg in 1..=3 {
+        let reply = call_ok(&mut cluster, "n1", producer(msg));
+        assert_eq!(reply["offset"], msg, "unexpected reply: {}", reply);
+    }
+    let commit = json!({"type": "commit_offsets", "offsets": {"k": 2}, "group": "g"});
+    assert_eq!(
+        call_ok(&mut cluster, "n1", commit)["type"],
+        "commit_offsets_ok"
+    );
+
+    // followers pick committed offsets up with their next fetch
+    std::thread::sleep(Duration::from_millis(300));
+    let (leader, survivor) = leader_and_other(&mut cluster, "n1");
+    cluster.kill(&leader);
+
+    // the producer did not hear back about its last send and sends it again
+    let reply = call_ok(&mut cluster, &survivor, producer(3));
+    assert_eq!(reply["offset"], 3, "resend got another offset: {}", reply);
+    let reply = call_ok(&mut cluster, &survivor, producer(4));
+    assert_eq!(reply["offset"], 4, "unexpected reply: {}", reply);
+
+    let (new_leader, _) = leader_and_other(&mut cluster, &survivor);
+    assert_ne!(new_leader, leader);
+    assert_eq!(
+        poll(&mut cluster, &survivor),
+        entries(&[(1, 1), (2, 2), (3, 3), (4, 4)])
+    );
+    let body = json!({"type": "list_committed_offsets", "keys": ["k"], "group": "g"});
+    let reply = call_ok(&mut cluster, &survivor, body);
+    assert_eq!(
+        reply["offsets"],
+        json!({"k": 2}),
+        "unexpected reply: {}",
+        reply
+    );
+}
+
+// wait until every replica of "k" is in sync with its leader
+fn wait_in_sync(cluster: &mut Cluster) {
+    let in_sync = |replies: &[Value]| {
+        replies.iter().all(|reply| {
+            reply["in_sync"]
+                .as_array()
+                .is_some_and(|nodes| nodes.len() == 3)
+        })
+    };
+    let body = json!({"type": "describe_key", "key": "k"});
+    let replies = cluster.wait_for(body, SETTLE_TIMEOUT, in_sync);
+    assert!(in_sync(&replies), "replicas not in sync: {:?}", replies);
+}
+
+fn deposed_leader_drops_what_it_wrote_alone(envs: &[(&str, &str)]) {
+    let mut cluster = start(3, envs);
+    send(&mut cluster, "n1", 1);
+    wait_in_sync(&mut cluster);
+    let (old_leader, other) = leader_and_other(&mut cluster, "n1");
+
+    // with one ack the cut-off leader still takes sends, which the others
+    // never see before one of them takes over
+    cluster.partition(&[old_leader.as_str()]);
+    for msg in 2..=4 {
+        assert_eq!(send(&mut cluster, &old_leader, msg), msg);
+    }
+    assert_eq!(send(&mut cluster, &other, 5), 2);
+    let (new_leader, _) = leader_and_other(&mut cluster, &other);
+    assert_ne!(new_leader, old_leader);
+    cluster.heal();
+
+    // once the old leader is back in sync, it is the only one left
+    wait_in_sync(&mut cluster);
+    for node in cluster.node_ids.clone() {
+        if node != old_leader {
+            cluster.kill(&node);
+        }
+    }
+    assert_eq!(poll(&mut cluster, &old_leader), entries(&[(1, 1), (2, 5)]));
+    assert_eq!(send(&mut cluster, &old_leader, 6), 3);
+}
+
+#[test]
+fn a_deposed_leader_drops_what_it_wrote_alone() {
+    deposed_leader_drops_what_it_wrote_alone(&[]);
+}
+
+#[test]
+fn a_deposed_leader_drops_what_it_wrote_alone_from_its_segments() {
+    let dir = common::temp_dir("kafka-deposed-leader");
+    let dir = dir.to_str().expect("utf-8 path");
+    // a record or two per segment
+    deposed_leader_drops_what_it_wrote_alone(&[
+        ("MALEN_KAFKA_STORAGE", "file"),
+        ("MALEN_KAFKA_DATA_DIR", dir),
+        ("MALEN_KAFKA_SEGMENT_BYTES", "100"),
+    ]);
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+fn start(envs: &[(&str, &str)]) -> TestNode {
+    let mut envs = envs.to_vec();
+    envs.push(("MALEN_KAFKA_RETENTION_CHECK_MS", "50"));
+    let mut node = TestNode::spawn(KAFKA, "n1", &envs);
+    node.init(&["n1"]);
+    node
+}
+
+fn send(node: &mut TestNode, msg: u64) {
+    let reply = node.call(json!({"type": "send", "key": "k", "msg": msg}));
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+}
+
+fn poll(node: &mut TestNode, offset: u64) -> Value {
+    node.call(json!({"type": "poll", "offsets": {"k": offset}}))
+}
+
+/// Wait for retention to drop every message before `offset`.
+fn wait_for_start(node: &mut TestNode, offset: u64) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let reply = node.call(json!({"type": "describe_key", "key": "k"}));
+        if reply["first_offset"] == offset {
+            return;
+        }
+        assert!(Instant::now() < deadline, "log still starts at: {}", reply);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn drops_messages_older_than_the_maximum_age() {
+    let mut node = start(&[("MALEN_KAFKA_RETENTION_MS", "500")]);
+    send(&mut node, 1);
+    send(&mut node, 2);
+    std::thread::sleep(Duration::from_millis(700));
+    send(&mut node, 3);
+
+    wait_for_start(&mut node, 3);
+    assert_eq!(poll(&mut node, 1)["msgs"]["k"], json!([[3, 3]]));
+}
+
+#[test]
+fn keeps_the_newest_messages_that_fit_the_byte_limit() {
+    // each of these records takes 46 bytes as stored, so 120 fit two
+    let mut node = start(&[("MALEN_KAFKA_RETENTION_BYTES", "120")]);
+    for msg in 1..=5 {
+        send(&mut node, msg);
+    }
+
+    wait_for_start(&mut node, 4);
+    assert_eq!(poll(&mut node, 1)["msgs"]["k"], json!([[4, 4], [5, 5]]));
+}
+
+#[test]
+fn compaction_keeps_what_every_group_still_has_to_read() {
+    let mut node = start(&[("MALEN_KAFKA_COMPACT", "true")]);
+    for msg in 1..=5 {
+        send(&mut node, msg);
+    }
+    for (group, offset) in [("g1", 4), ("g2", 3)] {
+        let body = json!({"type": "commit_offsets", "offsets": {"k": offset}, "group": group});
+        assert_eq!(node.call(body)["type"], "commit_offsets_ok");
+    }
+
+    wait_for_start(&mut node, 3);
+    assert_eq!(
+        poll(&mut node, 1)["msgs"]["k"],
+        json!([[3, 3], [4, 4], [5, 5]])
+    );
+}
+
+#[test]
+fn a_poll_below_the_start_skips_ahead_or_fails() {
+    let retention = ("MALEN_KAFKA_RETENTION_MESSAGES", "2");
+    let mut skip = start(&[retention]);
+    let mut error = start(&[retention, ("MALEN_KAFKA_TRUNCATED_POLL", "error")]);
+    for node in [&mut skip, &mut error] {
+        for msg in 1..=4 {
+            send(node, msg);
+        }
+        wait_for_start(node, 3);
+    }
+
+    assert_eq!(poll(&mut skip, 1)["msgs"]["k"], json!([[3, 3], [4, 4]]));
+    let reply = poll(&mut error, 1);
+    assert_eq!(reply["code"], 1000, "unexpected reply: {}", reply);
+    assert_eq!(reply["text"], "offsets out of range: k starts at offset 3");
+    // offsets that are still kept poll as usual
+    assert_eq!(poll(&mut error, 3)["msgs"]["k"], json!([[3, 3], [4, 4]]));
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use common::{temp_dir, TestNode};
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+fn start(data_dir: &Path) -> TestNode {
+    start_with(data_dir, &[])
+}
+
+fn start_with(data_dir: &Path, extra_envs: &[(&str, &str)]) -> TestNode {
+    let mut envs = vec![
+        ("MALEN_KAFKA_STORAGE", "file"),
+        (
+            "MALEN_KAFKA_DATA_DIR",
+            data_dir.to_str().expect("UTF-8 path"),
+        ),
+    ];
+    envs.extend_from_slice(extra_envs);
+    let mut node = TestNode::spawn(KAFKA, "n1", &envs);
+    node.init(&["n1"]);
+    node
+}
+
+fn send(node: &mut TestNode, key: &str, msg: u64) -> u64 {
+    let reply = node.call(json!({"type": "send", "key": key, "msg": msg}));
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+    reply["offset"].as_u64().expect("offset")
+}
+
+fn poll(node: &mut TestNode, key: &str) -> Vec<u64> {
+    let reply = node.call(json!({"type": "poll", "offsets": {key: 0}}));
+    assert_eq!(reply["type"], "poll_ok", "unexpected reply: {}", reply);
+    reply["msgs"][key]
+        .as_array()
+        .map_or(&[] as &[Value], Vec::as_slice)
+        .iter()
+        .map(|entry| entry[1].as_u64().expect("msg"))
+        .collect()
+}
+
+fn send_idempotent(node: &mut TestNode, key: &str, msg: u64, seq: u64) -> u64 {
+    let body = json!({"type": "send", "key": key, "msg": msg, "producer_id": "p1", "seq": seq});
+    let reply = node.call(body);
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+    reply["offset"].as_u64().expect("offset")
+}
+
+// the newest segment file of `key` on node n1
+fn newest_segment(data_dir: &Path, key: &str) -> PathBuf {
+    let mut logs: Vec<PathBuf> = std::fs::read_dir(data_dir.join("n1").join(key))
+        .expect("key directory")
+        .map(|entry| entry.expect("directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    logs.sort();
+    logs.pop().expect("a segment file")
+}
+
+#[test]
+fn recovers_everything_after_a_crash() {
+    let dir = temp_dir("kafka-restart");
+    let mut node = start(&dir);
+    for msg in [10, 11, 12] {
+        send(&mut node, "k", msg);
+    }
+    let reply = node.call(json!({"type": "commit_offsets", "offsets": {"k": 1}}));
+    assert_eq!(
+        reply["type"], "commit_offsets_ok",
+        "unexpected reply: {}",
+        reply
+    );
+    node.kill();
+
+    let mut node = start(&dir);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11, 12]);
+    let reply = node.call(json!({"type": "list_committed_offsets", "keys": ["k"]}));
+    assert_eq!(reply["offsets"]["k"], 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn truncates_a_torn_write() {
+    let dir = temp_dir("kafka-torn-write");
+    let mut node = start(&dir);
+    for msg in [10, 11, 12] {
+        send(&mut node, "k", msg);
+    }
+    node.kill();
+
+    // half a frame header, as if the node died in the middle of a write
+    let segment = newest_segment(&dir, "k");
+    let length = std::fs::metadata(&segment).expect("segment").len();
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .expect("open");
+    file.write_all(&[0x20, 0, 0]).expect("append torn header");
+    drop(file);
+
+    let mut node = start(&dir);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11, 12]);
+    assert_eq!(std::fs::metadata(&segment).expect("segment").len(), length);
+    // appends carry on right after the last intact record
+    let offset = send(&mut node, "k", 13);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11, 12, 13]);
+    node.kill();
+
+    let mut node = start(&dir);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11, 12, 13]);
+    assert_eq!(send(&mut node, "k", 14), offset + 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn drops_a_corrupt_last_record() {
+    let dir = temp_dir("kafka-corrupt-tail");
+    let mut node = start(&dir);
+    for msg in [10, 11, 12] {
+        send(&mut node, "k", msg);
+    }
+    node.kill();
+
+    // flip a byte of the last record's payload so its checksum fails
+    let segment = newest_segment(&dir, "k");
+    let mut bytes = std::fs::read(&segment).expect("segment");
+    let last = bytes.len() - 2;
+    bytes[last] ^= 0xff;
+    std::fs::write(&segment, &bytes).expect("corrupt segment");
+
+    let mut node = start(&dir);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11]);
+    send(&mut node, "k", 13);
+    assert_eq!(poll(&mut node, "k"), vec![10, 11, 13]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn finds_offsets_by_time_across_reopened_segments() {
+    let dir = temp_dir("kafka-offsets-for-times");
+    let envs = [
+        ("MALEN_KAFKA_STORAGE", "file"),
+        ("MALEN_KAFKA_DATA_DIR", dir.to_str().expect("UTF-8 path")),
+        // a few records per segment and per index entry
+        ("MALEN_KAFKA_SEGMENT_BYTES", "300"),
+        ("MALEN_KAFKA_INDEX_INTERVAL_BYTES", "100"),
+    ];
+    let mut node = TestNode::spawn(KAFKA, "n1", &envs);
+    node.init(&["n1"]);
+    let mut times = Vec::new();
+    for msg in 0..30 {
+        std::thread::sleep(std::time::Duration::from_millis(3));
+        times.push(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("clock after the epoch")
+                .as_millis() as u64,
+        );
+        std::thread::sleep(std::time::Duration::from_millis(3));
+        send(&mut node, "k", msg);
+    }
+    node.kill();
+
+    let mut node = TestNode::spawn(KAFKA, "n1", &envs);
+    node.init(&["n1"]);
+    for (msg, time) in times.iter().enumerate() {
+        let reply = node.call(json!({"type": "offsets_for_times", "times": {"k": time}}));
+        let offset = reply["offsets"]["k"].clone();
+        let polled = node.call(json!({"type": "poll", "offsets": {"k": offset}}));
+        assert_eq!(
+            polled["msgs"]["k"][0][1], msg,
+            "lookup of {}: {}",
+            time, reply
+        );
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn deduplicates_resends_after_truncation_and_restart() {
+    let dir = temp_dir("kafka-producer-state");
+    let retention = [
+        ("MALEN_KAFKA_RETENTION_MESSAGES", "2"),
+        ("MALEN_KAFKA_RETENTION_CHECK_MS", "50"),
+    ];
+    let mut node = start_with(&dir, &retention);
+    let first = send_idempotent(&mut node, "k", 10, 1);
+    for msg in [11, 12, 13] {
+        send(&mut node, "k", msg);
+    }
+    // wait for retention to drop the idempotent send
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while poll(&mut node, "k") != vec![12, 13] {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "retention never truncated"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    node.kill();
+
+    let mut node = start_with(&dir, &retention);
+    assert_eq!(send_idempotent(&mut node, "k", 10, 1), first);
+    assert_eq!(poll(&mut node, "k"), vec![12, 13]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn deduplicates_resends_after_delete_and_restart() {
+    let dir = temp_dir("kafka-producer-delete");
+    let mut node = start(&dir);
+    let first = send_idempotent(&mut node, "k", 10, 1);
+    let reply = node.call(json!({"type": "delete_key", "key": "k"}));
+    assert_eq!(
+        reply["type"], "delete_key_ok",
+        "unexpected reply: {}",
+        reply
+    );
+    assert_eq!(send_idempotent(&mut node, "k", 10, 1), first);
+    node.kill();
+
+    let mut node = start(&dir);
+    assert_eq!(send_idempotent(&mut node, "k", 10, 1), first);
+    // the next sequence number is still a new message
+    assert!(send_idempotent(&mut node, "k", 11, 2) > first);
+    assert_eq!(poll(&mut node, "k"), vec![11]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestNode;
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+
+/// Unacknowledged deliveries are sent again after this long.
+const REDELIVERY_MS: u64 = 300;
+
+fn start() -> TestNode {
+    let timeout = REDELIVERY_MS.to_string();
+    let mut node = TestNode::spawn(KAFKA, "n1", &[("MALEN_KAFKA_REQUEST_TIMEOUT_MS", &timeout)]);
+    node.init(&["n1"]);
+    node
+}
+
+fn send(node: &mut TestNode, msg: u64) {
+    let reply = node.call(json!({"type": "send", "key": "k", "msg": msg}));
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+}
+
+fn subscribe(node: &mut TestNode, body: Value) -> Value {
+    let reply = node.call(body);
+    assert_eq!(reply["type"], "subscribe_ok", "unexpected reply: {}", reply);
+    reply["subscription"].clone()
+}
+
+fn is_deliver(message: &Value) -> bool {
+    message["body"]["type"] == "deliver"
+}
+
+fn ack(node: &mut TestNode, deliver: &Value) {
+    let body = json!({"type": "deliver_ok", "in_reply_to": deliver["body"]["msg_id"]});
+    node.send(body);
+}
+
+#[test]
+fn ignores_a_stray_deliver() {
+    let mut node = TestNode::spawn(KAFKA, "n1", &[]);
+    node.init(&["n1"]);
+    let body = json!({"type": "deliver", "subscription": "s1", "msgs": {"k": [[1, 1]]}});
+    node.send_from("n2", body);
+    let reply = node.call(json!({"type": "send", "key": "k", "msg": 1}));
+    assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+}
+
+#[test]
+fn pushes_messages_from_the_subscribed_offset_on() {
+    let mut node = start();
+    send(&mut node, 10);
+    send(&mut node, 11);
+    let subscription = subscribe(&mut node, json!({"type": "subscribe", "offsets": {"k": 2}}));
+
+    let deliver = node.receive(is_deliver);
+    assert_eq!(deliver["dest"], "c1");
+    assert_eq!(deliver["body"]["subscription"], subscription);
+    assert_eq!(deliver["body"]["msgs"], json!({"k": [[2, 11]]}));
+    ack(&mut node, &deliver);
+
+    // later sends are pushed as they are appended
+    send(&mut node, 12);
+    let deliver = node.receive(is_deliver);
+    assert_eq!(deliver["body"]["msgs"], json!({"k": [[3, 12]]}));
+}
+
+#[test]
+fn stops_pushing_once_the_in_flight_limit_is_reached() {
+    let mut node = start();
+    for msg in [1, 2, 3] {
+        send(&mut node, msg);
+    }
+    let body = json!({"type": "subscribe", "offsets": {"k": 1}, "max_in_flight": 2});
+    subscribe(&mut node, body);
+
+    let deliver = node.receive(is_deliver);
+    assert_eq!(deliver["body"]["msgs"], json!({"k": [[1, 1], [2, 2]]}));
+    let early = Duration::from_millis(REDELIVERY_MS / 2);
+    assert_eq!(node.receive_within(early, is_deliver), None);
+
+    // acknowledging frees room for the rest
+    ack(&mut node, &deliver);
+    let deliver = node.receive(is_deliver);
+    assert_eq!(deliver["body"]["msgs"], json!({"k": [[3, 3]]}));
+}
+
+#[test]
+fn redelivers_what_was_not_acknowledged() {
+    let mut node = start();
+    send(&mut node, 1);
+    subscribe(&mut node, json!({"type": "subscribe", "offsets": {"k": 1}}));
+
+    let first = node.receive(is_deliver);
+    let again = node.receive(is_deliver);
+    assert_eq!(again["body"]["msgs"], first["body"]["msgs"]);
+    assert_ne!(again["body"]["msg_id"], first["body"]["msg_id"]);
+
+    // an acknowledged delivery is not sent again
+    ack(&mut node, &again);
+    let wait = Duration::from_millis(REDELIVERY_MS * 3);
+    assert_eq!(node.receive_within(wait, is_deliver), None);
+}
@@ -0,0 +1,302 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{cluster::Cluster, TestNode};
+use serde_json::{json, Value};
+
+const KAFKA: &str = env!("CARGO_BIN_EXE_kafka");
+/// Coordinators and participants give up on each other quickly.
+const TXN_ENV: &[(&str, &str)] = &[("MALEN_KAFKA_TXN_TIMEOUT_MS", "300")];
+
+/// A key `node` owns. Finding it sends one message to each key tried, so the
+/// key's next offset is 2.
+fn key_owned_by(cluster: &mut Cluster, node: &str) -> String {
+    for i in 0.. {
+        let key = format!("{}-k{}", node, i);
+        let reply = cluster.call("n1", json!({"type": "send", "key": key, "msg": 0}));
+        assert_eq!(reply["type"], "send_ok", "unexpected reply: {}", reply);
+        let reply = cluster.call("n1", json!({"type": "describe_key", "key": key}));
+        if reply["owner"] == node {
+            return key;
+        }
+    }
+    unreachable!()
+}
+
+// messages of `key` from offset 2 on, after the one `key_owned_by` sent
+fn msgs(reply: &Value, key: &str) -> Vec<u64> {
+    assert_eq!(reply["type"], "poll_ok", "unexpected reply: {}", reply);
+    reply["msgs"][key]
+        .as_array()
+        .map_or(&[] as &[Value], Vec::as_slice)
+        .iter()
+        .map(|entry| entry[1].as_u64().expect("msg"))
+        .collect()
+}
+
+fn poll(a: &str, b: &str) -> Value {
+    json!({"type": "poll", "offsets": {a: 2, b: 2}, "max_messages_per_key": 1000})
+}
+
+#[test]
+fn commits_across_owners() {
+    let mut cluster = Cluster::start(KAFKA, 2, TXN_ENV);
+    let a = key_owned_by(&mut cluster, "n1");
+    let b = key_owned_by(&mut cluster, "n2");
+
+    let body = json!({"type": "send_txn", "msgs": [[a, 10], [b, 20], [a, 11]]});
+    let reply = cluster.call("n2", body);
+    assert_eq!(reply["type"], "send_txn_ok", "unexpected reply: {}", reply);
+    assert_eq!(reply["offsets"], json!([[a, 2], [b, 2], [a, 3]]));
+
+    for node in ["n1", "n2"] {
+        let reply = cluster.call(node, poll(&a, &b));
+        assert_eq!(msgs(&reply, &a), vec![10, 11], "{}: {}", node, reply);
+        assert_eq!(msgs(&reply, &b), vec![20], "{}: {}", node, reply);
+    }
+}
+
+#[test]
+fn aborts_when_votes_are_lost() {
+    let mut cluster = Cluster::start(KAFKA, 2, TXN_ENV);
+    let a = key_owned_by(&mut cluster, "n1");
+    let b = key_owned_by(&mut cluster, "n2");
+
+    // n2 prepares, but its vote never reaches the coordinator
+    cluster.block("n2", "n1");
+    let reply = cluster.call("n1", json!({"type": "send_txn", "msgs": [[a, 1], [b, 2]]}));
+    assert_eq!(reply["code"], 14, "unexpected reply: {}", reply);
+    cluster.heal();
+
+    let reply = cluster.call("n1", json!({"type": "send_txn", "msgs": [[a, 3], [b, 4]]}));
+    assert_eq!(reply["type"], "send_txn_ok", "unexpected reply: {}", reply);
+    // neither participant kept anything of the aborted transaction
+    assert_eq!(reply["offsets"], json!([[a, 2], [b, 2]]));
+    let reply = cluster.call("n2", poll(&a, &b));
+    assert_eq!((msgs(&reply, &a), msgs(&reply, &b)), (vec![3], vec![4]));
+}
+
+#[test]
+fn concurrent_polls_see_whole_transactions() {
+    let mut cluster = Cluster::start(KAFKA, 2, TXN_ENV);
+    let a = key_owned_by(&mut cluster, "n1");
+    let b = key_owned_by(&mut cluster, "n2");
+
+    let mut sends = Vec::new();
+    for msg in 1..=30 {
+        let body = json!({"type": "send_txn", "msgs": [[a, msg], [b, msg]]});
+        sends.push(cluster.send("n1", body));
+        // poll while the transaction is still on its way through both owners
+        for node in ["n1", "n2"] {
+            let reply = cluster.call(node, poll(&a, &b));
+            assert_eq!(msgs(&reply, &a), msgs(&reply, &b), "{}: {}", node, reply);
+        }
+    }
+    for msg_id in sends {
+        let reply = cluster.reply(msg_id);
+        assert_eq!(reply["type"], "send_txn_ok", "unexpected reply: {}", reply);
+    }
+    let reply = cluster.call("n2", poll(&a, &b));
+    assert_eq!(msgs(&reply, &a), (1..=30).collect::<Vec<u64>>());
+}
+
+#[test]
+fn a_poll_leaves_out_what_another_owner_still_hides() {
+    // the test stands in for n2, which owns `b`
+    let mut node = TestNode::spawn(KAFKA, "n1", TXN_ENV);
+    node.init(&["n1", "n2"]);
+    let to_n2 = |message: &Value| message["dest"] == "n2";
+    let owned = |node: &mut TestNode, key: &str| {
+        let msg_id = node.send(json!({"type": "describe_key", "key": key}));
+        let message = node
+            .receive(|message| to_n2(message) || message["body"]["in_reply_to"] == json!(msg_id));
+        !to_n2(&message)
+    };
+    let a = (0..)
+        .map(|i| format!("a{}", i))
+        .find(|key| owned(&mut node, key));
+    let b = (0..)
+        .map(|i| format!("b{}", i))
+        .find(|key| !owned(&mut node, key));
+    let (a, b) = (a.expect("key on n1"), b.expect("key on n2"));
+
+    let send_txn = node.send(json!({"type": "send_txn", "msgs": [[a, 1], [b, 1]]}));
+    let prepare = node.receive(to_n2);
+    let txn_id = prepare["body"]["txn_id"].clone();
+    node.send_from(
+        "n2",
+        json!({"type": "txn_vote", "txn_id": txn_id, "prepared": true}),
+    );
+    let commit = node.receive(to_n2);
+    assert_eq!(commit["body"]["type"], "txn_commit");
+    let body = json!({"type": "txn_commit_ok", "txn_id": txn_id, "offsets": [[b, 1]]});
+    node.send_from("n2", body);
+    // n2 hears the transaction is complete before the client hears back
+    let complete = node.receive(to_n2);
+    assert_eq!(complete["body"]["type"], "txn_complete");
+    let reply = node.receive(|message| message["body"]["in_reply_to"] == json!(send_txn));
+    assert_eq!(
+        reply["body"]["type"], "send_txn_ok",
+        "unexpected reply: {}",
+        reply
+    );
+
+    // n1 shows its half, but n2 has not handled the txn_complete yet
+    let poll_through_n1 = |node: &mut TestNode, hidden: Value, b_msgs: Value| {
+        let msg_id = node.send(json!({"type": "poll", "offsets": {a.as_str(): 0, b.as_str(): 0}}));
+        let part = node.receive(to_n2);
+        let body = json!({
+            "type": "poll_ok",
+            "in_reply_to": part["body"]["msg_id"],
+            "msgs": {b.as_str(): b_msgs},
+            "hidden_txns": hidden,
+        });
+        node.send_from("n2", body);
+        node.receive(|message| message["body"]["in_reply_to"] == json!(msg_id))["body"].clone()
+    };
+    let reply = poll_through_n1(&mut node, json!([txn_id]), json!([]));
+    assert_eq!(
+        reply["msgs"][&a],
+        json!([]),
+        "half a transaction: {}",
+        reply
+    );
+    assert!(
+        reply.get("hidden_txns").is_none(),
+        "internal fields: {}",
+        reply
+    );
+
+    let reply = poll_through_n1(&mut node, json!([]), json!([[1, 1]]));
+    assert_eq!(
+        reply["msgs"][&a],
+        json!([[1, 1]]),
+        "unexpected reply: {}",
+        reply
+    );
+    assert_eq!(
+        reply["msgs"][&b],
+        json!([[1, 1]]),
+        "unexpected reply: {}",
+        reply
+    );
+}
+
+#[test]
+fn votes_no_once_decided_and_refuses_unprepared_commits() {
+    let mut node = TestNode::spawn(KAFKA, "n1", TXN_ENV);
+    node.init(&["n1"]);
+
+    // the abort overtook the prepare
+    let reply = node.call(json!({"type": "txn_abort", "txn_id": "t1"}));
+    assert_eq!(reply["type"], "txn_abort_ok", "unexpected reply: {}", reply);
+    let reply = node.call(json!({"type": "txn_prepare", "txn_id": "t1", "msgs": [["k", 1]]}));
+    assert_eq!(reply["prepared"], false, "unexpected reply: {}", reply);
+
+    let reply = node.call(json!({"type": "txn_commit", "txn_id": "t2"}));
+    assert_eq!(
+        reply["type"], "txn_commit_failed",
+        "unexpected reply: {}",
+        reply
+    );
+
+    let reply = node.call(json!({"type": "txn_prepare", "txn_id": "t3", "msgs": [["k", 3]]}));
+    assert_eq!(reply["prepared"], true, "unexpected reply: {}", reply);
+    let reply = node.call(json!({"type": "txn_commit", "txn_id": "t3"}));
+    assert_eq!(
+        reply["offsets"],
+        json!([["k", 1]]),
+        "unexpected reply: {}",
+        reply
+    );
+    let reply = node.call(json!({"type": "txn_prepare", "txn_id": "t3", "msgs": [["k", 3]]}));
+    assert_eq!(reply["prepared"], false, "unexpected reply: {}", reply);
+}
+
+#[test]
+fn recovers_from_a_coordinator_that_crashed() {
+    // the test is the coordinator, c1, and crashes after the prepares
+    let mut cluster = Cluster::start(KAFKA, 2, TXN_ENV);
+    let a = key_owned_by(&mut cluster, "n1");
+    let b = key_owned_by(&mut cluster, "n2");
+    for (txn_id, msgs) in [("t1", [1, 2]), ("t2", [7, 8])] {
+        for (node, key, msg) in [("n1", &a, msgs[0]), ("n2", &b, msgs[1])] {
+            let body = json!({"type": "txn_prepare", "txn_id": txn_id,
+                "msgs": [[key, msg]], "participants": ["n1", "n2"]});
+            assert_eq!(cluster.call(node, body)["prepared"], true);
+        }
+    }
+    // t2 was decided and reached n1, but not n2
+    let reply = cluster.call("n1", json!({"type": "txn_commit", "txn_id": "t2"}));
+    assert_eq!(
+        reply["offsets"],
+        json!([[a, 2]]),
+        "unexpected reply: {}",
+        reply
+    );
+    let reply = cluster.call("n1", poll(&a, &b));
+    assert_eq!(
+        msgs(&reply, &a),
+        Vec::<u64>::new(),
+        "shown too early: {}",
+        reply
+    );
+
+    // once the coordinator is restarted it knows neither transaction
+    let started = Instant::now();
+    let mut asked = Vec::new();
+    while asked.len() < 4 {
+        let message = cluster.receive(|message| message["body"]["type"] == "txn_status");
+        let ask = (message["src"].clone(), message["body"]["txn_id"].clone());
+        if !asked.contains(&ask) {
+            asked.push(ask);
+        }
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    for (node, txn_id) in asked {
+        let node = node.as_str().expect("src");
+        cluster.send(node, json!({"type": "txn_complete", "txn_id": txn_id}));
+    }
+
+    // n1 committed t2, so n2 commits it too, and neither committed t1
+    let replies = cluster.wait_for(poll(&a, &b), Duration::from_secs(5), |replies| {
+        replies
+            .iter()
+            .all(|reply| (msgs(reply, &a), msgs(reply, &b)) == (vec![7], vec![8]))
+    });
+    for reply in replies {
+        assert_eq!((msgs(&reply, &a), msgs(&reply, &b)), (vec![7], vec![8]));
+    }
+    for node in ["n1", "n2"] {
+        let reply = cluster.call(node, json!({"type": "txn_commit", "txn_id": "t1"}));
+        assert_eq!(
+            reply["type"], "txn_commit_failed",
+            "unexpected reply: {}",
+            reply
+        );
+    }
+}
+
+#[test]
+fn a_restarted_coordinator_reports_forgotten_transactions_complete() {
+    let mut node = TestNode::spawn(KAFKA, "n1", TXN_ENV);
+    node.init(&["n1"]);
+    node.send(json!({"type": "txn_status", "txn_id": "n1-0-1"}));
+    let message = node.receive(|message| message["body"]["type"] == "txn_complete");
+    assert_eq!(message["body"]["txn_id"], "n1-0-1");
+}
+
+#[test]
+fn ignores_a_stray_send_txn_ok() {
+    let mut node = TestNode::spawn(KAFKA, "n1", TXN_ENV);
+    node.init(&["n1"]);
+    node.send_from("n2", json!({"type": "send_txn_ok", "offsets": []}));
+    let reply = node.call(json!({"type": "send_txn", "msgs": [["k", 1]]}));
+    assert_eq!(
+        reply["offsets"],
+        json!([["k", 1]]),
+        "unexpected reply: {}",
+        reply
+    );
+}
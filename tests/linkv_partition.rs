@@ -0,0 +1,93 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::cluster::Cluster;
+use serde_json::{json, Value};
+
+const LINKV: &str = env!("CARGO_BIN_EXE_linkv");
+/// Short Raft timers, so elections happen within a second or so.
+const RAFT_ENV: &[(&str, &str)] = &[
+    ("MALEN_RAFT_ELECTION_TIMEOUT_MS", "300"),
+    ("MALEN_RAFT_HEARTBEAT_MS", "50"),
+];
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Call `node` with `body` until it answers with something other than an
+/// error, and return that answer.
+fn call_until_ok(cluster: &mut Cluster, node: &str, body: Value) -> Value {
+    let deadline = Instant::now() + ELECTION_TIMEOUT;
+    loop {
+        let reply = cluster.call(node, body.clone());
+        if reply["type"] != "error" || Instant::now() >= deadline {
+            return reply;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn write(cluster: &mut Cluster, node: &str, key: u64, value: u64) {
+    let body = json!({"type": "write", "key": key, "value": value});
+    let reply = call_until_ok(cluster, node, body);
+    assert_eq!(reply["type"], "write_ok", "unexpected reply: {}", reply);
+}
+
+fn read(cluster: &mut Cluster, node: &str, key: u64) -> Value {
+    cluster.call(node, json!({"type": "read", "key": key}))
+}
+
+#[test]
+fn every_node_serves_reads_writes_and_cas() {
+    let mut cluster = Cluster::start(LINKV, 3, RAFT_ENV);
+    write(&mut cluster, "n1", 1, 10);
+
+    // followers proxy to the leader, so every node sees the write
+    for node in ["n1", "n2", "n3"] {
+        let reply = read(&mut cluster, node, 1);
+        assert_eq!(reply["type"], "read_ok", "unexpected reply: {}", reply);
+        assert_eq!(reply["value"], 10);
+    }
+
+    let cas = |from, to| json!({"type": "cas", "key": 1, "from": from, "to": to});
+    let reply = cluster.call("n2", cas(10, 11));
+    assert_eq!(reply["type"], "cas_ok", "unexpected reply: {}", reply);
+    let reply = cluster.call("n3", cas(10, 12));
+    assert_eq!(reply["code"], 22, "unexpected reply: {}", reply);
+    assert_eq!(read(&mut cluster, "n3", 1)["value"], 11);
+
+    let reply = read(&mut cluster, "n1", 2);
+    assert_eq!(reply["code"], 20, "unexpected reply: {}", reply);
+    let body = json!({"type": "cas", "key": 2, "from": 0, "to": 1, "create_if_not_exists": true});
+    let reply = cluster.call("n2", body);
+    assert_eq!(reply["type"], "cas_ok", "unexpected reply: {}", reply);
+}
+
+#[test]
+fn minority_side_of_a_partition_cannot_serve() {
+    let mut cluster = Cluster::start(LINKV, 5, RAFT_ENV);
+    write(&mut cluster, "n1", 1, 1);
+
+    // wherever the leader is, only n3, n4 and n5 can make progress
+    cluster.partition(&["n1", "n2"]);
+    write(&mut cluster, "n4", 1, 2);
+
+    // the minority can neither write nor read what it last saw
+    for node in ["n1", "n2"] {
+        let body = json!({"type": "write", "key": 1, "value": 3});
+        let reply = cluster.call(node, body);
+        assert_eq!(reply["type"], "error", "minority wrote: {}", reply);
+        let reply = read(&mut cluster, node, 1);
+        assert_eq!(reply["type"], "error", "minority read: {}", reply);
+    }
+    assert_eq!(read(&mut cluster, "n5", 1)["value"], 2);
+
+    // writes the old leader may have logged on the minority side are
+    // discarded, and everyone catches up with the majority
+    cluster.heal();
+    for node in ["n1", "n2", "n3", "n4", "n5"] {
+        let reply = call_until_ok(&mut cluster, node, json!({"type": "read", "key": 1}));
+        assert_eq!(reply["value"], 2, "{} read {}", node, reply);
+    }
+    write(&mut cluster, "n2", 1, 4);
+    assert_eq!(read(&mut cluster, "n1", 1)["value"], 4);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{cluster::Cluster, TestNode, CONVERGENCE_TIMEOUT};
+use serde_json::{json, Value};
+
+const LIST_APPEND: &str = env!("CARGO_BIN_EXE_list_append");
+
+fn start(count: usize, mode: &str) -> Cluster {
+    Cluster::start(LIST_APPEND, count, &[("MALEN_LIST_APPEND_MODE", mode)])
+}
+
+fn append(cluster: &mut Cluster, node: &str, key: u64, element: u64) {
+    let txn = json!([["append", key, element]]);
+    let reply = cluster.call(node, json!({"type": "txn", "txn": txn}));
+    assert_eq!(reply["type"], "txn_ok", "unexpected reply: {}", reply);
+}
+
+fn read(cluster: &mut Cluster, node: &str, key: u64) -> Value {
+    let reply = cluster.call(node, json!({"type": "txn", "txn": [["r", key, null]]}));
+    assert_eq!(reply["type"], "txn_ok", "unexpected reply: {}", reply);
+    reply["txn"][0][2].clone()
+}
+
+#[test]
+fn strict_serializable_reads_every_completed_append() {
+    let mut cluster = start(3, "strict-serializable");
+    for element in 1..=9 {
+        let node = cluster.node_ids[element as usize % 3].clone();
+        append(&mut cluster, &node, 1, element);
+        // whichever node is asked next, the append is already there
+        let next = cluster.node_ids[(element as usize + 1) % 3].clone();
+        let expected: Vec<u64> = (1..=element).collect();
+        assert_eq!(read(&mut cluster, &next, 1), json!(expected));
+    }
+
+    // a transaction reads its own appends after the ones before it
+    let txn = json!([
+        ["append", 1, 10],
+        ["r", 1, null],
+        ["append", 2, 1],
+        ["r", 2, null]
+    ]);
+    let reply = cluster.call("n3", json!({"type": "txn", "txn": txn}));
+    assert_eq!(reply["txn"][1][2], json!((1..=10).collect::<Vec<u64>>()));
+    assert_eq!(reply["txn"][3][2], json!([1]));
+}
+
+#[test]
+fn available_nodes_agree_on_the_order_of_every_list() {
+    let mut cluster = start(3, "available");
+    for element in 1..=9 {
+        let node = cluster.node_ids[element as usize % 3].clone();
+        append(&mut cluster, &node, 1, element);
+    }
+
+    let replies = cluster.wait_for(
+        json!({"type": "txn", "txn": [["r", 1, null]]}),
+        CONVERGENCE_TIMEOUT,
+        |replies| {
+            replies
+                .iter()
+                .all(|reply| reply["txn"][0][2].as_array().map(Vec::len) == Some(9))
+        },
+    );
+    let lists: Vec<&Value> = replies.iter().map(|reply| &reply["txn"][0][2]).collect();
+    assert!(
+        lists.windows(2).all(|pair| pair[0] == pair[1]),
+        "nodes read different lists: {:?}",
+        lists
+    );
+    let mut elements: Vec<u64> = serde_json::from_value(lists[0].clone()).expect("elements");
+    elements.sort();
+    assert_eq!(elements, (1..=9).collect::<Vec<_>>());
+}
+
+fn timestamp(wall: u64) -> Value {
+    json!({"wall": wall, "logical": 0, "node": "n1"})
+}
+
+fn read_on(node: &mut TestNode, key: u64) -> Value {
+    let reply = node.call(json!({"type": "txn", "txn": [["r", key, null]]}));
+    assert_eq!(reply["type"], "txn_ok", "unexpected reply: {}", reply);
+    reply["txn"][0][2].clone()
+}
+
+#[test]
+fn available_reads_keep_up_with_heartbeats_ahead_of_records() {
+    let mut node = TestNode::spawn(
+        LIST_APPEND,
+        "n2",
+        &[("MALEN_LIST_APPEND_MODE", "available")],
+    );
+    node.init(&["n1", "n2"]);
+
+    // the test stands in for n1, which keeps taking records: each
+    // heartbeat arrives before the records it says n1 had taken
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_millis() as u64;
+    let record = |seq: u64| {
+        json!({
+            "origin": "n1",
+            "seq": seq,
+            "at": timestamp(start + 10 * seq),
+            "appends": [[1, seq]],
+        })
+    };
+    for seq in 1..=3 {
+        let clock = timestamp(start + 10 * seq + 5);
+        node.send_from(
+            "n1",
+            json!({"type": "heartbeat", "clock": clock, "last_seq": seq}),
+        );
+    }
+    let gossip = |records: Vec<Value>| json!({"type": "gossip", "messages": records});
+    node.send_from("n1", gossip(vec![record(1), record(2)]));
+    node.receive(|message| message["dest"] == "n1" && message["body"]["type"] == "gossip_ok");
+    // the second heartbeat is covered, the third is not yet
+    assert_eq!(read_on(&mut node, 1), json!([1, 2]));
+
+    node.send_from("n1", gossip(vec![record(3)]));
+    node.receive(|message| message["dest"] == "n1" && message["body"]["type"] == "gossip_ok");
+    assert_eq!(read_on(&mut node, 1), json!([1, 2, 3]));
+}
+
+#[test]
+fn a_single_available_node_reads_its_own_appends() {
+    let mut cluster = start(1, "available");
+    append(&mut cluster, "n1", 1, 1);
+    append(&mut cluster, "n1", 1, 2);
+    assert_eq!(read(&mut cluster, "n1", 1), json!([1, 2]));
+}
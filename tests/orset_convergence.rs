@@ -0,0 +1,61 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{
+    cluster::Cluster,
+    set::{add, converge_to, remove, sorted},
+};
+use serde_json::json;
+
+const ORSET: &str = env!("CARGO_BIN_EXE_orset");
+/// A tombstone is collected on the first gossip after every peer
+/// acknowledged it, which takes at most two rounds.
+const COLLECTION_DELAY: Duration = Duration::from_secs(12);
+
+#[test]
+fn removed_elements_can_be_added_back() {
+    let mut cluster = Cluster::start(ORSET, 3, &[]);
+    add(&mut cluster, "n1", 1);
+    add(&mut cluster, "n2", 2);
+    remove(&mut cluster, "n1", 1);
+    // unlike a two-phase set, a later add on any node brings it back
+    add(&mut cluster, "n3", 1);
+    add(&mut cluster, "n3", 3);
+    converge_to(&mut cluster, &[1, 2, 3]);
+
+    remove(&mut cluster, "n2", 1);
+    converge_to(&mut cluster, &[2, 3]);
+    add(&mut cluster, "n1", 1);
+    converge_to(&mut cluster, &[1, 2, 3]);
+}
+
+#[test]
+fn concurrent_adds_survive_removes_and_removed_elements_stay_removed() {
+    let mut cluster = Cluster::start(ORSET, 3, &[]);
+    add(&mut cluster, "n1", 1);
+    add(&mut cluster, "n2", 2);
+    add(&mut cluster, "n3", 3);
+    converge_to(&mut cluster, &[1, 2, 3]);
+
+    // n3 adds 1 again while cut off, so n1 removes only the add it saw
+    cluster.partition(&["n3"]);
+    remove(&mut cluster, "n1", 1);
+    add(&mut cluster, "n3", 1);
+    remove(&mut cluster, "n2", 3);
+    cluster.heal();
+    converge_to(&mut cluster, &[1, 2]);
+
+    remove(&mut cluster, "n2", 1);
+    converge_to(&mut cluster, &[2]);
+
+    // once every tombstone has been collected, no node brings back an add
+    // it was told about earlier
+    std::thread::sleep(COLLECTION_DELAY);
+    for node in cluster.node_ids.clone() {
+        let reply = cluster.call(&node, json!({"type": "read"}));
+        assert_eq!(sorted(&reply), vec![2], "{} after collection", node);
+    }
+    add(&mut cluster, "n3", 3);
+    converge_to(&mut cluster, &[2, 3]);
+}
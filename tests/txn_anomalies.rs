@@ -0,0 +1,171 @@
+mod common;
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use common::{cluster::Cluster, CONVERGENCE_TIMEOUT};
+use serde_json::{json, Value};
+
+const TXN: &str = env!("CARGO_BIN_EXE_txn");
+
+/// A transaction the test ran, with the reads filled in.
+struct Committed {
+    id: usize,
+    ops: Vec<(String, u64, Option<u64>)>,
+}
+
+/// The transactions of a history that committed, and the values written by
+/// those that did not.
+#[derive(Default)]
+struct History {
+    committed: Vec<Committed>,
+    aborted: HashSet<u64>,
+}
+
+fn micro_ops(reply: &Value) -> Vec<(String, u64, Option<u64>)> {
+    serde_json::from_value(reply["txn"].clone()).expect("txn_ok carries micro-ops")
+}
+
+/// Run a history in which every transaction writes keys 1 and 2, some
+/// write key 3 twice, and all of them read what they write before and
+/// after. Transactions go to different nodes faster than gossip, so most
+/// are concurrent. Every third one is preceded by a transaction on the same
+/// node that fails after its writes, which nothing may read.
+fn run_history(cluster: &mut Cluster, ids: std::ops::Range<usize>, history: &mut History) {
+    for id in ids {
+        let node = cluster.node_ids[id % cluster.node_ids.len()].clone();
+        let value = |key: u64| id as u64 * 10 + key;
+        if id % 3 == 0 {
+            let txn = json!([
+                ["w", 1, value(5)],
+                ["w", 2, value(6)],
+                ["r", 1, null],
+                // a write without a value
+                ["w", 3, null],
+            ]);
+            let reply = cluster.call(&node, json!({"type": "txn", "txn": txn}));
+            assert_eq!(reply["type"], "error", "unexpected reply: {}", reply);
+            assert_eq!(reply["code"], 12, "unexpected reply: {}", reply);
+            history.aborted.extend([value(5), value(6)]);
+        }
+        let txn = json!([
+            ["r", 1, null],
+            ["w", 1, value(1)],
+            ["w", 3, value(3)],
+            ["r", 2, null],
+            ["w", 2, value(2)],
+            ["r", 1, null],
+            ["w", 3, value(4)],
+            ["r", 3, null],
+        ]);
+        let reply = cluster.call(&node, json!({"type": "txn", "txn": txn}));
+        assert_eq!(reply["type"], "txn_ok", "unexpected reply: {}", reply);
+        history.committed.push(Committed {
+            id,
+            ops: micro_ops(&reply),
+        });
+    }
+}
+
+/// Check the reads of `history` and the state the nodes converged on.
+fn check(cluster: &mut Cluster, history: &History, intermediate_reads_allowed: bool) {
+    // value -> the transaction that wrote it, and whether it was that
+    // transaction's last write to the key
+    let mut writers = HashMap::new();
+    for txn in &history.committed {
+        for (i, (op, key, value)) in txn.ops.iter().enumerate() {
+            if op == "w" {
+                let last = !txn.ops[i + 1..]
+                    .iter()
+                    .any(|(op, k, _)| op == "w" && k == key);
+                writers.insert(value.expect("writes carry a value"), (txn.id, last));
+            }
+        }
+    }
+
+    for txn in &history.committed {
+        for (i, (op, key, value)) in txn.ops.iter().enumerate() {
+            if op != "r" {
+                continue;
+            }
+            // a transaction sees its own writes
+            let own = txn.ops[..i]
+                .iter()
+                .rev()
+                .find(|(op, k, _)| op == "w" && k == key)
+                .map(|(_, _, v)| *v);
+            if let Some(own) = own {
+                assert_eq!(*value, own, "txn {} did not read its own write", txn.id);
+                continue;
+            }
+            let Some(value) = value else { continue };
+            // G1a: no value read was written by a transaction that failed,
+            // and every other one by a committed transaction
+            assert!(
+                !history.aborted.contains(value),
+                "txn {} read {} of a failed transaction",
+                txn.id,
+                value
+            );
+            let (writer, last) = writers
+                .get(value)
+                .unwrap_or_else(|| panic!("txn {} read {} nobody wrote", txn.id, value));
+            // G1b: and, under read committed, was its writer's final value
+            assert!(
+                *last || intermediate_reads_allowed,
+                "txn {} read intermediate value {} of txn {}",
+                txn.id,
+                value,
+                writer
+            );
+        }
+    }
+
+    // G0: every transaction wrote keys 1 and 2, so if the nodes order writes
+    // consistently the last writer of both is the same
+    let read = json!({"type": "txn", "txn": [["r", 1, null], ["r", 2, null], ["r", 3, null]]});
+    let replies = cluster.wait_for(read, CONVERGENCE_TIMEOUT, |replies| {
+        replies
+            .windows(2)
+            .all(|pair| pair[0]["txn"] == pair[1]["txn"])
+    });
+    let finals: Vec<Value> = replies.iter().map(|reply| reply["txn"].clone()).collect();
+    assert!(
+        finals.windows(2).all(|pair| pair[0] == pair[1]),
+        "nodes did not converge: {:?}",
+        finals
+    );
+    let ops = micro_ops(&replies[0]);
+    let writer_of = |i: usize| {
+        let value = ops[i].2.expect("every key was written");
+        writers[&value]
+    };
+    assert_eq!(
+        writer_of(0).0,
+        writer_of(1).0,
+        "keys 1 and 2 ended up with writes of different transactions: {:?}",
+        ops
+    );
+    assert_eq!(writer_of(2), (writer_of(0).0, true), "key 3: {:?}", ops);
+}
+
+#[test]
+fn read_uncommitted_has_no_dirty_writes() {
+    let mut cluster = Cluster::start(TXN, 3, &[("MALEN_TXN_ISOLATION", "read-uncommitted")]);
+    let mut history = History::default();
+    run_history(&mut cluster, 0..30, &mut history);
+    check(&mut cluster, &history, true);
+}
+
+#[test]
+fn read_committed_has_no_dirty_writes_or_bad_reads() {
+    let mut cluster = Cluster::start(TXN, 3, &[("MALEN_TXN_ISOLATION", "read-committed")]);
+    let mut history = History::default();
+    run_history(&mut cluster, 0..30, &mut history);
+    // let gossip catch up, so later transactions read replicated values
+    std::thread::sleep(Duration::from_secs(6));
+    run_history(&mut cluster, 30..60, &mut history);
+    check(&mut cluster, &history, false);
+}